@@ -3,127 +3,460 @@
 
 extern crate tcod;
 extern crate rand;
-extern crate rustc_serialize;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate roguelike_tutorial;
 
 use std::ascii::AsciiExt;
 use std::cmp::{self, Ordering};
-use std::fs::File;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Error};
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize};
+use std::thread;
+use std::time::Duration;
 use tcod::console::*;
 use tcod::colors::{self, Color};
-use tcod::input::{self, Key, Event, Mouse};
+use tcod::input::{self, Key, KeyCode, Event, Mouse};
 use tcod::map::Map as FovMap;
 use tcod::map::FovAlgorithm;
-use rand::Rng;
-use rustc_serialize::{json, Encodable, Encoder};
-
-
-// actual size of the window
-const SCREEN_WIDTH: i32 = 80;
-const SCREEN_HEIGHT: i32 = 50;
-
-// size of the map
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
-
-// sizes and coordinates relevant for the GUI
+use tcod::line::Line;
+use rand::{Rng, SeedableRng, StdRng};
+use rand::isaac::Isaac64Rng;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+// the map and everything else that can be used without a window
+use roguelike_tutorial::*;
+
+
+// size of the window, unless `--width` and `--height` say otherwise
+const DEFAULT_SCREEN_WIDTH: i32 = 80;
+const DEFAULT_SCREEN_HEIGHT: i32 = 50;
+// the menus need this much room, and level generation this much space
+const MIN_SCREEN_WIDTH: i32 = 60;
+const MIN_MAP_WIDTH: i32 = 40;
+const MIN_MAP_HEIGHT: i32 = 20;
+
+// sizes and coordinates relevant for the GUI (see `Config` for the rest)
 const BAR_WIDTH: i32 = 20;
 const PANEL_HEIGHT: i32 = 7;
-const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+// how many messages the log keeps (and saves) for the history, by default and
+// what the option goes through
+const LOG_CAPACITY: usize = 100;
+const LOG_CAPACITIES: &[usize] = &[100, 250, 1000];
 const INVENTORY_WIDTH: i32 = 50;
-const CHARACTER_SCREEN_WIDTH: i32 = 30;
+// one letter for each option of a menu, `TcodState::paged_menu` shows more a page at a time
+const MENU_PAGE_SIZE: usize = 26;
+const MAX_INVENTORY: usize = 52;
+// how long each frame of a spell animation stays on screen, 0 turns them off
+const ANIMATION_FRAME_MS: u64 = 25;
+// the default pause before each key press of a replay, `--replay-delay` changes it
+const REPLAY_DELAY_MS: u64 = 100;
+// the bars shown over hurt monsters
+const HEALTH_BAR_WIDTH: i32 = 3;
+// the HP bar goes from the first color when empty to the last one when full
+const HP_BAR_GRADIENT: &[Color] = &[colors::RED, colors::YELLOW, colors::GREEN];
+const CHARACTER_SCREEN_WIDTH: i32 = 40;
 const LEVEL_SCREEN_WIDTH: i32 = 40;
+const PAUSE_MENU_WIDTH: i32 = 30;
 
 //parameters for dungeon generator
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
 const MAX_ROOMS: i32 = 30;
-
-// spell values
-const HEAL_AMOUNT: i32 = 40;
-const LIGHTNING_DAMAGE: i32 = 40;
-const LIGHTNING_RANGE: i32 = 5;
-const CONFUSE_RANGE: i32 = 8;
-const CONFUSE_NUM_TURNS: i32 = 10;
-const FIREBALL_RADIUS: i32 = 3;
-const FIREBALL_DAMAGE: i32 = 25;
+// chance of a doorway between a room and a tunnel getting a door
+const DOOR_CHANCE: f32 = 0.5;
+// summoners run away from a player that comes closer than this
+const SUMMONER_SAFE_DISTANCE: f32 = 3.0;
+// the monster the scroll of summoning calls to the player's side, see `MONSTERS_FILE`
+const ALLY_NAME: &'static str = "spirit wolf";
+// with no enemy around, allies stay at most this far from the player
+const ALLY_FOLLOW_DISTANCE: f32 = 3.0;
+// chance of a monster being asleep when the level is made, and of a sleeping
+// one noticing the player every turn they're in view
+const SLEEP_CHANCE: f32 = 0.3;
+const WAKE_UP_CHANCE: f32 = 0.25;
+// sleeping monsters this close to a fight or a loud spell wake up
+const ATTACK_NOISE_RADIUS: f32 = 5.0;
+const SPELL_NOISE_RADIUS: f32 = 10.0;
+// monsters that come in packs have this many others of their kind around them
+const PACK_MIN_FOLLOWERS: i32 = 2;
+const PACK_MAX_FOLLOWERS: i32 = 4;
+const PACK_RADIUS: i32 = 2;
+// chance of a room (except the first one) being a vault instead
+const VAULT_CHANCE: f32 = 0.1;
+// chance of a level having a room hidden behind a secret wall
+const SECRET_ROOM_CHANCE: f32 = 0.5;
+const SECRET_ROOM_ATTEMPTS: i32 = 20;
+// chance of finding each secret wall next to the player, every turn spent searching
+const SEARCH_CHANCE: f32 = 0.3;
+// BSP levels never split an area into pieces smaller than this
+const BSP_MIN_LEAF_SIZE: i32 = ROOM_MIN_SIZE + 2;
+// cave levels
+const CAVE_FILL_CHANCE: f32 = 0.45;
+const CAVE_SMOOTHING_PASSES: i32 = 5;
+const CAVE_WALL_NEIGHBORS: i32 = 5;  // a tile with at least this many becomes a wall
+const CAVE_MIN_OPEN_SHARE: i32 = 4;  // the cave covers at least 1/this of the map
+
+// monsters give up looking for a way around obstacles after this many tiles
+const MONSTER_PATH_MAX_NODES: usize = 200;
+// cost of stepping on a tile another monster stands on, when looking for a path
+const CROWDED_STEP_COST: i32 = 5;
+
+// spell values (the rest is in `ITEMS_FILE`)
+const CONFUSE_TURNS_PER_LEVEL: i32 = 1;
+// each point of spell power makes spells this many percent stronger
+const SPELL_POWER_PERCENT: i32 = 10;
+
+// attacks sometimes hit so well they do double damage (the rest of the combat
+// numbers are in the library)
+const CRITICAL_HIT_CHANCE: f32 = 0.05;
+// the perks that change combat, see `Perk`
+const KEEN_EYE_CRITICAL_BONUS: f32 = 0.05;
+const LIFESTEAL_FRACTION: f32 = 0.1;
+// how many perks are offered on level-up
+const PERK_CHOICES: usize = 3;
+
+// bows and arrows
+const ARROW_RECOVERY_CHANCE: f32 = 0.5;
+
+// hunger: how full the player is. It goes down by one every turn.
+const MAX_HUNGER: i32 = 1500;
+const HUNGRY_THRESHOLD: i32 = 300;
+const STARVING_THRESHOLD: i32 = 100;
+const STARVATION_DAMAGE: i32 = 1;  // every turn, once it reaches 0
+
+// corpses go away after a while, and make the player sick once they've started to rot
+const CORPSE_DECAY_TURNS: i32 = 400;
+const CORPSE_ROTTEN_TURNS: i32 = 150;  // left before it's gone
+const CORPSE_NUTRITION: i32 = 250;
+const ROTTEN_CORPSE_POISON: i32 = 1;  // damage per turn, for `POISON_TURNS` turns
+
+// out of sight of monsters, the player heals 1 HP every this many turns, a bit
+// faster with every level
+const REGEN_BASE_INTERVAL: i32 = 20;
+const REGEN_INTERVAL_PER_LEVEL: i32 = 2;
+const REGEN_MIN_INTERVAL: i32 = 5;
+
+// thrown potions
+const THROW_RANGE: f32 = 8.0;
+const SPLASH_RADIUS: i32 = 1;  // how far a thrown vial of poison spreads
+
+// what unidentified potions and scrolls look like, see `assign_flavors`
+const POTION_FLAVORS: &[&str] = &["cloudy potion", "fizzing potion", "murky potion",
+                                  "glowing potion", "smoky potion"];
+const SCROLL_FLAVORS: &[&str] = &["fizzing scroll", "crumpled scroll", "dusty scroll",
+                                  "singed scroll", "glittering scroll", "faded scroll",
+                                  "blotched scroll", "humming scroll", "smudged scroll"];
+
+// status effects
+const POISON_TURNS: i32 = 8;  // the damage per turn is in `ITEMS_FILE`
+const RATION_REGEN_TURNS: i32 = 10;
+const RATION_REGEN_HP: i32 = 1;
+
+// chance of equipment found in the dungeon being cursed
+const CURSE_CHANCE: f32 = 0.1;
+// chance of each enchantment on the equipment found in the dungeon, in the
+// (value, level) form `from_dungeon_level` takes: deeper down it gets better
+const ENCHANTMENT_CHANCES: &[(i32, &[(u32, i32)])] = &[
+    (-2, &[(5, 1), (2, 6)]),
+    (-1, &[(10, 1), (5, 6)]),
+    (0, &[(60, 1), (50, 4), (40, 7)]),
+    (1, &[(15, 1), (20, 4)]),
+    (2, &[(5, 1), (10, 4), (15, 7)]),
+    (3, &[(2, 3), (5, 5), (10, 8)]),
+];
+// scrolls of enchantment can't take equipment past this
+const MAX_ENCHANTMENT: i32 = 5;
+
+// traps: hidden until stepped on or spotted
+const TRAP_MIN_LEVEL: i32 = 2;
+const TRAP_CHANCE: f32 = 0.2;  // for every room
+const TRAP_SPOT_CHANCE: f32 = 0.1;  // every turn, for each one next to the player
+const SPIKE_TRAP_DAMAGE: i32 = 6;
+const CONFUSION_TRAP_TURNS: i32 = 5;
+
+// chance of a room getting a crafting altar
+const ALTAR_CHANCE: f32 = 0.05;
+
+// braziers light up the tiles around them, even out of the player's torch radius
+const BRAZIER_CHANCE: f32 = 0.1;  // for every room
+const BRAZIER_LIGHT_RADIUS: i32 = 5;
+
+// gold: monsters sometimes drop some, shopkeepers take it
+const GOLD_DROP_CHANCE: f32 = 0.5;
+// every this many levels there's a shop room, or a shopkeeper near the
+// entrance when there's no room for it
+const SHOP_LEVEL_INTERVAL: i32 = 5;
+const SHOP_ROOM_WIDTH: i32 = 6;
+const SHOP_ROOM_HEIGHT: i32 = 5;
+const SHOP_ROOM_ATTEMPTS: i32 = 50;
+// what shopkeepers sell, and for how much gold
+const SHOP_STOCK: &'static [(Item, i32)] = &[(Item::Heal, 30),
+                                             (Item::Ration, 15),
+                                             (Item::Arrow, 20),
+                                             (Item::Sword, 80),
+                                             (Item::Shield, 60),
+                                             (Item::Bow, 70)];
+
+// chance of a room getting a patch of grass, a pool of water or a pool of lava
+const GRASS_CHANCE: f32 = 0.2;
+const WATER_CHANCE: f32 = 0.1;
+const LAVA_CHANCE: f32 = 0.05;
+// damage for every step taken on lava
+const LAVA_DAMAGE: i32 = 5;
+
+// burning grass
+const FIRE_DURATION: i32 = 4;
+const FIRE_SPREAD_CHANCE: f32 = 0.3;
+const FIRE_DAMAGE: i32 = 3;
+const BURN_NUM_TURNS: i32 = 3;
+const BURN_DAMAGE: i32 = 2;
+
+// automatic movement (travel, exploring) stops when the player's HP drops under this
+const AUTO_MIN_HP_FRACTION: f32 = 0.5;
 
 // experience and level-ups
 const LEVEL_UP_BASE: i32 = 200;
 const LEVEL_UP_FACTOR: i32 = 150;
 
 
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
 const TORCH_RADIUS: i32 = 10;
+// what the torch radius option goes through
+const TORCH_RADII: &[i32] = &[6, 8, 10, 12, 15];
+// how far toward the dark colors a tile gets at the edge of the torch radius
+const LIGHT_FALLOFF: f32 = 0.7;
 
 const LIMIT_FPS: i32 = 20;  // 20 frames-per-second maximum
 
+#[cfg(not(test))]
+const SAVE_FILE: &'static str = "savegame";
+// so running the tests leaves the player's save alone
+#[cfg(test)]
+const SAVE_FILE: &'static str = "target/test-savegame";
+const MONSTERS_FILE: &'static str = "monsters.json";
+const ITEMS_FILE: &'static str = "items.json";
+const VAULTS_FILE: &'static str = "vaults.txt";
+const SCORES_FILE: &'static str = "scores.txt";
+const LEVEL_DUMP_FILE: &'static str = "level_dump.txt";
+const MAX_SCORES: usize = 10;
+
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
 const COLOR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
+const COLOR_DARK_GRASS: Color = Color { r: 30, g: 70, b: 60 };
+const COLOR_LIGHT_GRASS: Color = Color { r: 90, g: 160, b: 40 };
+const COLOR_DARK_WATER: Color = Color { r: 20, g: 30, b: 120 };
+const COLOR_LIGHT_WATER: Color = Color { r: 40, g: 90, b: 220 };
+const COLOR_DARK_LAVA: Color = Color { r: 90, g: 20, b: 10 };
+const COLOR_LIGHT_LAVA: Color = Color { r: 220, g: 60, b: 0 };
+const COLOR_FIRE: Color = Color { r: 250, g: 90, b: 10 };
+
+/// The colors of the walls and the floor of a level.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Theme {
+    #[serde(with = "ColorDef")]
+    dark_wall: Color,
+    #[serde(with = "ColorDef")]
+    light_wall: Color,
+    #[serde(with = "ColorDef")]
+    dark_ground: Color,
+    #[serde(with = "ColorDef")]
+    light_ground: Color,
+}
+
+const THEMES: &[Theme] = &[
+    // the classic dungeon, the first level always looks like this
+    Theme {
+        dark_wall: COLOR_DARK_WALL, light_wall: COLOR_LIGHT_WALL,
+        dark_ground: COLOR_DARK_GROUND, light_ground: COLOR_LIGHT_GROUND,
+    },
+    // caverns
+    Theme {
+        dark_wall: Color { r: 40, g: 30, b: 20 }, light_wall: Color { r: 120, g: 90, b: 60 },
+        dark_ground: Color { r: 60, g: 50, b: 40 }, light_ground: Color { r: 170, g: 140, b: 100 },
+    },
+    // crypt
+    Theme {
+        dark_wall: Color { r: 30, g: 30, b: 40 }, light_wall: Color { r: 110, g: 110, b: 120 },
+        dark_ground: Color { r: 50, g: 50, b: 60 }, light_ground: Color { r: 160, g: 160, b: 170 },
+    },
+    // lava halls
+    Theme {
+        dark_wall: Color { r: 60, g: 10, b: 10 }, light_wall: Color { r: 150, g: 40, b: 20 },
+        dark_ground: Color { r: 70, g: 30, b: 20 }, light_ground: Color { r: 200, g: 110, b: 50 },
+    },
+];
+
+impl Theme {
+    /// the same level of the same dungeon always gets the same theme
+    fn for_level(seed: u64, level: i32) -> Self {
+        if level == 1 {
+            return THEMES[0];
+        }
+        // a different stream than the one the map is generated from
+        let mut rng = level_rng(seed, -level);
+        *rng.choose(THEMES).unwrap()
+    }
+}
 
 const PLAYER: usize = 0;
 
-type Map = Vec<Vec<Tile>>;
+/// `Color` doesn't implement the serde traits, this teaches serde to (de)serialize it
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Color")]
+struct ColorDef {
+    r: u8,
+    g: u8,
+    b: u8,
+}
 
-#[derive(Clone, Copy, Debug, RustcDecodable, RustcEncodable)]
-struct Tile {
-    blocked: bool,
-    explored: bool,
-    block_sight: bool,
+/// the same for the input events that get recorded, see `RecordedInput`
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "KeyCode")]
+enum KeyCodeDef {
+    NoKey, Escape, Backspace, Tab, Enter, Shift, Control, Alt, Pause, CapsLock, PageUp,
+    PageDown, End, Home, Up, Left, Right, Down, PrintScreen, Insert, Delete, LeftWin, RightWin,
+    Apps, Number0, Number1, Number2, Number3, Number4, Number5, Number6, Number7, Number8,
+    Number9, NumPad0, NumPad1, NumPad2, NumPad3, NumPad4, NumPad5, NumPad6, NumPad7, NumPad8,
+    NumPad9, NumPadAdd, NumPadSubtract, NumPadDivide, NumPadMultiply, NumPadDecimal,
+    NumPadEnter, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, NumLock, ScrollLock,
+    Spacebar, Char,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Key")]
+struct KeyDef {
+    #[serde(with = "KeyCodeDef")]
+    code: KeyCode,
+    printable: char,
+    pressed: bool,
+    left_alt: bool,
+    left_ctrl: bool,
+    right_alt: bool,
+    right_ctrl: bool,
+    shift: bool,
+    alt: bool,
+    ctrl: bool,
 }
 
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Mouse")]
+struct MouseDef {
+    x: isize,
+    y: isize,
+    dx: isize,
+    dy: isize,
+    cx: isize,
+    cy: isize,
+    dcx: isize,
+    dcy: isize,
+    lbutton: bool,
+    rbutton: bool,
+    mbutton: bool,
+    lbutton_pressed: bool,
+    rbutton_pressed: bool,
+    mbutton_pressed: bool,
+    wheel_up: bool,
+    wheel_down: bool,
+}
 
-    pub fn center(&self) -> (i32, i32) {
-        let center_x = (self.x1 + self.x2) / 2;
-        let center_y = (self.y1 + self.y2) / 2;
-        (center_x, center_y)
-    }
+/// Something the game read from the outside world, as it's recorded with
+/// `--record` and played back with `--replay`. Everything else follows from
+/// these and the seed, so playing them back repeats the session exactly.
+#[derive(Debug, Serialize, Deserialize)]
+enum RecordedInput {
+    Key(#[serde(with = "KeyDef")] Key),
+    Mouse(#[serde(with = "MouseDef")] Mouse),
+    // whether a key press stopped the player running, see `TcodState::key_interrupt`
+    Interrupt(bool),
+    // the seed a new game was started with
+    Seed(u64),
+}
+
+/// The input of a session being recorded with `--record`. It's written out
+/// when this is dropped, which also happens when the game panics, so the
+/// sessions that most need replaying aren't lost.
+struct Recording {
+    path: String,
+    inputs: Vec<RecordedInput>,
+}
 
-    pub fn intersect(&self, other: &Rect) -> bool {
-        // returns true if this rectangle intersects with another one
-        (self.x1 <= other.x2) && (self.x2 >= other.x1) && (self.y1 <= other.y2) &&
-        (self.y2 >= other.y1)
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let json = serde_json::to_string(&self.inputs).unwrap();
+        // no panicking here, this may run because of a panic already
+        if let Err(e) = File::create(&self.path).and_then(|mut file| file.write_all(json.as_bytes())) {
+            eprintln!("Could not save the recording {}: {}", self.path, e);
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+// the next unused object uid
+static NEXT_OBJECT_UID: AtomicUsize = AtomicUsize::new(0);
+
+/// a tile of grass that is currently on fire
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Fire {
+    x: i32,
+    y: i32,
+    turns_left: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct Object {
+    // unlike its index in the objects list, this never changes and is never reused.
+    // NOTE: cloning keeps it, so give clones spawned into the world a new one!
+    uid: usize,
     x: i32,
     y: i32,
     char: char,
     name: String,
+    #[serde(with = "ColorDef")]
     color: Color,
     blocks: bool,
     alive: bool,
     always_visible: bool,
+    // the player has seen this object at some point
+    seen: bool,
+    // where the player last saw this monster, drawn dimmed while it's out of sight
+    last_known_pos: Option<(i32, i32)>,
+    // how many items are in this stack (1 for everything that doesn't stack)
+    count: i32,
     level: i32,
     fighter: Option<Fighter>,
     ai: Option<MonsterAI>,
+    // who it fights for: monsters attack the player and their allies, and the other way round
+    faction: Faction,
+    // allies that are only summoned for a while disappear after this many more turns
+    expires_in: Option<i32>,
+    // corpses rot away after this many more turns
+    decay_turns: Option<i32>,
     item: Option<Item>,
     equipment: Option<Equipment>,
+    altar: Option<Altar>,
+    trap: Option<Trap>,
+    door: Option<Door>,
+    // light sources light up the tiles this far around them
+    light_radius: Option<i32>,
+    // how much gold a gold pile is worth
+    gold_amount: Option<i32>,
+    // shopkeepers sell `SHOP_STOCK` to a player next to them
+    shopkeeper: bool,
+    // poison and the like, see `tick_effects`
+    effects: Vec<StatusEffect>,
 }
 
 impl Object {
     pub fn new(x: i32, y: i32, char: char, name: &str, color: Color, blocks: bool) -> Self {
         Object {
+            uid: NEXT_OBJECT_UID.fetch_add(1, atomic::Ordering::SeqCst),
             x: x,
             y: y,
             char: char,
@@ -132,11 +465,24 @@ impl Object {
             blocks: blocks,
             alive: false,
             always_visible: false,
+            seen: false,
+            last_known_pos: None,
+            count: 1,
             level: 0,
             fighter: None,
             ai: None,
+            faction: Faction::Monster,
+            expires_in: None,
+            decay_turns: None,
             item: None,
             equipment: None,
+            altar: None,
+            trap: None,
+            door: None,
+            light_radius: None,
+            gold_amount: None,
+            shopkeeper: false,
+            effects: vec![],
         }
     }
 
@@ -144,6 +490,20 @@ impl Object {
         self.name == "player"
     }
 
+    /// a monster fighting on the player's side
+    pub fn is_ally(&self) -> bool {
+        self.faction == Faction::Player && !self.is_player()
+    }
+
+    /// the name, plus the size of the stack if there's more than one
+    pub fn display_name(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.name, self.count)
+        } else {
+            self.name.clone()
+        }
+    }
+
     pub fn pos(&self) -> (i32, i32) {
         (self.x, self.y)
     }
@@ -166,19 +526,60 @@ impl Object {
     }
 
     /// Set the color and then draw the character that represents this object at its position.
-    pub fn draw(&self, con: &mut Console, map: &Map, fov: &FovMap) {
+    pub fn draw(&self, con: &mut renderer::Renderer, game: &Game, fov: &Sight) {
+        if self.is_hidden() {
+            return;
+        }
         // only show if it's visible to the player; or it's set to
         // "always visible" and on an explored tile
-        if fov.is_in_fov(self.x, self.y) ||
-           (self.always_visible && map[self.x as usize][self.y as usize].explored) {
-            con.set_default_foreground(self.color);
+        let explored = game.map[self.x as usize][self.y as usize].explored;
+        let detected = game.detect_monsters_turns > 0 && self.alive && self.fighter.is_some();
+        let color = if fov.is_in_fov(self.x, self.y) && self.is_asleep() {
+            Some(colors::lerp(self.color, colors::BLACK, 0.4))
+        } else if fov.is_in_fov(self.x, self.y) {
+            Some(self.color)
+        } else if detected {
+            // monsters sensed by magic are faded
+            Some(colors::lerp(self.color, colors::BLACK, 0.5))
+        } else if game.settings.show_remembered && self.seen && self.fighter.is_none() && explored {
+            // static things the player remembers are shown dimmed (monsters never are)
+            Some(colors::lerp(self.color, game.theme.dark_ground, 0.6))
+        } else if self.always_visible && explored {
+            Some(self.color)
+        } else {
+            None
+        };
+        if let Some(color) = color {
+            con.set_default_foreground(color);
             con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+        } else if let Some((x, y)) = self.last_known_pos {
+            // out of sight, so show where the player last saw it
+            if game.map[x as usize][y as usize].explored && !fov.is_in_fov(x, y) {
+                con.set_default_foreground(colors::lerp(self.color, game.theme.dark_ground, 0.6));
+                con.put_char(x, y, self.char, BackgroundFlag::None);
+            }
+        }
+    }
+
+    pub fn is_asleep(&self) -> bool {
+        self.ai.as_ref().map_or(false, |ai| ai.ai_type == MonsterAIType::Sleeping)
+    }
+
+    /// a sleeping monster goes back to its old AI
+    pub fn wake_up(&mut self, game: &mut Game) {
+        if self.is_asleep() {
+            self.ai = self.ai.take().and_then(|ai| ai.old_ai).map(|ai| *ai);
+            game.log.add(format!("The {} wakes up!", self.name), colors::LIGHT_YELLOW);
         }
     }
 
-    /// Erase the character that represents this object.
-    pub fn clear(&self, con: &mut Console) {
+    /// Erase the character that represents this object, and the one left
+    /// where the player last saw it.
+    pub fn clear(&self, con: &mut renderer::Renderer) {
         con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
+        if let Some((x, y)) = self.last_known_pos {
+            con.put_char(x, y, ' ', BackgroundFlag::None);
+        }
     }
 
     pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
@@ -193,27 +594,118 @@ impl Object {
                 None
             }
         });
+        if death.is_none() && damage > 0 {
+            self.wake_up(game);
+            let splits_at = self.fighter.as_ref().and_then(|fighter| fighter.splits_at);
+            if splits_at.map_or(false, |hp| self.fighter.as_ref().unwrap().hp >= hp) {
+                // there's no room for the new one in `objects` here
+                game.splits.push(self.uid);
+            }
+        }
         death.map(|(death, xp)| {
             death.callback(self, game);
             xp
         })
     }
 
+    /// start a status effect. One that's already active just gets the longer
+    /// duration and the stronger magnitude of the two.
+    pub fn add_effect(&mut self, effect: StatusEffect) {
+        if let Some(active) = self.effects.iter_mut().find(|e| e.kind == effect.kind) {
+            active.turns_left = cmp::max(active.turns_left, effect.turns_left);
+            active.magnitude = cmp::max(active.magnitude, effect.magnitude);
+            return;
+        }
+        self.effects.push(effect);
+    }
+
+    pub fn has_effect(&self, kind: StatusEffectKind) -> bool {
+        self.effects.iter().any(|e| e.kind == kind)
+    }
+
+    /// traps the player hasn't found yet are neither drawn nor named
+    pub fn is_hidden(&self) -> bool {
+        self.trap.map_or(false, |trap| !trap.discovered)
+    }
+
+    pub fn is_closed_door(&self) -> bool {
+        self.door.map_or(false, |door| !door.open)
+    }
+
+    /// slowed fighters only get to act every other turn
+    pub fn loses_turn(&self) -> bool {
+        self.effects.iter().any(|e| e.kind == StatusEffectKind::Slow && e.turns_left % 2 == 0)
+    }
+
+    /// work out how much damage an attack on the target would do
+    fn roll_damage(&self, target: &Object, game: &mut Game) -> DamageRoll {
+        // a simple formula for attack damage, give or take a bit
+        let power = self.full_power(game);
+        let defense = target.full_defense(game);
+        let model = game.settings.combat_model;
+        let base = model.base_damage(power, defense);
+        let spread = damage_spread(base);
+        let mut damage = base + game.rng.gen_range(-spread, spread + 1);
+        let keen_eye = self.is_player() && game.perks.contains(&Perk::KeenEye);
+        let critical_chance = CRITICAL_HIT_CHANCE + if keen_eye { KEEN_EYE_CRITICAL_BONUS } else { 0.0 };
+        let critical = damage > 0 && game.rng.gen::<f32>() < critical_chance;
+        if critical {
+            damage *= 2;
+        }
+        DamageRoll {
+            model: model,
+            power: power,
+            defense: defense,
+            base: base,
+            damage: damage,
+            critical: critical,
+        }
+    }
+
+    /// the chance this object's attacks land on the target
+    fn hit_chance(&self, target: &Object, game: &Game) -> f32 {
+        hit_chance(self.full_power(game), target.full_evasion(game))
+    }
+
     fn attack(&mut self, target: &mut Object, game: &mut Game) {
-        // a simple formula for attack damage
-        let damage = self.full_power(game) - target.full_defense(game);
+        let hit_chance = self.hit_chance(target, game);
+        if game.rng.gen::<f32>() >= hit_chance {
+            let details = if game.settings.verbose_combat {
+                format!(" [{:.0}% to hit]", hit_chance * 100.0)
+            } else {
+                String::new()
+            };
+            game.log.add(format!("{} misses {}.{}", self.name, target.name, details),
+                         colors::LIGHT_GREY);
+            return;
+        }
+        let roll = self.roll_damage(target, game);
+        let damage = roll.damage;
+        if roll.critical {
+            game.log.add("A critical hit!", colors::LIGHT_ORANGE);
+        }
+        let details = if game.settings.verbose_combat {
+            format!(" [{}]", roll.breakdown())
+        } else {
+            String::new()
+        };
         if damage > 0 {
             // make the target take some damage
-            game.log.add(format!("{} attacks {} for {} hit points.",
-                                 self.name, target.name, damage),
+            game.log.add(format!("{} attacks {} for {} hit points.{}",
+                                 self.name, target.name, damage, details),
                          colors::WHITE);
             target.take_damage(damage, game).map(|xp| {
                 if self.is_player() {
                     self.fighter.as_mut().unwrap().xp += xp;
                 }
             });
+            if self.is_player() && game.perks.contains(&Perk::Lifesteal) {
+                let stolen = (damage as f32 * LIFESTEAL_FRACTION).ceil() as i32;
+                self.fighter.as_mut().unwrap().heal(stolen);
+            }
         } else {
-            game.log.add(format!("{} attacks {} but it has no effect!", self.name, target.name),
+            game.log.add(format!("{} attacks {} but it has no effect!{}",
+                                 self.name, target.name, details),
                          colors::WHITE);
         }
     }
@@ -232,12 +724,29 @@ impl Object {
         base_defense + bonus
     }
 
+    fn full_evasion(&self, game: &Game) -> i32 {
+        let base_evasion = self.fighter.as_ref().map_or(0, |f| f.base_evasion);
+        let bonus = self.get_all_equipped(game).iter().fold(0, |sum, e| sum + e.evasion_bonus);
+        base_evasion + bonus
+    }
+
     fn full_max_hp(&self, game: &Game) -> i32 {
         let base_max_hp = self.fighter.as_ref().map_or(0, |f| f.base_max_hp);
         let bonus = self.get_all_equipped(game).iter().fold(0, |sum, e| sum + e.max_hp_bonus);
         base_max_hp + bonus
     }
 
+    fn full_spell_power(&self, game: &Game) -> i32 {
+        let base_spell_power = self.fighter.as_ref().map_or(0, |f| f.base_spell_power);
+        let bonus = self.get_all_equipped(game).iter().fold(0, |sum, e| sum + e.spell_bonus);
+        base_spell_power + bonus
+    }
+
+    /// the strength of a spell with the given base value when cast by this object
+    fn spell_strength(&self, base: i32, game: &Game) -> i32 {
+        base * (100 + self.full_spell_power(game) * SPELL_POWER_PERCENT) / 100
+    }
+
     /// returns a list of equipped items
     fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
         if self.is_player() {
@@ -259,93 +768,178 @@ impl Object {
             equipment.is_equipped = true;
             messages.add(format!("Equipped {} on {}.", self.name, equipment.slot),
                          colors::LIGHT_GREEN);
+            if equipment.cursed {
+                messages.add(format!("A chill runs down your spine. The {} is cursed!", self.name),
+                             colors::RED);
+            }
         }
     }
 
-    fn dequip(&mut self, messages: &mut MessageLog) {
+    /// returns false if it's cursed and stays on
+    fn dequip(&mut self, messages: &mut MessageLog) -> bool {
         // dequip object and show a message about it
         if let Some(equipment) = self.equipment.as_mut() {
             if equipment.is_equipped {
+                if equipment.cursed {
+                    messages.add(format!("The {} is stuck to you!", self.name), colors::RED);
+                    return false;
+                }
                 equipment.is_equipped = false;
                 messages.add(format!("Dequipped {} from {}.", self.name, equipment.slot),
                              colors::LIGHT_YELLOW);
             }
         }
+        true
+    }
+}
+
+impl Blocker for Object {
+    fn pos(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    fn blocks(&self) -> bool {
+        self.blocks
     }
 }
 
+/// everything that went into the damage of a single attack
+#[derive(Clone, Copy, Debug)]
+struct DamageRoll {
+    model: CombatModel,
+    power: i32,
+    defense: i32,
+    // what the model makes of power and defense, before the random part
+    base: i32,
+    damage: i32,
+    critical: bool,
+}
+
+impl DamageRoll {
+    /// a human-readable explanation of how the damage was computed
+    fn breakdown(&self) -> String {
+        let base = match self.model {
+            CombatModel::Classic => format!("power {} - defense {} = {}", self.power, self.defense, self.base),
+            CombatModel::Percentage => format!("power {} - {}% (defense {}) = {}",
+                                               self.power,
+                                               CombatModel::reduction_percent(self.defense),
+                                               self.defense,
+                                               self.base),
+        };
+        format!("{}, rolled {}{}", base, self.damage, if self.critical { " (critical)" } else { "" })
+    }
+}
 
-/// move by the given amount, if the destination is not blocked
+/// move by the given amount, if the destination is not blocked. Bumping into a
+/// closed door opens it instead.
 fn move_by(id: usize, dx: i32, dy: i32, objects: &mut [Object], game: &mut Game) {
     let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, &game.map, &objects) {
-        objects[id].set_pos(x + dx, y + dy);
+    // off the edge of the map is as good as a wall (confused monsters try to go anywhere)
+    let (new_x, new_y) = (x + dx, y + dy);
+    if !in_map(new_x, new_y, &game.map) {
+        return;
+    }
+    if let Some(door_id) = closed_door_at(new_x, new_y, objects) {
+        open_door(door_id, objects, game);
+        if objects[id].is_player() {
+            game.log.add("You open the door.", colors::LIGHT_GREY);
+        }
+    } else if !is_blocked(new_x, new_y, &game.map, &objects) {
+        objects[id].set_pos(new_x, new_y);
     }
 }
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, objects: &mut [Object], game: &mut Game) {
-    // vector from this object to the target, and distance
-    let (dx, dy) = {
-        let (ox, oy) = objects[id].pos();
-        (target_x - ox, target_y - oy)
+/// move a step towards `target`: straight at it, unless something is in the way
+fn chase(id: usize, target: (i32, i32), objects: &mut [Object], game: &mut Game) {
+    let (x, y) = objects[id].pos();
+    let (dx, dy) = direction_towards((x, y), target);
+    let (dx, dy) = if is_blocked(x + dx, y + dy, &game.map, objects) ||
+        game.map[(x + dx) as usize][(y + dy) as usize].terrain == Terrain::Lava {
+        astar_step(id, target, objects, &game.map).unwrap_or((dx, dy))
+    } else {
+        (dx, dy)
     };
-    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
-
-    // normalize it to length 1 (preserving direction), then round it and
-    // convert to integer so the movement is restricted to the map grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
     move_by(id, dx, dy, objects, game);
 }
 
-/// Mutably borrow two *separate* elements from the given slice.
-/// Panics when the indexes are equal or out of bounds.
-fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
-    assert!(first_index != second_index);
-    let split_at_index = if first_index < second_index {
-        second_index
-    } else {
-        first_index
+/// the first step along the shortest path from the object to `target`, if there is one.
+/// Other blocking objects only make a tile more expensive, as they will probably
+/// have moved by the time we get there.
+fn astar_step(id: usize, target: (i32, i32), objects: &[Object], map: &Map) -> Option<(i32, i32)> {
+    let start = objects[id].pos();
+    let step_cost = |x: i32, y: i32| {
+        // monsters walk around lava
+        if !in_map(x, y, map) || map[x as usize][y as usize].blocked ||
+            map[x as usize][y as usize].terrain == Terrain::Lava {
+            None
+        } else if objects.iter().any(|object| object.blocks && object.pos() == (x, y)) {
+            Some(CROWDED_STEP_COST)
+        } else {
+            Some(1)
+        }
     };
-    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
-    if first_index < second_index {
-        (&mut first_slice[first_index], &mut second_slice[0])
-    } else {
-        (&mut second_slice[0], &mut first_slice[second_index])
-    }
+    find_path(start, target, MONSTER_PATH_MAX_NODES, step_cost)
+        .and_then(|path| path.first().cloned())
+        .map(|(x, y)| (x - start.0, y - start.1))
+}
+
+/// Take an object out of the level. Unlike `swap_remove`, this leaves every
+/// object before it where it was; the ones after it move down by one, so an
+/// index held across the removal has to be looked up again (see `find_by_uid`).
+fn remove_object(id: usize, objects: &mut Vec<Object>) -> Object {
+    assert!(id != PLAYER, "the player can't be removed from the level");
+    objects.remove(id)
 }
 
 // an item that can be picked up and used.
 fn pick_item_up(object_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
+    if !has_room_for(&objects[object_id], &game.inventory) {
+        let name = game.object_name(&objects[object_id]);
+        game.log.add(format!("Your inventory is full, cannot pick up {}.", name), colors::RED);
+        return;
+    }
+
     // add to the player's inventory and remove from the map
-    if game.inventory.len() >= 26 {
-        game.log.add(format!("Your inventory is full, cannot pick up {}.", objects[object_id].name),
-                     colors::RED);
+    let item = remove_object(object_id, objects);
+    let name = game.object_name(&item);
+    if inventory_stack(&item, &game.inventory).is_some() {
+        game.log.add(format!("You picked up {}!", name), colors::GREEN);
     } else {
-        let item = objects.swap_remove(object_id);
-        game.log.add(format!("You picked up a {}!", item.name), colors::GREEN);
-        let inventory_id = game.inventory.len();
-        let equipment_slot = item.equipment.as_ref().map(|e| e.slot);
-        game.inventory.push(item);
-
-        // special case: automatically equip, if the corresponding equipment slot is unused
-        if let Some(equipment_slot) = equipment_slot {
-            if get_equipped_in_slot(equipment_slot, &game.inventory).is_none() {
-                game.inventory[inventory_id].equip(&mut game.log);
-            }
+        game.log.add(format!("You picked up a {}!", name), colors::GREEN);
+    }
+    let equipment = item.equipment;
+    let inventory_id = add_to_inventory(item, game);
+
+    // special case: automatically equip, if the corresponding equipment slot is unused
+    // (and the other hand doesn't get in the way)
+    if let Some(equipment) = equipment {
+        if get_equipped_in_slot(equipment.slot, &game.inventory).is_none() &&
+            hand_conflict(&equipment, &game.inventory).is_none() {
+            game.inventory[inventory_id].equip(&mut game.log);
         }
     }
 }
 
-fn use_item(inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) {
+fn use_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
     // just call the "use_item" if it is defined
     if let Some(item) = game.inventory[inventory_id].item {
+        // don't waste the item if it can't possibly do anything (the player
+        // can't know that about things they haven't identified, though)
+        let identified = game.unidentified_name(item).is_none();
+        if let Some(reason) = item.pointless_reason(objects, game, tcod).filter(|_| identified) {
+            game.log.add(reason, colors::RED);
+            return;
+        }
         match item.use_item(inventory_id, objects, game, tcod) {
             UseResult::UsedUp => {
                 // destroy after use, unless it was cancelled for some reason
-                game.inventory.remove(inventory_id);
+                use_up_one(inventory_id, game);
+                game.identify(item);
+            }
+            UseResult::UsedAndKept => {
+                // This item can be used multiple times, don't remove it
+                game.identify(item);
             }
-            UseResult::UsedAndKept => {},  // This item can be used multiple times, don't remove it
             UseResult::Cancelled => {
                 game.log.add("Cancelled", colors::WHITE);
             }
@@ -355,23 +949,164 @@ fn use_item(inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod:
     }
 }
 
+fn throw_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    let item = game.inventory[inventory_id].item;
+    match item {
+        Some(item) if item.category() == ItemCategory::Potion => {
+            match throw_potion(item, inventory_id, objects, game, tcod) {
+                UseResult::UsedUp => {
+                    // what it did gives away what it was
+                    use_up_one(inventory_id, game);
+                    game.identify(item);
+                }
+                UseResult::UsedAndKept => {}
+                UseResult::Cancelled => {
+                    game.log.add("Cancelled", colors::WHITE);
+                }
+            }
+        }
+        _ => {
+            game.log.add("You can only throw potions.", colors::WHITE);
+        }
+    }
+}
+
+/// throw a potion at a tile, where it shatters and does its thing on whoever is there
+fn throw_potion(item: Item, inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    game.log.add("Left-click where to throw it, or right-click to cancel.", colors::LIGHT_CYAN);
+    let (x, y) = match target_tile(objects, game, tcod, Some(THROW_RANGE)) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    // just the one potion, not the whole stack
+    let name = game.unidentified_name(item).map(|name| name.to_owned())
+        .unwrap_or_else(|| game.inventory[inventory_id].name.clone());
+    game.log.add(format!("The {} shatters!", name), colors::WHITE);
+    let power = game.data.item(item).power;
+    match item {
+        Item::Heal => {
+            // heals whoever it lands on, even a monster
+            let target = objects.iter().position(|obj| obj.pos() == (x, y) && obj.fighter.is_some());
+            match target {
+                Some(id) => {
+                    game.log.add(format!("The {} looks healthier!", objects[id].name),
+                                 colors::LIGHT_VIOLET);
+                    objects[id].fighter.as_mut().unwrap().heal(power);
+                }
+                None => game.log.add("The potion soaks into the floor.", colors::LIGHT_GREY),
+            }
+        }
+        Item::PoisonVial => {
+            // find every fighter in the splash, including the player
+            let poisoned_objects: Vec<_> = objects.iter()
+                .enumerate()
+                .filter(|&(_id, obj)| obj.distance(x, y) <= SPLASH_RADIUS as f32 && obj.fighter.is_some())
+                .map(|(id, _obj)| id)
+                .collect();
+            for &id in &poisoned_objects {
+                game.log.add(format!("The {} is poisoned!", objects[id].name), colors::LIME);
+                objects[id].add_effect(StatusEffect {
+                    kind: StatusEffectKind::Poison,
+                    turns_left: POISON_TURNS,
+                    magnitude: power,
+                });
+            }
+        }
+        _ => {}
+    }
+    UseResult::UsedUp
+}
+
+/// the text of the examine screen for an item
+fn describe_item(object: &Object, game: &Game) -> String {
+    let text = match object.item {
+        Some(item) if game.unidentified_name(item).is_some() => {
+            "You won't know what it does until you try it.".to_owned()
+        }
+        // the equipment of this one, which might be cursed
+        Some(_) if object.equipment.is_some() => object.equipment.unwrap().describe(),
+        Some(item) => item.describe(game.data.item(item)),
+        None => "It can't be used.".to_owned(),
+    };
+    format!("{}\n\n{}", game.object_name(object), text)
+}
+
+/// use up one item from an inventory stack, and the stack itself once it's empty
+fn use_up_one(inventory_id: usize, game: &mut Game) {
+    game.inventory[inventory_id].count -= 1;
+    if game.inventory[inventory_id].count <= 0 {
+        game.inventory.remove(inventory_id);
+    }
+}
+
+/// the stack of the inventory an item would join, if it stacks
+fn inventory_stack(item: &Object, inventory: &[Object]) -> Option<usize> {
+    item.item.and_then(|kind| {
+        if kind.is_stackable() {
+            inventory.iter().position(|obj| obj.item == Some(kind))
+        } else {
+            None
+        }
+    })
+}
+
+/// items that stack always fit, anything else needs a free inventory slot
+fn has_room_for(item: &Object, inventory: &[Object]) -> bool {
+    inventory_stack(item, inventory).is_some() || inventory.len() < MAX_INVENTORY
+}
+
+/// put an item in the inventory, on top of a stack of the same kind if it
+/// stacks, and return where it ended up
+fn add_to_inventory(item: Object, game: &mut Game) -> usize {
+    match inventory_stack(&item, &game.inventory) {
+        Some(stack_id) => {
+            game.inventory[stack_id].count += item.count;
+            stack_id
+        }
+        None => {
+            game.inventory.push(item);
+            game.inventory.len() - 1
+        }
+    }
+}
+
 fn drop_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
-    let mut item = game.inventory.remove(inventory_id);
-    item.dequip(&mut game.log);
+    if !game.inventory[inventory_id].dequip(&mut game.log) {
+        return;
+    }
+    // only one from a stack
+    let mut item = if game.inventory[inventory_id].count > 1 {
+        let mut item = game.inventory[inventory_id].clone();
+        item.uid = NEXT_OBJECT_UID.fetch_add(1, atomic::Ordering::SeqCst);
+        item.count = 1;
+        game.inventory[inventory_id].count -= 1;
+        item
+    } else {
+        game.inventory.remove(inventory_id)
+    };
     let (px, py) = objects[PLAYER].pos();
     item.set_pos(px, py);
-    game.log.add(format!("You dropped a {}.", item.name), colors::YELLOW);
+    let name = game.object_name(&item);
+    game.log.add(format!("You dropped a {}.", name), colors::YELLOW);
     objects.push(item);
 }
 
 
-#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct Fighter {
     base_max_hp: i32,
     hp: i32,
     base_defense: i32,
+    base_evasion: i32,
     base_power: i32,
+    base_spell_power: i32,
     xp: i32,
+    // chance (in percent) to shrug off confusion and other control effects
+    control_resistance: i32,
+    // number of turns this fighter will keep burning
+    burning: i32,
+    // a hit that leaves it with at least this many HP splits it in two, see `split_monsters`
+    splits_at: Option<i32>,
     death: Option<DeathCallback>,
 }
 
@@ -385,10 +1120,51 @@ impl Fighter {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum StatusEffectKind {
+    Poison,
+    Regen,
+    Slow,
+    Confused,
+}
+
+impl StatusEffectKind {
+    /// the letter and color shown next to the HP bar while it's active
+    fn icon(&self) -> (char, Color) {
+        use StatusEffectKind::*;
+        match *self {
+            Poison => ('P', colors::LIME),
+            Regen => ('R', colors::LIGHT_RED),
+            Slow => ('S', colors::LIGHT_BLUE),
+            Confused => ('C', colors::LIGHT_PURPLE),
+        }
+    }
+
+    fn expired_message(&self) -> &'static str {
+        use StatusEffectKind::*;
+        match *self {
+            Poison => "is no longer poisoned",
+            Regen => "stops regenerating",
+            Slow => "is no longer slowed",
+            Confused => "is no longer confused",
+        }
+    }
+}
+
+/// something that affects a fighter for a number of turns
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct StatusEffect {
+    kind: StatusEffectKind,
+    turns_left: i32,
+    // the damage (poison) or healing (regen) every turn, unused when slowed
+    magnitude: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum DeathCallback {
     Monster,
     Player,
+    Ally,
 }
 
 impl DeathCallback {
@@ -397,6 +1173,7 @@ impl DeathCallback {
         let callback: fn(&mut Object, &mut Game) = match *self {
             Monster => monster_death,
             Player => player_death,
+            Ally => ally_death,
         };
         callback(object, game);
     }
@@ -404,18 +1181,66 @@ impl DeathCallback {
 
 
 
-#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+/// the side an object fights on
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Faction {
+    // the player and the allies they summon
+    Player,
+    Monster,
+}
+
+/// the closest living fighter of another faction that `id` knows about: with the
+/// monster in the player's view, that's every enemy in view too (if you can see
+/// it, it can see you), otherwise only the ones right next to it. With only the
+/// player to fight, that's the player whenever the monster is in view.
+fn nearest_enemy(id: usize, objects: &[Object], fov: &Sight) -> Option<usize> {
+    let object = &objects[id];
+    let in_view = fov.is_in_fov(object.x, object.y);
+    objects.iter()
+        .enumerate()
+        .filter(|&(other_id, other)| {
+            other_id != id && other.alive && other.fighter.is_some() && other.faction != object.faction &&
+                (object.distance_to(other) < 2.0 || (in_view && fov.is_in_fov(other.x, other.y)))
+        })
+        .min_by(|&(_, a), &(_, b)| object.distance_to(a).partial_cmp(&object.distance_to(b)).unwrap())
+        .map(|(other_id, _)| other_id)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum MonsterAIType {
     Basic,
     Confused {
         num_turns: i32,
     },
+    // keeps its distance and shoots
+    Ranged {
+        range: i32,
+        damage: i32,
+    },
+    // fights like a basic monster, but runs away below this fraction of its max HP
+    Cowardly {
+        flee_threshold: f32,
+    },
+    // does nothing until it wakes up, then goes back to its old AI
+    Sleeping,
+    // keeps away from the player and calls monsters drawn as `summon` to help,
+    // every `interval` turns and at most `summons_left` more times
+    Summoner {
+        summon: char,
+        interval: i32,
+        cooldown: i32,
+        summons_left: i32,
+    },
 }
 
-#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct MonsterAI {
     old_ai: Option<Box<MonsterAI>>,
     ai_type: MonsterAIType,
+    // where the monster last saw the player, so it can go looking there
+    last_seen_player: Option<(i32, i32)>,
+    // whether it's running away from the player
+    fleeing: bool,
 }
 
 impl MonsterAI {
@@ -424,40 +1249,206 @@ impl MonsterAI {
         match self.ai_type {
             Basic => self.monster_basic_ai(monster_id, objects, game, tcod),
             Confused{mut num_turns} => self.monster_confused_ai(monster_id, &mut num_turns, objects, game, tcod),
+            Ranged{range, damage} => self.monster_ranged_ai(monster_id, range, damage, objects, game, tcod),
+            Cowardly{flee_threshold} => self.monster_cowardly_ai(monster_id, flee_threshold, objects, game, tcod),
+            Sleeping => self.monster_sleeping_ai(monster_id, objects, game, tcod),
+            Summoner{..} => self.monster_summoner_ai(monster_id, objects, game, tcod),
         }
     }
 
     fn monster_basic_ai(&mut self, monster_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> Option<MonsterAI> {
-        // a basic monster takes its turn. If you can see it, it can see you
+        // a basic monster takes its turn: it goes for the nearest enemy, the player or one of their allies
+        match nearest_enemy(monster_id, objects, &tcod.fov_map) {
+            Some(target_id) => {
+                // move towards the target if far away
+                let distance = objects[monster_id].distance_to(&objects[target_id]);
+                if distance >= 2.0 {
+                    let target_pos = objects[target_id].pos();
+                    chase(monster_id, target_pos, objects, game);
+                } else if objects[target_id].fighter.as_ref().map_or(false, |fighter| fighter.hp > 0) {
+                    // close enough, attack! (if the target is still alive.)
+                    let (monster, target) = mut_two(monster_id, target_id, objects);
+                    monster.attack(target, game);
+                }
+            }
+            None if objects[monster_id].is_ally() &&
+                objects[monster_id].distance_to(&objects[PLAYER]) > ALLY_FOLLOW_DISTANCE => {
+                // nothing to fight, keep up with the player
+                let player_pos = objects[PLAYER].pos();
+                chase(monster_id, player_pos, objects, game);
+            }
+            None => {}
+        }
+        None
+    }
+
+    fn monster_ranged_ai(&mut self, monster_id: usize, range: i32, damage: i32, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> Option<MonsterAI> {
         let (monster_x, monster_y) = objects[monster_id].pos();
-        if tcod.fov_map.is_in_fov(monster_x, monster_y) {
-            // move towards player if far away
-            let distance = {
-                let monster = &objects[monster_id];
-                let player = &objects[PLAYER];
-                monster.distance_to(player)
-            };
-            if distance >= 2.0 {
-                let (player_x, player_y) = objects[PLAYER].pos();
-                move_towards(monster_id, player_x, player_y, objects, game);
-            } else if objects[PLAYER].fighter.as_ref().map_or(
-                false, |fighter| fighter.hp > 0) {
-                // close enough, attack! (if the player is still alive.)
-                let (monster, player) = mut_two(monster_id, PLAYER, objects);
-                monster.attack(player, game);
+        if let Some(target_id) = nearest_enemy(monster_id, objects, &tcod.fov_map) {
+            let target_pos = objects[target_id].pos();
+            if target_id == PLAYER {
+                self.last_seen_player = Some(target_pos);
+            }
+            let distance = objects[monster_id].distance_to(&objects[target_id]);
+            // back away from a target that gets too close, if there's room to
+            let (dx, dy) = direction_towards(target_pos, (monster_x, monster_y));
+            if distance < 2.0 && !is_blocked(monster_x + dx, monster_y + dy, &game.map, objects) {
+                move_by(monster_id, dx, dy, objects, game);
+            } else if distance <= range as f32 {
+                if objects[target_id].fighter.as_ref().map_or(false, |fighter| fighter.hp > 0) {
+                    let target_name = if target_id == PLAYER {
+                        "you".to_owned()
+                    } else {
+                        format!("your {}", objects[target_id].name)
+                    };
+                    let hit_chance = objects[monster_id].hit_chance(&objects[target_id], game);
+                    if game.rng.gen::<f32>() < hit_chance {
+                        // armor works against arrows like against everything else
+                        let defense = objects[target_id].full_defense(game);
+                        let damage = game.settings.combat_model.base_damage(damage, defense);
+                        if damage > 0 {
+                            game.log.add(format!("The {} shoots an arrow at {} for {} hit points.",
+                                                 objects[monster_id].name, target_name, damage),
+                                         colors::WHITE);
+                            objects[target_id].take_damage(damage, game);
+                        } else {
+                            game.log.add(format!("The {}'s arrow bounces off {}.",
+                                                 objects[monster_id].name, target_name),
+                                         colors::LIGHT_GREY);
+                        }
+                    } else {
+                        game.log.add(format!("The {} shoots an arrow at {}, but misses.",
+                                             objects[monster_id].name, target_name),
+                                     colors::LIGHT_GREY);
+                    }
+                }
+            } else {
+                chase(monster_id, target_pos, objects, game);
+            }
+        } else if let Some(target) = self.last_seen_player {
+            // go where the player was last seen, then give up
+            if (monster_x, monster_y) == target {
+                self.last_seen_player = None;
+            } else {
+                chase(monster_id, target, objects, game);
+            }
+        }
+        None
+    }
+
+    fn monster_cowardly_ai(&mut self, monster_id: usize, flee_threshold: f32, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> Option<MonsterAI> {
+        let scared = objects[monster_id].fighter.as_ref().map_or(false, |fighter| {
+            (fighter.hp as f32) < fighter.base_max_hp as f32 * flee_threshold
+        });
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if !scared || !tcod.fov_map.is_in_fov(monster_x, monster_y) {
+            self.fleeing = false;
+            return self.monster_basic_ai(monster_id, objects, game, tcod);
+        }
+
+        if self.flee(monster_id, objects, game) {
+            None
+        } else {
+            // cornered: turn and fight
+            self.monster_basic_ai(monster_id, objects, game, tcod)
+        }
+    }
+
+    /// take a step away from the player, if there's anywhere to go
+    fn flee(&mut self, monster_id: usize, objects: &mut [Object], game: &mut Game) -> bool {
+        // run straight away from the player, or failing that any way that gets further from them
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        let (player_x, player_y) = objects[PLAYER].pos();
+        let distance = |x: i32, y: i32| (x - player_x).pow(2) + (y - player_y).pow(2);
+        let (away_x, away_y) = direction_towards((player_x, player_y), (monster_x, monster_y));
+        let mut steps = vec![(away_x, away_y)];
+        for dx in -1..2 {
+            for dy in -1..2 {
+                steps.push((dx, dy));
+            }
+        }
+        let flee_step = steps.into_iter().find(|&(dx, dy)| {
+            let (x, y) = (monster_x + dx, monster_y + dy);
+            distance(x, y) > distance(monster_x, monster_y) && !is_blocked(x, y, &game.map, objects)
+        });
+        match flee_step {
+            Some((dx, dy)) => {
+                if !self.fleeing {
+                    game.log.add(format!("The {} flees in terror!", objects[monster_id].name),
+                                 colors::LIGHT_YELLOW);
+                    self.fleeing = true;
+                }
+                move_by(monster_id, dx, dy, objects, game);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn monster_summoner_ai(&mut self, monster_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> Option<MonsterAI> {
+        let (summon, interval, cooldown, summons_left) = match self.ai_type {
+            MonsterAIType::Summoner{summon, interval, cooldown, summons_left} => {
+                (summon, interval, cooldown, summons_left)
+            }
+            _ => unreachable!(),
+        };
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if !tcod.fov_map.is_in_fov(monster_x, monster_y) {
+            self.fleeing = false;
+            return None;
+        }
+        let cooldown = cooldown - 1;
+        let mut summons_left = summons_left;
+        if objects[monster_id].distance_to(&objects[PLAYER]) < SUMMONER_SAFE_DISTANCE {
+            if !self.flee(monster_id, objects, game) {
+                // cornered: turn and fight
+                self.monster_basic_ai(monster_id, objects, game, tcod);
+            }
+        } else if cooldown <= 0 && summons_left > 0 {
+            // somewhere free right next to it
+            let spots: Vec<_> = (-1..2)
+                .flat_map(|dx| (-1..2).map(move |dy| (monster_x + dx, monster_y + dy)))
+                .filter(|&(x, y)| {
+                    !is_blocked(x, y, &game.map, objects) &&
+                        !game.spawned.iter().any(|obj| obj.pos() == (x, y))
+                })
+                .collect();
+            let def = game.data.monsters.iter().find(|def| def.char == summon).cloned();
+            if let (Some(&(x, y)), Some(def)) = (game.rng.choose(&spots), def) {
+                game.log.add(format!("The {} summons help!", objects[monster_id].name),
+                             colors::LIGHT_PURPLE);
+                game.spawned.push(make_monster(&def, x, y));
+                summons_left -= 1;
             }
         }
+        let cooldown = if cooldown <= 0 { interval } else { cooldown };
+        self.ai_type = MonsterAIType::Summoner {
+            summon: summon,
+            interval: interval,
+            cooldown: cooldown,
+            summons_left: summons_left,
+        };
         None
     }
 
+    fn monster_sleeping_ai(&mut self, monster_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> Option<MonsterAI> {
+        // with the player in view, it may notice them and wake up
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        if tcod.fov_map.is_in_fov(monster_x, monster_y) && game.rng.gen::<f32>() < WAKE_UP_CHANCE {
+            // this AI was taken out for the turn: put it back to wake the monster up
+            objects[monster_id].ai = Some(self.clone());
+            objects[monster_id].wake_up(game);
+            objects[monster_id].ai.take()
+        } else {
+            None
+        }
+    }
+
     fn monster_confused_ai(&mut self, monster_id: usize, num_turns: &mut i32, objects: &mut [Object], game: &mut Game, _tcod: &mut TcodState) -> Option<MonsterAI> {
         if *num_turns > 0 {  // still confused...
             // move in a random direction, and decrease the number of turns confused
-            move_by(monster_id,
-                    rand::thread_rng().gen_range(-1, 2),
-                    rand::thread_rng().gen_range(-1, 2),
-                    objects,
-                    game);
+            let (dx, dy) = (game.rng.gen_range(-1, 2), game.rng.gen_range(-1, 2));
+            move_by(monster_id, dx, dy, objects, game);
             *num_turns -= 1;
             None
         } else {  // restore the previous AI (this one will be deleted)
@@ -470,582 +1461,2138 @@ impl MonsterAI {
 }
 
 
-#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum Item {
     Heal,
     Lightning,
     Fireball,
     Confuse,
     Sword,
+    Greataxe,
     Shield,
+    Bow,
+    Helmet,
+    Armor,
+    Boots,
+    Arrow,
+    DetectMonsters,
+    Ration,
+    PoisonVial,
+    Identify,
+    RemoveCurse,
+    Mapping,
+    SummonAlly,
+    Enchant,
 }
 
 impl Item {
-    fn use_item(&self, inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    fn use_item(&self, inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
         use Item::*;
-        let callback: fn(usize, &mut [Object], &mut Game, &mut TcodState) -> UseResult = match *self {
+        let callback: fn(usize, &mut Vec<Object>, &mut Game, &mut TcodState) -> UseResult = match *self {
             Heal => cast_heal,
             Lightning => cast_lightning,
             Fireball => cast_fireball,
             Confuse => cast_confuse,
             Sword => equip_or_dequip,
+            Greataxe => equip_or_dequip,
             Shield => equip_or_dequip,
+            Bow => equip_or_dequip,
+            Helmet => equip_or_dequip,
+            Armor => equip_or_dequip,
+            Boots => equip_or_dequip,
+            Arrow => shoot_arrow,
+            DetectMonsters => cast_detect_monsters,
+            Ration => eat_ration,
+            PoisonVial => cast_poison,
+            Identify => cast_identify,
+            RemoveCurse => cast_remove_curse,
+            Mapping => cast_mapping,
+            SummonAlly => cast_summon_ally,
+            Enchant => cast_enchant,
         };
         callback(inventory_id, objects, game, tcod)
     }
-}
-
-enum UseResult {
-    UsedUp,
-    UsedAndKept,
-    Cancelled,
-}
 
-#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
-enum EquipmentSlot {
-    RightHand,
-    LeftHand,
-}
-
-impl std::fmt::Display for EquipmentSlot {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        use EquipmentSlot::*;
+    /// why using this item right now would just waste it, if that's the case
+    fn pointless_reason(&self, objects: &mut [Object], game: &Game, tcod: &TcodState) -> Option<&'static str> {
+        use Item::*;
+        match *self {
+            Heal => {
+                let player = &objects[PLAYER];
+                if player.fighter.as_ref().map_or(false, |f| f.hp >= player.full_max_hp(game)) {
+                    Some("You are already at full health.")
+                } else {
+                    None
+                }
+            }
+            Lightning if closest_monster(game.data.item(Lightning).range, objects, tcod).is_none() => {
+                Some("No enemy is close enough to strike.")
+            }
+            Confuse if closest_monster(game.data.item(Confuse).range, objects, tcod).is_none() => {
+                Some("No enemy is close enough to confuse.")
+            }
+            Arrow if equipped_bow(game).is_none() => {
+                Some("You need to wield a bow to shoot arrows.")
+            }
+            PoisonVial if closest_monster(game.data.item(PoisonVial).range, objects, tcod).is_none() => {
+                Some("No enemy is close enough to poison.")
+            }
+            RemoveCurse if !game.wears_cursed() => {
+                Some("Nothing you wear is cursed.")
+            }
+            Mapping if explored_percentage(&game.map) == 100 => {
+                Some("You already know every corner of this level.")
+            }
+            Identify if !game.carries_unidentified() => {
+                Some("You already know what everything you carry is.")
+            }
+            Enchant if !game.inventory.iter().any(|obj| {
+                obj.equipment.is_some() && game.data.base_equipment(obj).map_or(false, |e| e.can_be_enchanted())
+            }) => {
+                Some("You carry nothing the scroll could improve.")
+            }
+            Ration if game.hunger >= MAX_HUNGER => {
+                Some("You are too full to eat.")
+            }
+            _ => None,
+        }
+    }
+
+    fn category(&self) -> ItemCategory {
+        use Item::*;
+        match *self {
+            Heal | PoisonVial => ItemCategory::Potion,
+            Lightning | Fireball | Confuse | DetectMonsters | Identify | RemoveCurse | Mapping | SummonAlly |
+            Enchant => {
+                ItemCategory::Scroll
+            }
+            Sword | Greataxe | Shield | Bow | Helmet | Armor | Boots => ItemCategory::Equipment,
+            Arrow => ItemCategory::Ammo,
+            Ration => ItemCategory::Food,
+        }
+    }
+
+    /// whether it has to be identified before the player knows what it is
+    fn needs_identifying(&self) -> bool {
+        match self.category() {
+            ItemCategory::Potion | ItemCategory::Scroll => true,
+            _ => false,
+        }
+    }
+
+    /// what it does, with the numbers from its definition
+    fn describe(&self, def: &ItemDef) -> String {
+        use Item::*;
+        match *self {
+            Heal => format!("Heals {} hit points. Thrown, it heals whoever it hits.", def.power),
+            PoisonVial => format!("Thrown at an enemy up to {} tiles away, poisons it for {} damage a \
+                                   turn for {} turns.",
+                                  def.range, def.power, POISON_TURNS),
+            Lightning => format!("Strikes the closest enemy up to {} tiles away for {} damage, more \
+                                  with spell power.",
+                                 def.range, def.power),
+            Fireball => format!("Explodes where you choose, burning everything within {} tiles for \
+                                 {} damage, more with spell power.",
+                                def.range, def.power),
+            Confuse => format!("Confuses an enemy up to {} tiles away for {} turns, more as you level up.",
+                               def.range, def.power),
+            DetectMonsters => format!("Shows every monster on the level for {} turns.", def.power),
+            Identify => "Tells you what an unknown item is.".into(),
+            RemoveCurse => "Lifts the curse of everything you're wearing.".into(),
+            Mapping => "Shows you the whole level.".into(),
+            SummonAlly if def.power > 0 => format!("Calls a {} to fight at your side for {} turns.", ALLY_NAME, def.power),
+            SummonAlly => format!("Calls a {} to fight at your side.", ALLY_NAME),
+            Enchant => format!("Improves a weapon or a piece of armor by {}, up to {:+} in all.",
+                               def.power, MAX_ENCHANTMENT),
+            Ration => format!("Food. Eating it fills you up by {} and helps your wounds close.", def.power),
+            Arrow => format!("Shot with a bow, it hits for {} damage plus the bow's bonus.", def.power),
+            Sword | Greataxe | Shield | Bow | Helmet | Armor | Boots => {
+                def.equipment.map_or(String::new(), |equipment| equipment.describe())
+            }
+        }
+    }
+
+    /// whether several of these take up a single inventory slot
+    fn is_stackable(&self) -> bool {
+        self.category() != ItemCategory::Equipment
+    }
+}
+
+/// broad kinds of items, used e.g. to decide what gets picked up automatically
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ItemCategory {
+    Potion,
+    Scroll,
+    Equipment,
+    Ammo,
+    Food,
+}
+
+impl std::fmt::Display for ItemCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use ItemCategory::*;
+        match *self {
+            Potion => write!(f, "potions"),
+            Scroll => write!(f, "scrolls"),
+            Equipment => write!(f, "equipment"),
+            Ammo => write!(f, "ammo"),
+            Food => write!(f, "food"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UseResult {
+    UsedUp,
+    UsedAndKept,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum EquipmentSlot {
+    RightHand,
+    LeftHand,
+    Head,
+    Body,
+    Feet,
+}
+
+/// every slot, in the order the character screen lists them
+const EQUIPMENT_SLOTS: &[EquipmentSlot] = &[EquipmentSlot::RightHand,
+                                            EquipmentSlot::LeftHand,
+                                            EquipmentSlot::Head,
+                                            EquipmentSlot::Body,
+                                            EquipmentSlot::Feet];
+
+impl std::fmt::Display for EquipmentSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use EquipmentSlot::*;
         match *self {
             RightHand => write!(f, "right hand"),
             LeftHand => write!(f, "left hand"),
+            Head => write!(f, "head"),
+            Body => write!(f, "body"),
+            Feet => write!(f, "feet"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Equipment {
     slot: EquipmentSlot,
     is_equipped: bool,
     power_bonus: i32,
     defense_bonus: i32,
+    evasion_bonus: i32,
     max_hp_bonus: i32,
+    spell_bonus: i32,
+    // takes up the left hand too, so it can't be used with a shield
+    two_handed: bool,
+    // can't be taken off once equipped, the player finds out when equipping it
+    cursed: bool,
+    // already part of the bonuses, kept to show it in the name
+    #[serde(default)]
+    enchantment: i32,
 }
 
-fn get_equipped_in_slot(slot: EquipmentSlot, inventory: &[Object]) -> Option<usize> {
-    for (inventory_id, item) in inventory.iter().enumerate() {
-        if item.equipment.as_ref().map_or(false, |e| e.is_equipped && e.slot == slot) {
-            return Some(inventory_id)
+impl Equipment {
+    /// cursed equipment does the opposite of what it should
+    fn curse(&mut self) {
+        self.cursed = true;
+        self.power_bonus = -self.power_bonus;
+        self.defense_bonus = -self.defense_bonus;
+        self.evasion_bonus = -self.evasion_bonus;
+        self.max_hp_bonus = -self.max_hp_bonus;
+        self.spell_bonus = -self.spell_bonus;
+    }
+
+    /// turn the bonuses of cursed equipment back the right way round
+    fn lift_curse(&mut self) {
+        if self.cursed {
+            self.curse();
+            self.cursed = false;
         }
     }
-    None
-}
 
-fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    // first test the map tile
-    if map[x as usize][y as usize].blocked {
-        return true;
+    /// Make it better or worse than usual: weapons hit harder, the rest
+    /// protects more. Which one it is comes from `base`, the equipment as
+    /// defined, since enchantments can bring a bonus down to 0.
+    fn enchant(&mut self, base: &Equipment, enchantment: i32) {
+        self.enchantment += enchantment;
+        // the bonuses of cursed equipment are the wrong way round already
+        let change = if self.cursed { -enchantment } else { enchantment };
+        if base.power_bonus != 0 {
+            self.power_bonus += change;
+        } else {
+            self.defense_bonus += change;
+        }
+    }
+
+    /// only equipment meant for attack or defense gets enchanted, ask the
+    /// definition (the unenchanted `base` of `enchant`)
+    fn can_be_enchanted(&self) -> bool {
+        self.power_bonus != 0 || self.defense_bonus != 0
     }
-    // now check for any blocking objects
-    objects.iter().any(|object| {
-        object.blocks && object.pos() == (x, y)
-    })
-}
 
-fn create_room(room: Rect, map: &mut Map) {
-    // go through the tiles in the rectangle and make them passable
-    for x in (room.x1 + 1)..room.x2 {
-        for y in (room.y1 + 1)..room.y2 {
-            let (x, y) = (x as usize, y as usize);
-            map[x][y].blocked = false;
-            map[x][y].block_sight = false;
+    /// what it seems to be. A curse only shows once it's worn.
+    fn looks(&self) -> Equipment {
+        let mut looks = *self;
+        if self.cursed && !self.is_equipped {
+            looks.curse();
         }
+        looks
     }
-}
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    // horizontal tunnel. `min()` and `max()` are used in case `x1 > x2`
-    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        let (x, y) = (x as usize, y as usize);
-        map[x][y].blocked = false;
-        map[x][y].block_sight = false;
+    /// what the player can tell about it
+    fn describe(&self) -> String {
+        let mut text = format!("Worn on the {}: {}.", self.slot, self.looks().bonus_summary());
+        if self.two_handed {
+            text.push_str(" It takes both hands, so it can't be used with a shield.");
+        }
+        if self.cursed && self.is_equipped {
+            text.push_str(" It is cursed, and can't be taken off.");
+        }
+        text
     }
-}
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        let (x, y) = (x as usize, y as usize);
-        map[x][y].blocked = false;
-        map[x][y].block_sight = false;
+    /// the bonuses it gives, e.g. "+3 attack, +1 defense"
+    fn bonus_summary(&self) -> String {
+        let bonuses = [(self.power_bonus, "attack"),
+                       (self.defense_bonus, "defense"),
+                       (self.evasion_bonus, "evasion"),
+                       (self.max_hp_bonus, "HP"),
+                       (self.spell_bonus, "spell power")];
+        let parts: Vec<_> = bonuses.iter()
+            .filter(|&&(bonus, _)| bonus != 0)
+            .map(|&(bonus, name)| format!("{:+} {}", bonus, name))
+            .collect();
+        if parts.is_empty() {
+            "no bonus".into()
+        } else {
+            parts.join(", ")
+        }
     }
 }
 
-fn make_map(objects: &mut Vec<Object>,
-            level: i32)
-            -> Map {
-    // fill map with "blocked" tiles
-    let mut map = vec![vec![Tile{blocked: true, explored: false, block_sight: true};
-                            MAP_HEIGHT as usize];
-                       MAP_WIDTH as usize];
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TrapKind {
+    Spikes,
+    Confusion,
+    Teleport,
+}
 
-    objects.truncate(1);  // Player is the first element, remove everything else
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Trap {
+    kind: TrapKind,
+    discovered: bool,
+}
 
-    let mut rooms = vec![];
+fn make_trap(x: i32, y: i32, kind: TrapKind) -> Object {
+    let (name, color) = match kind {
+        TrapKind::Spikes => ("spike trap", colors::LIGHT_GREY),
+        TrapKind::Confusion => ("confusion trap", colors::LIGHT_PURPLE),
+        TrapKind::Teleport => ("teleport trap", colors::LIGHT_CYAN),
+    };
+    let mut trap = Object::new(x, y, '^', name, color, false);
+    trap.trap = Some(Trap { kind: kind, discovered: false });
+    trap
+}
 
-    for _ in 0..MAX_ROOMS {
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Door {
+    open: bool,
+}
 
-        // "Rect" struct makes rectangles easier to work with
-        let new_room = Rect::new(x, y, w, h);
+/// a closed door; it blocks sight through its tile too, see `open_door`
+fn place_door(x: i32, y: i32, map: &mut Map, objects: &mut Vec<Object>) {
+    let mut door = Object::new(x, y, '+', "door", colors::SEPIA, true);
+    door.door = Some(Door { open: false });
+    door.always_visible = true;
+    map[x as usize][y as usize].block_sight = true;
+    objects.push(door);
+}
 
-        // run through the other rooms and see if they intersect with this one
-        let failed = rooms.iter().any(|other_room| new_room.intersect(other_room));
-        if !failed {
-            // this means there are no intersections, so this room is valid
+/// open the door with the given id, letting everything through
+fn open_door(id: usize, objects: &mut [Object], game: &mut Game) {
+    let door = &mut objects[id];
+    door.door = Some(Door { open: true });
+    door.blocks = false;
+    door.char = '\'';
+    game.map[door.x as usize][door.y as usize].block_sight = false;
+    game.fov_recompute = true;
+}
 
-            // "paint" it to the map's tiles
-            create_room(new_room, &mut map);
+/// whether there's a door at the given position that's closed
+fn closed_door_at(x: i32, y: i32, objects: &[Object]) -> Option<usize> {
+    objects.iter().position(|object| object.pos() == (x, y) && object.is_closed_door())
+}
 
-            // TODO: first time through, the player's position is "unitialised"
-            // to (0, 0) here. Therefore, it's possible to place a monster or
-            // item at the same position:
+/// put doors in some of the spots where a tunnel goes through the wall of a room
+fn place_doors<R: Rng>(rooms: &[Rect], map: &mut Map, objects: &mut Vec<Object>, rng: &mut R) {
+    let open = |map: &Map, x: i32, y: i32| !map[x as usize][y as usize].blocked;
+    let (width, height) = map_size(map);
+    for room in rooms {
+        // the walls around the room, without the corners
+        let horizontal = ((room.x1 + 1)..room.x2).flat_map(|x| vec![(x, room.y1), (x, room.y2)]);
+        let vertical = ((room.y1 + 1)..room.y2).flat_map(|y| vec![(room.x1, y), (room.x2, y)]);
+        let walls: Vec<_> = horizontal.map(|pos| (pos, true)).chain(vertical.map(|pos| (pos, false))).collect();
+        for ((x, y), along_x) in walls {
+            if x <= 0 || y <= 0 || x >= width - 1 || y >= height - 1 || !open(map, x, y) {
+                continue;
+            }
+            // only one tile wide, so tunnels that run along the wall don't get a row of doors
+            let (dx, dy) = if along_x { (1, 0) } else { (0, 1) };
+            let doorway = !open(map, x - dx, y - dy) && !open(map, x + dx, y + dy);
+            if doorway && !objects.iter().any(|object| object.pos() == (x, y)) &&
+                rng.gen::<f32>() < DOOR_CHANCE {
+                place_door(x, y, map, objects);
+            }
+        }
+    }
+}
 
-            // add some contents to this room, such as monsters
-            place_objects(new_room, &map, objects, level);
+/// an altar that can combine two items into a stronger one, once
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Altar {
+    used: bool,
+}
 
-            // center coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.center();
+/// the two ingredients and the result of each recipe an altar knows
+const RECIPES: &[(Item, Item, Item)] = &[
+    (Item::Confuse, Item::Confuse, Item::Lightning),
+    (Item::Lightning, Item::Lightning, Item::Fireball),
+    (Item::Confuse, Item::Lightning, Item::Fireball),
+];
 
-            if rooms.is_empty() {
-                let player = &mut objects[PLAYER];
-                // TODO: this is where we set player's position for the first
-                // time. This should happen before we place any objects,
-                // otherwise something could spawn here already.
+fn get_equipped_in_slot(slot: EquipmentSlot, inventory: &[Object]) -> Option<usize> {
+    for (inventory_id, item) in inventory.iter().enumerate() {
+        if item.equipment.as_ref().map_or(false, |e| e.is_equipped && e.slot == slot) {
+            return Some(inventory_id)
+        }
+    }
+    None
+}
 
-                // this is the first room, where the player starts at
-                player.set_pos(new_x, new_y);
+/// the equipped item in the other hand that can't be used together with this
+/// one: whatever is in the left hand of a two-handed weapon, or the two-handed
+/// weapon that's in the way of something for the left hand
+fn hand_conflict(equipment: &Equipment, inventory: &[Object]) -> Option<usize> {
+    if equipment.two_handed {
+        get_equipped_in_slot(EquipmentSlot::LeftHand, inventory)
+    } else if equipment.slot == EquipmentSlot::LeftHand {
+        get_equipped_in_slot(EquipmentSlot::RightHand, inventory).and_then(|id| {
+            if inventory[id].equipment.as_ref().map_or(false, |e| e.two_handed) {
+                Some(id)
             } else {
-                // all rooms after the first:
-                // connect it to the previous room with a tunnel
-
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
-
-                // draw a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
+                None
             }
+        })
+    } else {
+        None
+    }
+}
 
-            // finally, append the new room to the list
-            rooms.push(new_room);
+/// make sure the player doesn't arrive on a level inside a wall or a monster:
+/// if their tile isn't free, move them to the closest one that is
+fn unstrand_player(objects: &mut [Object], map: &Map) {
+    let (x, y) = objects[PLAYER].pos();
+    let nearest = {
+        let free = |&(x, y): &(i32, i32)| {
+            in_map(x, y, map) && !map[x as usize][y as usize].blocked &&
+                !objects[PLAYER + 1..].iter().any(|object| object.blocks && object.pos() == (x, y))
+        };
+        if free(&(x, y)) {
+            return;
         }
+        let (width, height) = map_size(map);
+        (0..width)
+            .flat_map(|x| (0..height).map(move |y| (x, y)))
+            .filter(free)
+            .min_by_key(|&(free_x, free_y)| (free_x - x).pow(2) + (free_y - y).pow(2))
+    };
+    if let Some((x, y)) = nearest {
+        objects[PLAYER].set_pos(x, y);
     }
+}
 
-    // create stairs at the center of the last room
-    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
-    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", colors::WHITE, false);
+fn paint_terrain<R: Rng>(room: Rect, map: &mut Map, rng: &mut R) {
+    let roll = rng.gen::<f32>();
+    let terrain = if roll < GRASS_CHANCE {
+        Terrain::Grass
+    } else if roll < GRASS_CHANCE + WATER_CHANCE {
+        Terrain::Water
+    } else if roll < GRASS_CHANCE + WATER_CHANCE + LAVA_CHANCE {
+        Terrain::Lava
+    } else {
+        return;
+    };
+    // a random rectangle inside the room
+    let x1 = rng.gen_range(room.x1 + 1, room.x2);
+    let y1 = rng.gen_range(room.y1 + 1, room.y2);
+    let x2 = rng.gen_range(x1, room.x2);
+    let y2 = rng.gen_range(y1, room.y2);
+    for x in x1..(x2 + 1) {
+        for y in y1..(y2 + 1) {
+            map[x as usize][y as usize].terrain = terrain;
+        }
+    }
+}
+
+fn place_stairs(x: i32, y: i32, objects: &mut Vec<Object>) {
+    let mut stairs = Object::new(x, y, '<', "stairs", colors::WHITE, false);
     stairs.always_visible = true;
     objects.push(stairs);
+}
+
+/// How the rooms of a level are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum MapStyle {
+    // random rectangles, dropped when they overlap
+    Classic,
+    // one room per leaf of a binary space partition of the map
+    Bsp,
+    // no rooms, the open space is grown by a cellular automaton
+    Caves,
+}
+
+impl MapStyle {
+    fn for_level(level: i32) -> Self {
+        if level % 3 == 0 {
+            MapStyle::Caves
+        } else if level % 2 == 1 {
+            MapStyle::Classic
+        } else {
+            MapStyle::Bsp
+        }
+    }
+}
 
+fn make_level_map<R: Rng>(style: MapStyle,
+                          config: &Config,
+                          data: &GameData,
+                          objects: &mut Vec<Object>,
+                          level: i32,
+                          difficulty: Difficulty,
+                          rng: &mut R)
+                          -> Map {
+    let mut map = match style {
+        MapStyle::Classic => make_map_rooms(config, data, objects, level, difficulty, rng),
+        MapStyle::Bsp => make_map_bsp(config, data, objects, level, difficulty, rng),
+        MapStyle::Caves => make_map_caves(config, data, objects, level, difficulty, rng),
+    };
+    if level % SHOP_LEVEL_INTERVAL == 0 && !place_shop_room(&mut map, objects, rng) {
+        place_shopkeeper(&map, objects, rng);
+    }
     map
 }
 
-#[derive(Clone, Copy, Debug)]
-enum MonsterType {
-    Orc,
-    Troll,
+fn make_shopkeeper(x: i32, y: i32) -> Object {
+    let mut keeper = Object::new(x, y, '@', "shopkeeper", colors::GOLD, true);
+    keeper.always_visible = true;
+    keeper.shopkeeper = true;
+    keeper
 }
 
-fn from_dungeon_level(table: &[(u32, i32)], level: i32) -> u32 {
-    // returns a value that depends on level. the table specifies
-    // what value occurs after each level, default is 0.
-    for &(value, table_level) in table.iter().rev() {
-        if level >= table_level {
-            return value;
+/// Dig a shop into solid rock, with a tunnel to the part of the level the
+/// player can reach and a shopkeeper in the middle. Returns false if there
+/// isn't enough rock left anywhere for it.
+fn place_shop_room<R: Rng>(map: &mut Map, objects: &mut Vec<Object>, rng: &mut R) -> bool {
+    let (width, height) = map_size(map);
+    for _ in 0..SHOP_ROOM_ATTEMPTS {
+        let x = rng.gen_range(0, width - SHOP_ROOM_WIDTH);
+        let y = rng.gen_range(0, height - SHOP_ROOM_HEIGHT);
+        let shop = Rect::new(x, y, SHOP_ROOM_WIDTH, SHOP_ROOM_HEIGHT);
+        // the room and its walls, so it doesn't open into anything but its tunnel
+        let solid = (shop.x1..(shop.x2 + 1)).all(|x| {
+            (shop.y1..(shop.y2 + 1)).all(|y| map[x as usize][y as usize].blocked)
+        });
+        if !solid {
+            continue;
         }
+        create_room(shop, map);
+        ensure_connected(objects[PLAYER].pos(), &[shop], map, rng);
+        let (keeper_x, keeper_y) = shop.center();
+        objects.push(make_shopkeeper(keeper_x, keeper_y));
+        return true;
     }
-    return 0;
+    false
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: i32) {
-    use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
-    let rng = &mut rand::thread_rng();
+/// put a shopkeeper on a free tile a few steps away from where the player starts
+fn place_shopkeeper<R: Rng>(map: &Map, objects: &mut Vec<Object>, rng: &mut R) {
+    let start = objects[PLAYER].pos();
+    let spots: Vec<_> = flood_fill(start, map).into_iter()
+        .filter(|&(x, y)| {
+            let distance = cmp::max((x - start.0).abs(), (y - start.1).abs());
+            distance >= 2 && distance <= 4 && !is_blocked(x, y, map, objects) &&
+                !objects.iter().any(|object| object.pos() == (x, y))
+        })
+        .collect();
+    if let Some(&(x, y)) = rng.choose(&spots) {
+        objects.push(make_shopkeeper(x, y));
+    }
+}
 
-    // maximum number of monsters per room
-    let max_monsters = from_dungeon_level(&[(2, 1), (3, 4), (5, 6)], level) as i32;
+/// puts the player, the vaults, the monsters and the items of a level in the
+/// rooms `make_map` digs
+struct LevelFurnisher<'a> {
+    data: &'a GameData,
+    objects: &'a mut Vec<Object>,
+    level: i32,
+    difficulty: Difficulty,
+    vaults: Vec<&'a Vault>,
+    // the vault the room being placed is for, if any
+    vault: Option<&'a Vault>,
+}
 
+impl<'a> Furnisher for LevelFurnisher<'a> {
+    fn room_size<R: Rng>(&mut self, first: bool, rng: &mut R) -> (i32, i32) {
+        // the player doesn't start in a vault
+        self.vault = if !first && rng.gen::<f32>() < VAULT_CHANCE {
+            rng.choose(&self.vaults).cloned()
+        } else {
+            None
+        };
+        // random width and height, or the vault's
+        match self.vault {
+            Some(vault) => (vault.width - 1, vault.height - 1),
+            None => (rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1),
+                     rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1)),
+        }
+    }
 
-    // choose random number of monsters
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+    fn furnish<R: Rng>(&mut self, room: Rect, first: bool, map: &mut Map, rng: &mut R) {
+        if first {
+            // this is the first room, where the player starts at. This has to
+            // happen before placing its contents: the player blocks the tile,
+            // so nothing gets put there
+            let (x, y) = room.center();
+            self.objects[PLAYER].set_pos(x, y);
+        }
 
-    // chance of each monster
-    let troll_chance = from_dungeon_level(&[(15, 3), (30, 5), (60, 7)], level);
-    let monster_chances = &mut [Weighted {weight: 80, item: MonsterType::Orc},
-                                Weighted {weight: troll_chance, item: MonsterType::Troll}];
-    let monster_choice = WeightedChoice::new(monster_chances);
+        if let Some(vault) = self.vault {
+            // vaults come with their own walls and contents
+            stamp_vault(vault, room, map, self.data, self.objects, rng);
+        } else {
+            // "paint" it to the map's tiles
+            create_room(room, map);
+            // some rooms get a patch of grass or a pool of water
+            paint_terrain(room, map, rng);
 
-    // maximum number of items per room
-    let max_items = from_dungeon_level(&[(1, 1), (2, 4)], level) as i32;
+            // add some contents to this room, such as monsters
+            place_objects(room, map, self.data, self.objects, self.level, self.difficulty, rng);
+        }
+    }
+}
 
-    // chance of each item (by default they have a chance of 0 at level 1, which then goes up)
-    let item_chances = &mut [Weighted {weight: 35, item: Item::Heal},
-                             Weighted {weight: from_dungeon_level(&[(25, 4)], level),
-                                       item: Item::Lightning},
-                             Weighted {weight: from_dungeon_level(&[(25, 6)], level),
-                                       item: Item::Fireball},
-                             Weighted {weight: from_dungeon_level(&[(10, 2)], level),
-                                       item: Item::Confuse},
-                             Weighted {weight: from_dungeon_level(&[(5, 4)], level),
-                                       item: Item::Sword},
-                             Weighted {weight: from_dungeon_level(&[(15, 8)], level),
-                                       item: Item::Shield}];
-    let item_choice = WeightedChoice::new(item_chances);
-
-    for _ in 0..num_monsters {
-        // choose random spot for this monster
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-
-        // only place it if the tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            let monster = match monster_choice.ind_sample(rng) {
-                MonsterType::Orc => {
-                    // create an orc
-                    let mut orc = Object::new(x, y, 'o', "orc", colors::DESATURATED_GREEN, true);
-                    orc.fighter = Some(
-                        Fighter{hp: 20, base_max_hp: 20, base_defense: 0, base_power: 4, xp: 35,
-                                death: Some(DeathCallback::Monster)});
-                    orc.alive = true;
-                    orc.ai = Some(MonsterAI{
-                        old_ai: None,
-                        ai_type: MonsterAIType::Basic,
-                    });
-                    orc
-                },
-                MonsterType::Troll => {
-                    // create a troll
-                    let mut troll = Object::new(x, y, 'T', "troll", colors::DARKER_GREEN, true);
-                    troll.fighter = Some(
-                        Fighter{hp: 30, base_max_hp: 30, base_defense: 2, base_power: 8, xp: 100,
-                                death: Some(DeathCallback::Monster)});
-                    troll.alive = true;
-                    troll.ai = Some(MonsterAI{
-                        old_ai: None,
-                        ai_type: MonsterAIType::Basic,
-                    });
-                    troll
-                },
-            };
+/// generate a level of rooms and tunnels; everything random about it comes from `rng`
+fn make_map_rooms<R: Rng>(config: &Config,
+                          data: &GameData,
+                          objects: &mut Vec<Object>,
+                          level: i32,
+                          difficulty: Difficulty,
+                          rng: &mut R)
+                          -> Map {
+    objects.truncate(1);  // Player is the first element, remove everything else
 
-            objects.push(monster);
-        }
+    let (mut map, rooms) = {
+        let mut furnisher = LevelFurnisher {
+            data: data,
+            objects: objects,
+            level: level,
+            difficulty: difficulty,
+            vaults: data.vaults.iter().filter(|vault| vault.allowed_on(data, level)).collect(),
+            vault: None,
+        };
+        make_map(config.map_width, config.map_height, MAX_ROOMS, &mut furnisher, rng)
+    };
+
+    // create stairs at the center of the last room
+    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
+    place_stairs(last_room_x, last_room_y, objects);
+
+    let start = objects[PLAYER].pos();
+    ensure_connected(start, &rooms, &mut map, rng);
+    place_doors(&rooms, &mut map, objects, rng);
+    if rng.gen::<f32>() < SECRET_ROOM_CHANCE {
+        place_secret_room(&rooms, &mut map, data, objects, level, difficulty, rng);
     }
+    assert!(flood_fill(start, &map).contains(&(last_room_x, last_room_y)),
+            "the stairs can't be reached on level {}", level);
 
-    // choose random number of items
-    let num_items = rand::thread_rng().gen_range(0, max_items + 1);
-    for _ in 0..num_items {
-        // choose random spot for this item
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    map
+}
 
-        // only place it if the tile is not blocked
-        if !is_blocked(x, y, map, objects) {
-            // create a healing potion
-            let item = match item_choice.ind_sample(rng) {
-                Item::Heal => {
-                    // create a healing potion
-                    let item_component = Item::Heal;
-                    let mut object = Object::new(x, y, '!', "healing potion",
-                                                 colors::VIOLET, false);
-                    object.item = Some(item_component);
-                    object
-                }
-                Item::Lightning => {
-                    // create a lightning bolt scroll
-                    let item_component = Item::Lightning;
-                    let mut object = Object::new(x, y, '#', "scroll of lightning bolt",
-                                                 colors::LIGHT_YELLOW, false);
-                    object.item = Some(item_component);
-                    object
-                }
-                Item::Fireball => {
-                    // create a fireball scroll
-                    let item_component = Item::Fireball;
-                    let mut object = Object::new(x, y, '#', "scroll of fireball",
-                                                 colors::LIGHT_YELLOW, false);
-                    object.item = Some(item_component);
-                    object
-                }
-                Item::Confuse => {
-                    // create a confuse scroll
-                    let item_component = Item::Confuse;
-                    let mut object = Object::new(x, y, '#', "scroll of confusion",
-                                                 colors::LIGHT_YELLOW, false);
-                    object.item = Some(item_component);
-                    object
-                }
-                Item::Sword => {
-                    // create a sword
-                    let equipment_component = Equipment{
-                        slot: EquipmentSlot::RightHand,
-                        is_equipped: false,
-                        power_bonus: 3,
-                        defense_bonus: 0,
-                        max_hp_bonus: 0,
-                    };
-                    let mut object = Object::new(x, y, '/', "sword", colors::SKY, false);
-                    object.equipment = Some(equipment_component);
-                    object.item = Some(Item::Sword);
-                    object
-                }
-                Item::Shield => {
-                    // create a sword
-                    let equipment_component = Equipment{
-                        slot: EquipmentSlot::LeftHand,
-                        is_equipped: false,
-                        power_bonus: 0,
-                        defense_bonus: 1,
-                        max_hp_bonus: 0,
-                    };
-                    let mut object = Object::new(x, y, '[', "shield", colors::DARKER_ORANGE, false);
-                    object.equipment = Some(equipment_component);
-                    object.item = Some(Item::Shield);
-                    object
+/// copy a vault onto the map, with its top-left corner at the corner of `room`
+fn stamp_vault<R: Rng>(vault: &Vault,
+                       room: Rect,
+                       map: &mut Map,
+                       data: &GameData,
+                       objects: &mut Vec<Object>,
+                       rng: &mut R) {
+    for (dy, row) in vault.grid.iter().enumerate() {
+        for (dx, &c) in row.iter().enumerate() {
+            let (x, y) = (room.x1 + dx as i32, room.y1 + dy as i32);
+            let tile = &mut map[x as usize][y as usize];
+            tile.blocked = c == '#';
+            tile.block_sight = c == '#';
+            if let Some(def) = data.monsters.iter().find(|def| def.char == c) {
+                let mut monster = make_monster(def, x, y);
+                if rng.gen::<f32>() < SLEEP_CHANCE {
+                    put_to_sleep(&mut monster);
                 }
-            };
-            objects.push(item);
+                objects.push(monster);
+            } else if let Some(def) = rng.choose(&vault_item_defs(c, data)) {
+                objects.push(make_item(def, x, y, rng));
+            }
         }
     }
 }
 
-fn render_bar(panel: &mut Offscreen,
-              x: i32,
-              y: i32,
-              total_width: i32,
-              name: &str,
-              value: i32,
-              maximum: i32,
-              bar_color: Color,
-              back_color: Color) {
-    // render a bar (HP, experience, etc). first calculate the width of the bar
-    let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
-
-    // render the background first
-    panel.set_default_background(back_color);
-    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
-
-    // now render the bar on top
-    panel.set_default_background(bar_color);
-    if bar_width > 0 {
-        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+/// carve a room right next to one of `rooms`, with only a secret wall between them
+fn place_secret_room<R: Rng>(rooms: &[Rect],
+                             map: &mut Map,
+                             data: &GameData,
+                             objects: &mut Vec<Object>,
+                             level: i32,
+                             difficulty: Difficulty,
+                             rng: &mut R) {
+    let (width, height) = map_size(map);
+    for _ in 0..SECRET_ROOM_ATTEMPTS {
+        let room = *rng.choose(rooms).unwrap();
+        let w = rng.gen_range(ROOM_MIN_SIZE / 2, ROOM_MIN_SIZE + 1);
+        let h = rng.gen_range(ROOM_MIN_SIZE / 2, ROOM_MIN_SIZE + 1);
+        // the new room shares one of the walls of `room`
+        let (secret_room, horizontal_wall) = match rng.gen_range(0, 4) {
+            0 => (Rect::new(room.x2, rng.gen_range(room.y1 - h + 2, room.y2 - 1), w, h), false),
+            1 => (Rect::new(room.x1 - w, rng.gen_range(room.y1 - h + 2, room.y2 - 1), w, h), false),
+            2 => (Rect::new(rng.gen_range(room.x1 - w + 2, room.x2 - 1), room.y2, w, h), true),
+            _ => (Rect::new(rng.gen_range(room.x1 - w + 2, room.x2 - 1), room.y1 - h, w, h), true),
+        };
+        if secret_room.x1 < 0 || secret_room.y1 < 0 ||
+            secret_room.x2 >= width || secret_room.y2 >= height {
+            continue;
+        }
+        // the spot has to be solid rock, so the secret wall is the only way in
+        let solid = (secret_room.x1..(secret_room.x2 + 1)).all(|x| {
+            (secret_room.y1..(secret_room.y2 + 1)).all(|y| map[x as usize][y as usize].blocked)
+        });
+        if !solid {
+            continue;
+        }
+        // somewhere along the shared wall, between the insides of both rooms
+        let (x, y) = if horizontal_wall {
+            let x1 = cmp::max(room.x1, secret_room.x1) + 1;
+            let x2 = cmp::min(room.x2, secret_room.x2) - 1;
+            let y = if secret_room.y1 == room.y2 { room.y2 } else { room.y1 };
+            (rng.gen_range(x1, x2 + 1), y)
+        } else {
+            let y1 = cmp::max(room.y1, secret_room.y1) + 1;
+            let y2 = cmp::min(room.y2, secret_room.y2) - 1;
+            let x = if secret_room.x1 == room.x2 { room.x2 } else { room.x1 };
+            (x, rng.gen_range(y1, y2 + 1))
+        };
+        // the room's side of the wall could be a wall of a vault
+        let (across_x, across_y) = if horizontal_wall { (0, 1) } else { (1, 0) };
+        if map[(x - across_x) as usize][(y - across_y) as usize].blocked &&
+            map[(x + across_x) as usize][(y + across_y) as usize].blocked {
+            continue;
+        }
+        create_room(secret_room, map);
+        map[x as usize][y as usize].secret = true;
+        place_objects(secret_room, map, data, objects, level, difficulty, rng);
+        return;
     }
-
-    // finally, some centered text with the values
-    panel.set_default_foreground(colors::WHITE);
-    panel.print_ex(x + total_width / 2, y, BackgroundFlag::None, TextAlignment::Center,
-                   &format!("{}: {}/{}", name, value, maximum));
 }
 
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    // return a string with the names of all objects under the mouse
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+/// generate a level by recursively splitting the map in two, carving a room in
+/// every piece that can't be split any further
+fn make_map_bsp<R: Rng>(config: &Config,
+                        data: &GameData,
+                        objects: &mut Vec<Object>,
+                        level: i32,
+                        difficulty: Difficulty,
+                        rng: &mut R)
+                        -> Map {
+    let mut map = filled_map(config.map_width, config.map_height);
+    objects.truncate(1);
+
+    // keep the outermost row and column solid, like `make_map` does
+    let rooms = bsp_carve(Rect::new(0, 0, config.map_width - 1, config.map_height - 1), &mut map, rng);
+
+    let (start_x, start_y) = rooms[0].center();
+    objects[PLAYER].set_pos(start_x, start_y);
+    for &room in &rooms {
+        paint_terrain(room, &mut map, rng);
+        place_objects(room, &map, data, objects, level, difficulty, rng);
+    }
 
-    // create a list with the names of all objects at the mouse's coordinates and in FOV
-    objects.iter().filter(
-        |obj| {
-            obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y)
-        }).map(|obj| obj.name.clone()).collect::<Vec<_>>().join(", ")
+    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
+    place_stairs(last_room_x, last_room_y, objects);
+
+    map
 }
 
-fn render_all(objects: &[Object], game: &mut Game, tcod: &mut TcodState) {
-    let player = &objects[PLAYER];
-    if game.fov_recompute {
-        game.fov_recompute = false;
-        let (player_x, player_y) = player.pos();
-        tcod.fov_map.compute_fov(player_x, player_y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+/// generate a cave level: random noise smoothed into caverns, of which only the
+/// largest is kept
+fn make_map_caves<R: Rng>(config: &Config,
+                          data: &GameData,
+                          objects: &mut Vec<Object>,
+                          level: i32,
+                          difficulty: Difficulty,
+                          rng: &mut R)
+                          -> Map {
+    objects.truncate(1);
+    let (width, height) = (config.map_width, config.map_height);
+
+    let (mut map, cave) = loop {
+        let mut map = filled_map(width, height);
+        // the border stays solid
+        for x in 1..(width - 1) {
+            for y in 1..(height - 1) {
+                let open = rng.gen::<f32>() >= CAVE_FILL_CHANCE;
+                map[x as usize][y as usize].blocked = !open;
+                map[x as usize][y as usize].block_sight = !open;
+            }
+        }
+        for _ in 0..CAVE_SMOOTHING_PASSES {
+            smooth_caves(&mut map);
+        }
 
-        // go through all tiles, and set their background color according to the FOV
-        for y in 0..MAP_HEIGHT {
-            for x in 0..MAP_WIDTH {
-                let visible = tcod.fov_map.is_in_fov(x, y);
-                let wall = game.map[x as usize][y as usize].block_sight;
-                if !visible {
-                    // if it's not visible right now, the player can only see if it's explored
-                    if game.map[x as usize][y as usize].explored {
-                        if wall {
-                            tcod.con.set_char_background(
-                                x, y, COLOR_DARK_WALL, BackgroundFlag::Set);
-                        } else {
-                            tcod.con.set_char_background(
-                                x, y, COLOR_DARK_GROUND, BackgroundFlag::Set);
-                        }
+        // split the open space into its separate pockets and keep the largest
+        let mut known = vec![vec![false; height as usize]; width as usize];
+        let mut cave = vec![];
+        for x in 0..width {
+            for y in 0..height {
+                if !map[x as usize][y as usize].blocked && !known[x as usize][y as usize] {
+                    let region = flood_fill((x, y), &map);
+                    for &(rx, ry) in &region {
+                        known[rx as usize][ry as usize] = true;
                     }
-                } else {
-                    // it's visible
-                    if wall {
-                        tcod.con.set_char_background(x, y, COLOR_LIGHT_WALL, BackgroundFlag::Set);
-                    } else {
-                        tcod.con.set_char_background(x, y, COLOR_LIGHT_GROUND, BackgroundFlag::Set);
+                    if region.len() > cave.len() {
+                        cave = region;
                     }
-                    // since it's visible, explore it
-                    game.map[x as usize][y as usize].explored = true;
                 }
             }
         }
-    }
+        // some seeds only grow a few small pockets, try again
+        if cave.len() >= (width * height / CAVE_MIN_OPEN_SHARE) as usize {
+            break (map, cave);
+        }
+    };
 
-    // Grab all renderable objects
-    let mut render_objects: Vec<_> = objects.iter().collect();
-    // Put the fighters first, then items, then everything else. This will not
-    // affect the order of the original objects vector.
-    render_objects.sort_by(|o1, o2| {
-        if o1.fighter.is_some() || o2.fighter.is_some() {
-            return o1.fighter.is_some().cmp(&o2.fighter.is_some());
+    // fill in every pocket that isn't part of the main cave
+    let mut in_cave = vec![vec![false; height as usize]; width as usize];
+    for &(x, y) in &cave {
+        in_cave[x as usize][y as usize] = true;
+    }
+    for x in 0..width as usize {
+        for y in 0..height as usize {
+            if !in_cave[x][y] {
+                map[x][y].blocked = true;
+                map[x][y].block_sight = true;
+            }
         }
-        if o1.item.is_some() || o2.item.is_some() {
-            return o1.item.is_some().cmp(&o2.item.is_some());
+    }
+
+    let (start_x, start_y) = cave[rng.gen_range(0, cave.len())];
+    objects[PLAYER].set_pos(start_x, start_y);
+
+    // there are no rooms to fill, so spread the contents over a grid of room-sized
+    // areas instead. `place_objects` skips the walls in them.
+    for x in 0..(width / ROOM_MAX_SIZE) {
+        for y in 0..(height / ROOM_MAX_SIZE) {
+            let area = Rect::new(x * ROOM_MAX_SIZE, y * ROOM_MAX_SIZE, ROOM_MAX_SIZE, ROOM_MAX_SIZE);
+            place_objects(area, &map, data, objects, level, difficulty, rng);
         }
-        Ordering::Equal
-    });
-    for object in &render_objects {
-        object.draw(&mut tcod.con, &game.map, &tcod.fov_map);
     }
 
-    // blit the contents of "con" to the root console
-    tcod::console::blit(&mut tcod.con,
-                        (0, 0),
-                        (MAP_WIDTH, MAP_HEIGHT),
-                        &mut tcod.root,
-                        (0, 0),
-                        1.0,
-                        1.0);
+    // the stairs go as far from the player as the cave allows
+    let (stairs_x, stairs_y) = *cave.iter()
+        .max_by_key(|&&(x, y)| (x - start_x).abs() + (y - start_y).abs())
+        .unwrap();
+    place_stairs(stairs_x, stairs_y, objects);
 
-    // prepare to render the GUI panel
+    map
+}
+
+/// one pass of the cave automaton: a tile becomes a wall when it has enough
+/// walls around it, and open ground otherwise
+fn smooth_caves(map: &mut Map) {
+    let previous = map.clone();
+    let (width, height) = map_size(map);
+    for x in 1..(width - 1) {
+        for y in 1..(height - 1) {
+            let mut walls = 0;
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    if (dx, dy) != (0, 0) && previous[(x + dx) as usize][(y + dy) as usize].blocked {
+                        walls += 1;
+                    }
+                }
+            }
+            let wall = walls >= CAVE_WALL_NEIGHBORS;
+            map[x as usize][y as usize].blocked = wall;
+            map[x as usize][y as usize].block_sight = wall;
+        }
+    }
+}
+
+/// carve the rooms of one node of the BSP tree and return them. Both halves of a
+/// split are connected to each other, so every room of the node is reachable.
+fn bsp_carve<R: Rng>(area: Rect, map: &mut Map, rng: &mut R) -> Vec<Rect> {
+    let (w, h) = (area.x2 - area.x1, area.y2 - area.y1);
+    let can_split_x = w >= 2 * BSP_MIN_LEAF_SIZE;
+    let can_split_y = h >= 2 * BSP_MIN_LEAF_SIZE;
+
+    if !can_split_x && !can_split_y {
+        // a leaf: one room somewhere inside it
+        let room_w = rng.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, w) + 1);
+        let room_h = rng.gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, h) + 1);
+        let x = rng.gen_range(area.x1, area.x2 - room_w + 1);
+        let y = rng.gen_range(area.y1, area.y2 - room_h + 1);
+        let room = Rect::new(x, y, room_w, room_h);
+        create_room(room, map);
+        return vec![room];
+    }
+
+    // cut across the longer side, so pieces don't end up as long thin strips
+    let split_x = can_split_x && (!can_split_y || w > h);
+    let (first, second) = if split_x {
+        let cut = rng.gen_range(area.x1 + BSP_MIN_LEAF_SIZE, area.x2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { x2: cut, ..area }, Rect { x1: cut, ..area })
+    } else {
+        let cut = rng.gen_range(area.y1 + BSP_MIN_LEAF_SIZE, area.y2 - BSP_MIN_LEAF_SIZE + 1);
+        (Rect { y2: cut, ..area }, Rect { y1: cut, ..area })
+    };
+
+    let mut rooms = bsp_carve(first, map, rng);
+    let second_rooms = bsp_carve(second, map, rng);
+
+    // link the two halves through a random room of each
+    let from = rooms[rng.gen_range(0, rooms.len())].center();
+    let to = second_rooms[rng.gen_range(0, second_rooms.len())].center();
+    create_tunnel_between(from, to, map, rng);
+
+    rooms.extend(second_rooms);
+    rooms
+}
+
+/// Everything needed to create a kind of monster. These are read from
+/// `MONSTERS_FILE` at startup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MonsterDef {
+    name: String,
+    char: char,
+    #[serde(with = "ColorDef")]
+    color: Color,
+    hp: i32,
+    defense: i32,
+    evasion: i32,
+    power: i32,
+    xp: i32,
+    control_resistance: i32,
+    ai_type: MonsterAIType,
+    // the chance of meeting one, in the (value, level) form `from_dungeon_level` takes
+    chances: Vec<(u32, i32)>,
+    // the percent chance of it coming with a pack of its own kind, in the same form
+    pack_chance: Vec<(u32, i32)>,
+    // see `Fighter::splits_at`
+    splits_at: Option<i32>,
+    // the chance of it leaving an item behind when it dies, and how likely each kind is
+    drop_chance: f32,
+    drops: Vec<(Item, u32)>,
+}
+
+/// the monsters to use when `MONSTERS_FILE` can't be read
+fn default_monster_defs() -> Vec<MonsterDef> {
+    vec![
+        MonsterDef {
+            name: "orc".into(), char: 'o', color: colors::DESATURATED_GREEN,
+            hp: 20, defense: 0, evasion: 0, power: 4, xp: 35, control_resistance: 0,
+            ai_type: MonsterAIType::Cowardly{flee_threshold: 0.35},
+            chances: vec![(80, 1)],
+            pack_chance: vec![(10, 4)],
+            splits_at: None,
+            drop_chance: 0.15,
+            drops: vec![(Item::Heal, 3), (Item::Ration, 1)],
+        },
+        MonsterDef {
+            name: "troll".into(), char: 'T', color: colors::DARKER_GREEN,
+            hp: 30, defense: 2, evasion: 0, power: 8, xp: 100, control_resistance: 25,
+            ai_type: MonsterAIType::Cowardly{flee_threshold: 0.2},
+            chances: vec![(15, 3), (30, 5), (60, 7)],
+            pack_chance: vec![],
+            splits_at: None,
+            drop_chance: 0.2,
+            drops: vec![(Item::Shield, 2), (Item::Helmet, 1)],
+        },
+        MonsterDef {
+            // shoots from afar
+            name: "goblin archer".into(), char: 'g', color: colors::DARKER_ORANGE,
+            hp: 12, defense: 0, evasion: 2, power: 2, xp: 60, control_resistance: 0,
+            ai_type: MonsterAIType::Ranged{range: 6, damage: 3},
+            chances: vec![(10, 3), (25, 5), (40, 7)],
+            pack_chance: vec![],
+            splits_at: None,
+            drop_chance: 0.3,
+            drops: vec![(Item::Arrow, 3), (Item::Bow, 1)],
+        },
+        MonsterDef {
+            // weak, but rarely alone
+            name: "jackal".into(), char: 'j', color: colors::LIGHT_SEPIA,
+            hp: 6, defense: 0, evasion: 1, power: 2, xp: 15, control_resistance: 0,
+            ai_type: MonsterAIType::Basic,
+            chances: vec![(20, 2), (30, 4)],
+            pack_chance: vec![(60, 2), (80, 4)],
+            splits_at: None,
+            drop_chance: 0.0,
+            drops: vec![],
+        },
+        MonsterDef {
+            // calls jackals from afar, and runs from a fight
+            name: "goblin shaman".into(), char: 's', color: colors::LIGHT_PURPLE,
+            hp: 14, defense: 0, evasion: 1, power: 2, xp: 80, control_resistance: 10,
+            ai_type: MonsterAIType::Summoner{summon: 'j', interval: 5, cooldown: 0, summons_left: 4},
+            chances: vec![(10, 4), (20, 6)],
+            pack_chance: vec![],
+            splits_at: None,
+            drop_chance: 0.5,
+            drops: vec![(Item::Confuse, 2), (Item::Lightning, 1)],
+        },
+        MonsterDef {
+            // every hit that doesn't kill it makes two smaller ones
+            name: "slime".into(), char: 'S', color: colors::LIGHT_GREEN,
+            hp: 24, defense: 0, evasion: 0, power: 3, xp: 20, control_resistance: 0,
+            ai_type: MonsterAIType::Basic,
+            chances: vec![(15, 2), (25, 4)],
+            pack_chance: vec![],
+            splits_at: Some(6),
+            drop_chance: 0.0,
+            drops: vec![],
+        },
+        MonsterDef {
+            // never met, only summoned by the player (see `ALLY_NAME`)
+            name: ALLY_NAME.into(), char: 'w', color: colors::LIGHT_BLUE,
+            hp: 16, defense: 1, evasion: 2, power: 4, xp: 0, control_resistance: 100,
+            ai_type: MonsterAIType::Basic,
+            chances: vec![],
+            pack_chance: vec![],
+            splits_at: None,
+            drop_chance: 0.0,
+            drops: vec![],
+        },
+    ]
+}
+
+fn make_monster(def: &MonsterDef, x: i32, y: i32) -> Object {
+    let mut monster = Object::new(x, y, def.char, &def.name, def.color, true);
+    monster.fighter = Some(
+        Fighter{hp: def.hp, base_max_hp: def.hp, base_defense: def.defense,
+                base_evasion: def.evasion, base_power: def.power, base_spell_power: 0, xp: def.xp, control_resistance: def.control_resistance,
+                burning: 0, splits_at: def.splits_at, death: Some(DeathCallback::Monster)});
+    monster.alive = true;
+    monster.ai = Some(MonsterAI{
+        old_ai: None,
+        ai_type: def.ai_type,
+        last_seen_player: None,
+        fleeing: false,
+    });
+    monster
+}
+
+/// keep the monster's AI for when it wakes up
+fn put_to_sleep(monster: &mut Object) {
+    let old_ai = monster.ai.take().map(Box::new);
+    monster.ai = Some(MonsterAI {
+        old_ai: old_ai,
+        ai_type: MonsterAIType::Sleeping,
+        last_seen_player: None,
+        fleeing: false,
+    });
+}
+
+/// wake up every sleeping monster within `radius` of the given position
+fn make_noise((x, y): (i32, i32), radius: f32, objects: &mut [Object], game: &mut Game) {
+    for object in objects.iter_mut().filter(|object| object.distance(x, y) <= radius) {
+        object.wake_up(game);
+    }
+}
+
+/// Everything needed to create a kind of item. These are read from
+/// `ITEMS_FILE` at startup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ItemDef {
+    // what it does when used
+    item: Item,
+    name: String,
+    char: char,
+    #[serde(with = "ColorDef")]
+    color: Color,
+    // how strong its effect is: HP healed, damage done, turns it lasts...
+    power: i32,
+    // how far its effect reaches, or the radius of the area it affects
+    range: i32,
+    equipment: Option<Equipment>,
+    // the chance of finding one, in the (value, level) form `from_dungeon_level` takes
+    chances: Vec<(u32, i32)>,
+}
+
+/// the items to use when `ITEMS_FILE` can't be read
+fn default_item_defs() -> Vec<ItemDef> {
+    let scroll = |item, name: &str, power, range, chances| ItemDef {
+        item: item, name: name.into(), char: '#', color: colors::LIGHT_YELLOW,
+        power: power, range: range, equipment: None, chances: chances,
+    };
+    let equipment = |slot, power_bonus, defense_bonus| Some(Equipment {
+        slot: slot, is_equipped: false, power_bonus: power_bonus, defense_bonus: defense_bonus,
+        evasion_bonus: 0, max_hp_bonus: 0, spell_bonus: 0, two_handed: false, cursed: false,
+        enchantment: 0,
+    });
+    let two_handed = |power_bonus| equipment(EquipmentSlot::RightHand, power_bonus, 0).map(|e| {
+        Equipment { two_handed: true, ..e }
+    });
+    vec![
+        ItemDef {
+            item: Item::Heal, name: "healing potion".into(), char: '!', color: colors::VIOLET,
+            power: 40, range: 0, equipment: None, chances: vec![(35, 1)],
+        },
+        ItemDef {
+            // the power is the damage every turn, for `POISON_TURNS` turns
+            item: Item::PoisonVial, name: "vial of poison".into(), char: '!', color: colors::LIME,
+            power: 2, range: 6, equipment: None, chances: vec![(10, 3)],
+        },
+        scroll(Item::Lightning, "scroll of lightning bolt", 40, 5, vec![(25, 4)]),
+        scroll(Item::Fireball, "scroll of fireball", 25, 3, vec![(25, 6)]),
+        // the power is the number of turns, for a level 1 caster
+        scroll(Item::Confuse, "scroll of confusion", 10, 8, vec![(10, 2)]),
+        scroll(Item::DetectMonsters, "scroll of detect monsters", 20, 0, vec![(10, 3)]),
+        scroll(Item::Identify, "scroll of identify", 0, 0, vec![(15, 2)]),
+        scroll(Item::RemoveCurse, "scroll of remove curse", 0, 0, vec![(10, 2)]),
+        scroll(Item::Mapping, "scroll of magic mapping", 0, 0, vec![(5, 3), (10, 6)]),
+        // the power is how many turns the ally stays, 0 for good
+        scroll(Item::SummonAlly, "scroll of summoning", 60, 0, vec![(10, 2)]),
+        // the power is how much the enchantment goes up
+        scroll(Item::Enchant, "scroll of enchantment", 1, 0, vec![(5, 3), (10, 6)]),
+        ItemDef {
+            item: Item::Sword, name: "sword".into(), char: '/', color: colors::SKY,
+            power: 0, range: 0, equipment: equipment(EquipmentSlot::RightHand, 3, 0),
+            chances: vec![(5, 4)],
+        },
+        ItemDef {
+            item: Item::Greataxe, name: "greataxe".into(), char: '\\', color: colors::SKY,
+            power: 0, range: 0, equipment: two_handed(6),
+            chances: vec![(5, 5), (10, 7)],
+        },
+        ItemDef {
+            item: Item::Shield, name: "shield".into(), char: '[', color: colors::DARKER_ORANGE,
+            power: 0, range: 0, equipment: equipment(EquipmentSlot::LeftHand, 0, 1),
+            chances: vec![(15, 8)],
+        },
+        ItemDef {
+            item: Item::Helmet, name: "helmet".into(), char: '(', color: colors::LIGHT_GREY,
+            power: 0, range: 0, equipment: equipment(EquipmentSlot::Head, 0, 1),
+            chances: vec![(10, 2)],
+        },
+        ItemDef {
+            item: Item::Armor, name: "chain mail".into(), char: ']', color: colors::LIGHT_GREY,
+            power: 0, range: 0, equipment: equipment(EquipmentSlot::Body, 0, 2),
+            chances: vec![(5, 4), (10, 6)],
+        },
+        ItemDef {
+            item: Item::Boots, name: "leather boots".into(), char: ';', color: colors::SEPIA,
+            power: 0, range: 0, equipment: Some(Equipment {
+                slot: EquipmentSlot::Feet, is_equipped: false, power_bonus: 0, defense_bonus: 0,
+                evasion_bonus: 1, max_hp_bonus: 0, spell_bonus: 0, two_handed: false,
+                cursed: false, enchantment: 0,
+            }),
+            chances: vec![(10, 3)],
+        },
+        ItemDef {
+            item: Item::Bow, name: "bow".into(), char: '}', color: colors::DARKER_ORANGE,
+            power: 0, range: 8, equipment: two_handed(2),
+            chances: vec![(5, 3)],
+        },
+        ItemDef {
+            // the power is how much hunger it restores
+            item: Item::Ration, name: "ration".into(), char: '%', color: colors::DARK_AMBER,
+            power: 800, range: 0, equipment: None, chances: vec![(20, 1)],
+        },
+        ItemDef {
+            // the power is the damage before the bow's bonus
+            item: Item::Arrow, name: "arrow".into(), char: '|', color: colors::LIGHT_SEPIA,
+            power: 4, range: 0, equipment: None, chances: vec![(15, 3)],
+        },
+    ]
+}
+
+/// Game content read from data files. It isn't part of the savegame: a loaded
+/// game uses whatever the files say now.
+#[derive(Clone, Debug, Default)]
+struct GameData {
+    monsters: Vec<MonsterDef>,
+    items: Vec<ItemDef>,
+    vaults: Vec<Vault>,
+}
+
+impl GameData {
+    /// read the data files, using the built-in content for the ones that can't be read
+    fn load() -> Self {
+        let monsters = match read_data_file(MONSTERS_FILE) {
+            Ok(monsters) => monsters,
+            Err(error) => {
+                eprintln!("Could not load {}: {}. Using the built-in monsters.", MONSTERS_FILE, error);
+                default_monster_defs()
+            }
+        };
+        let defaults = default_item_defs();
+        // every kind of item has to be defined, the game refers to them by kind
+        let items = read_data_file::<Vec<ItemDef>>(ITEMS_FILE).and_then(|items| {
+            match defaults.iter().find(|default| !items.iter().any(|def| def.item == default.item)) {
+                Some(missing) => Err(format!("no definition for {:?}", missing.item)),
+                None => Ok(items),
+            }
+        });
+        let items = match items {
+            Ok(items) => items,
+            Err(error) => {
+                eprintln!("Could not load {}: {}. Using the built-in items.", ITEMS_FILE, error);
+                defaults
+            }
+        };
+        let mut data = GameData { monsters: monsters, items: items, vaults: vec![] };
+        // the characters of a vault stand for monsters and items, so they're checked against those
+        let known = |c: char| {
+            c == '#' || c == '.' || data.monsters.iter().any(|def| def.char == c) ||
+                !vault_item_defs(c, &data).is_empty()
+        };
+        let vaults = read_vaults_file(VAULTS_FILE).and_then(|vaults| {
+            let mut chars = vaults.iter().flat_map(|vault| vault.grid.iter().flat_map(|row| row.iter()));
+            match chars.find(|&&c| !known(c)) {
+                Some(unknown) => Err(format!("no monster or item is drawn as '{}'", unknown)),
+                None => Ok(vaults),
+            }
+        });
+        data.vaults = match vaults {
+            Ok(vaults) => vaults,
+            Err(error) => {
+                eprintln!("Could not load {}: {}. Using the built-in vaults.", VAULTS_FILE, error);
+                parse_vaults(DEFAULT_VAULTS).expect("the built-in vaults are valid")
+            }
+        };
+        data
+    }
+
+    /// the definition of the given kind of item; the first one if there are several
+    fn item(&self, item: Item) -> &ItemDef {
+        self.items.iter().find(|def| def.item == item).expect("every item kind has a definition")
+    }
+
+    /// the equipment an object is as defined, before any enchantment or curse
+    fn base_equipment(&self, object: &Object) -> Option<Equipment> {
+        object.item.and_then(|item| self.item(item).equipment).or(object.equipment)
+    }
+}
+
+/// A hand-made room, read from `VAULTS_FILE`. Each character of the grid is a
+/// tile: `#` is a wall, `.` is floor, and anything else is floor with a monster
+/// or an item drawn that way on it (`?` being any scroll, as `#` is taken).
+#[derive(Clone, Debug)]
+struct Vault {
+    width: i32,
+    height: i32,
+    // indexed by `[y][x]`, the way it's written in the file
+    grid: Vec<Vec<char>>,
+}
+
+impl Vault {
+    /// whether every monster in it could be met on the given level
+    fn allowed_on(&self, data: &GameData, level: i32) -> bool {
+        self.grid.iter().flat_map(|row| row.iter()).all(|&c| {
+            data.monsters.iter()
+                .find(|def| def.char == c)
+                .map_or(true, |def| from_dungeon_level(&def.chances, level) > 0)
+        })
+    }
+}
+
+/// the vaults to use when `VAULTS_FILE` can't be read: the ones it comes with
+const DEFAULT_VAULTS: &'static str = include_str!("../../vaults.txt");
+
+/// the item definitions a vault character can stand for
+fn vault_item_defs<'a>(c: char, data: &'a GameData) -> Vec<&'a ItemDef> {
+    data.items.iter()
+        .filter(|def| if c == '?' { def.item.category() == ItemCategory::Scroll } else { def.char == c })
+        .collect()
+}
+
+/// Vaults are separated by empty lines. Tunnels lead to the center of a vault,
+/// so that has to be floor, and every vault has to fit on the smallest map.
+fn parse_vaults(text: &str) -> Result<Vec<Vault>, String> {
+    let mut vaults = vec![];
+    let lines: Vec<_> = text.lines().map(|line| line.trim_end()).collect();
+    for rows in lines.split(|line| line.is_empty()).filter(|rows| !rows.is_empty()) {
+        let grid: Vec<Vec<char>> = rows.iter().map(|row| row.chars().collect()).collect();
+        let width = grid[0].len();
+        if grid.iter().any(|row| row.len() != width) {
+            return Err(format!("the rows of vault {} aren't all as long", vaults.len() + 1));
+        }
+        if width < 3 || grid.len() < 3 || grid[(grid.len() - 1) / 2][(width - 1) / 2] == '#' {
+            return Err(format!("vault {} has no room in the middle", vaults.len() + 1));
+        }
+        if width as i32 > MIN_MAP_WIDTH || grid.len() as i32 > MIN_MAP_HEIGHT {
+            return Err(format!("vault {} is bigger than the smallest map ({}x{})",
+                               vaults.len() + 1, MIN_MAP_WIDTH, MIN_MAP_HEIGHT));
+        }
+        vaults.push(Vault { width: width as i32, height: grid.len() as i32, grid: grid });
+    }
+    Ok(vaults)
+}
+
+fn read_vaults_file(path: &str) -> Result<Vec<Vault>, String> {
+    let mut contents = String::new();
+    try!{ File::open(path)
+          .and_then(|mut file| file.read_to_string(&mut contents))
+          .map_err(|e| e.to_string()) };
+    parse_vaults(&contents)
+}
+
+fn read_data_file<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let mut contents = String::new();
+    try!{ File::open(path)
+          .and_then(|mut file| file.read_to_string(&mut contents))
+          .map_err(|e| e.to_string()) };
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+/// put a few more monsters of the given kind around the one at `leader`, as
+/// many as there's room for
+fn place_pack<R: Rng>(def: &MonsterDef,
+                      leader: (i32, i32),
+                      room: Rect,
+                      map: &Map,
+                      objects: &mut Vec<Object>,
+                      rng: &mut R) {
+    let followers = rng.gen_range(PACK_MIN_FOLLOWERS, PACK_MAX_FOLLOWERS + 1);
+    let mut spots: Vec<_> = ((room.x1 + 1)..room.x2)
+        .flat_map(|x| ((room.y1 + 1)..room.y2).map(move |y| (x, y)))
+        .filter(|&(x, y)| (x - leader.0).abs() <= PACK_RADIUS && (y - leader.1).abs() <= PACK_RADIUS)
+        .collect();
+    rng.shuffle(&mut spots);
+    let mut placed = 0;
+    for (x, y) in spots {
+        if placed == followers {
+            break;
+        }
+        if !is_blocked(x, y, map, objects) {
+            objects.push(make_monster(def, x, y));
+            placed += 1;
+        }
+    }
+}
+
+fn place_objects<R: Rng>(room: Rect,
+                         map: &Map,
+                         data: &GameData,
+                         objects: &mut Vec<Object>,
+                         level: i32,
+                         difficulty: Difficulty,
+                         rng: &mut R) {
+    use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
+
+    // maximum number of monsters per room
+    let max_monsters = from_dungeon_level(&[(2, 1), (3, 4), (5, 6)], level) as i32;
+    let max_monsters = scale(max_monsters, difficulty.monster_percent());
+
+    // choose random number of monsters
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
+
+    // chance of each monster
+    let monster_chances = &mut data.monsters.iter()
+        .map(|def| Weighted {weight: from_dungeon_level(&def.chances, level), item: def})
+        .filter(|chance| chance.weight > 0)
+        .collect::<Vec<_>>();
+    // `WeightedChoice` can't choose between nothing
+    let monster_choice = if monster_chances.is_empty() {
+        None
+    } else {
+        Some(WeightedChoice::new(monster_chances))
+    };
+
+    // maximum number of items per room
+    let max_items = from_dungeon_level(&[(1, 1), (2, 4)], level) as i32;
+    let max_items = scale(max_items, difficulty.item_percent());
+
+    // chance of each item (most have a chance of 0 at level 1, which then goes up)
+    let item_chances = &mut data.items.iter()
+        .map(|def| Weighted {weight: from_dungeon_level(&def.chances, level), item: def})
+        .filter(|chance| chance.weight > 0)
+        .collect::<Vec<_>>();
+    let item_choice = if item_chances.is_empty() {
+        None
+    } else {
+        Some(WeightedChoice::new(item_chances))
+    };
+
+    if let Some(monster_choice) = monster_choice {
+        for _ in 0..num_monsters {
+            // choose random spot for this monster
+            let x = rng.gen_range(room.x1 + 1, room.x2);
+            let y = rng.gen_range(room.y1 + 1, room.y2);
+
+            // only place it if the tile is not blocked
+            if !is_blocked(x, y, map, objects) {
+                let def = monster_choice.ind_sample(rng);
+                let mut monster = make_monster(def, x, y);
+                if rng.gen::<f32>() < SLEEP_CHANCE {
+                    put_to_sleep(&mut monster);
+                }
+
+                objects.push(monster);
+                if rng.gen_range(0, 100) < from_dungeon_level(&def.pack_chance, level) {
+                    place_pack(def, (x, y), room, map, objects, rng);
+                }
+            }
+        }
+    }
+
+    // choose random number of items
+    let num_items = rng.gen_range(0, max_items + 1);
+    if let Some(item_choice) = item_choice {
+        for _ in 0..num_items {
+            // choose random spot for this item
+            let x = rng.gen_range(room.x1 + 1, room.x2);
+            let y = rng.gen_range(room.y1 + 1, room.y2);
+
+            // only place it if the tile is not blocked
+            if !is_blocked(x, y, map, objects) {
+                let def = item_choice.ind_sample(rng);
+                let mut item = make_item(def, x, y, rng);
+                if let (Some(equipment), Some(base)) = (item.equipment.as_mut(), def.equipment) {
+                    let enchantment = roll_enchantment(level, rng);
+                    if enchantment != 0 && base.can_be_enchanted() {
+                        equipment.enchant(&base, enchantment);
+                        item.name = enchanted_name(&item.name, enchantment);
+                    }
+                    if rng.gen::<f32>() < CURSE_CHANCE {
+                        equipment.curse();
+                    }
+                }
+                objects.push(item);
+            }
+        }
+    }
+
+    // deeper down, some rooms are trapped
+    if level >= TRAP_MIN_LEVEL && rng.gen::<f32>() < TRAP_CHANCE {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        let kind = *rng.choose(&[TrapKind::Spikes, TrapKind::Confusion, TrapKind::Teleport]).unwrap();
+        if !is_blocked(x, y, map, objects) && !objects.iter().any(|obj| obj.pos() == (x, y)) {
+            objects.push(make_trap(x, y, kind));
+        }
+    }
+
+    // some rooms are lit by a brazier
+    if rng.gen::<f32>() < BRAZIER_CHANCE {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) && !objects.iter().any(|obj| obj.pos() == (x, y)) {
+            objects.push(make_brazier(x, y));
+        }
+    }
+
+    // occasionally, put a crafting altar in the room
+    if rng.gen::<f32>() < ALTAR_CHANCE {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) && !objects.iter().any(|obj| obj.pos() == (x, y)) {
+            objects.push(make_altar(x, y));
+        }
+    }
+}
+
+/// the name of an item with its enchantment, e.g. "+2 sword"
+fn enchanted_name(name: &str, enchantment: i32) -> String {
+    if enchantment == 0 {
+        name.to_owned()
+    } else {
+        format!("{:+} {}", enchantment, name)
+    }
+}
+
+/// the name `enchanted_name` was given, without the enchantment in front
+fn unenchanted_name(name: &str, enchantment: i32) -> &str {
+    let prefix = format!("{:+} ", enchantment);
+    if enchantment != 0 && name.starts_with(&prefix) {
+        &name[prefix.len()..]
+    } else {
+        name
+    }
+}
+
+/// how much better (or worse) than usual a piece of equipment found on this level is
+fn roll_enchantment<R: Rng>(level: i32, rng: &mut R) -> i32 {
+    use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
+    let chances = &mut ENCHANTMENT_CHANCES.iter()
+        .map(|&(enchantment, table)| Weighted {weight: from_dungeon_level(table, level), item: enchantment})
+        .filter(|chance| chance.weight > 0)
+        .collect::<Vec<_>>();
+    WeightedChoice::new(chances).ind_sample(rng)
+}
+
+/// Braziers can be walked past like items: the connectivity check only
+/// knows about walls, so one in a narrow spot mustn't cut the level in two.
+fn make_brazier(x: i32, y: i32) -> Object {
+    let mut brazier = Object::new(x, y, '&', "brazier", colors::ORANGE, false);
+    brazier.always_visible = true;
+    brazier.light_radius = Some(BRAZIER_LIGHT_RADIUS);
+    brazier
+}
+
+fn make_gold(x: i32, y: i32, amount: i32) -> Object {
+    let mut gold = Object::new(x, y, '$', "gold", colors::GOLD, false);
+    gold.gold_amount = Some(amount);
+    gold
+}
+
+/// the weapon every class starts with
+fn make_dagger(x: i32, y: i32) -> Object {
+    let mut dagger = Object::new(x, y, '-', "dagger", colors::SKY, false);
+    dagger.equipment = Some(Equipment {
+        slot: EquipmentSlot::RightHand,
+        is_equipped: false,
+        power_bonus: 2,
+        defense_bonus: 0,
+        evasion_bonus: 0,
+        max_hp_bonus: 0,
+        spell_bonus: 0,
+        two_handed: false,
+        cursed: false,
+        enchantment: 0,
+    });
+    dagger.item = Some(Item::Sword);
+    dagger
+}
+
+fn make_altar(x: i32, y: i32) -> Object {
+    let mut altar = Object::new(x, y, '_', "altar", colors::LIGHT_PURPLE, false);
+    altar.altar = Some(Altar { used: false });
+    altar
+}
+
+/// create an item from its definition at the given position
+fn make_item<R: Rng>(def: &ItemDef, x: i32, y: i32, rng: &mut R) -> Object {
+    let mut object = Object::new(x, y, def.char, &def.name, def.color, false);
+    object.item = Some(def.item);
+    object.equipment = def.equipment;
+    if def.item.category() == ItemCategory::Ammo {
+        // they're found in bundles
+        object.count = rng.gen_range(5, 11);
+    }
+    object
+}
+
+/// the color of the filled part of a bar
+#[derive(Clone, Copy, Debug)]
+enum BarColor {
+    Solid(Color),
+    // from the first color when empty to the last when full, see `gradient_color`
+    Gradient(&'static [Color]),
+}
+
+/// what a bar on the panel shows, and in which colors
+struct Bar<'a> {
+    name: &'a str,
+    value: i32,
+    maximum: i32,
+    color: BarColor,
+    back_color: Color,
+}
+
+fn render_bar(panel: &mut renderer::Renderer, x: i32, y: i32, total_width: i32, bar: &Bar) {
+    // render a bar (HP, experience, etc). first calculate the width of the bar
+    let (name, value, maximum) = (bar.name, bar.value, bar.maximum);
+    let fraction = value as f32 / maximum as f32;
+    let bar_width = (fraction * total_width as f32) as i32;
+
+    // render the background first
+    panel.set_default_background(bar.back_color);
+    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+
+    // now render the bar on top, in a color that depends on how full it is if
+    // there's a gradient
+    let bar_color = match bar.color {
+        BarColor::Solid(color) => color,
+        BarColor::Gradient(stops) => gradient_color(stops, fraction),
+    };
+    panel.set_default_background(bar_color);
+    if bar_width > 0 {
+        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+    }
+
+    // finally, some centered text with the values
+    panel.set_default_foreground(colors::WHITE);
+    panel.print_ex(x + total_width / 2, y, BackgroundFlag::None, TextAlignment::Center,
+                   &format!("{}: {}/{}", name, value, maximum));
+}
+
+/// a tiny bar over every hurt monster in view, on the row above it (or on its
+/// own tile, on the top row). It's drawn on the root console, so it doesn't
+/// linger when the monster moves.
+fn render_health_bars<R>(objects: &[Object], root: &mut R, fov_map: &Sight, map_width: i32)
+    where R: renderer::Renderer + ?Sized
+{
+    for object in objects.iter().skip(PLAYER + 1) {
+        let (hp, max_hp) = match object.fighter.as_ref() {
+            Some(fighter) if fighter.hp < fighter.base_max_hp => (fighter.hp, fighter.base_max_hp),
+            _ => continue,
+        };
+        if !fov_map.is_in_fov(object.x, object.y) {
+            continue;
+        }
+        let fraction = hp as f32 / max_hp as f32;
+        let filled = (fraction * HEALTH_BAR_WIDTH as f32).ceil() as i32;
+        let bar_y = if object.y > 0 { object.y - 1 } else { object.y };
+        for i in 0..HEALTH_BAR_WIDTH {
+            let bar_x = object.x - HEALTH_BAR_WIDTH / 2 + i;
+            if bar_x < 0 || bar_x >= map_width {
+                continue;
+            }
+            let color = if i < filled {
+                gradient_color(HP_BAR_GRADIENT, fraction)
+            } else {
+                colors::DARKER_RED
+            };
+            root.set_char_background(bar_x, bar_y, color, BackgroundFlag::Set);
+        }
+    }
+}
+
+/// the color at `fraction` (0 to 1) along evenly spaced color stops
+fn gradient_color(stops: &[Color], fraction: f32) -> Color {
+    let fraction = fraction.max(0.0).min(1.0);
+    if stops.len() < 2 {
+        return stops.first().cloned().unwrap_or(colors::WHITE);
+    }
+    let position = fraction * (stops.len() - 1) as f32;
+    let index = cmp::min(position as usize, stops.len() - 2);
+    colors::lerp(stops[index], stops[index + 1], position - index as f32)
+}
+
+fn get_names_under_mouse(mouse: Mouse, objects: &[Object], game: &Game, fov_map: &Sight) -> String {
+    // return a string with the names of all objects under the mouse
+    get_names_at(mouse.cx as i32, mouse.cy as i32, objects, game, fov_map)
+}
+
+fn get_names_at(x: i32, y: i32, objects: &[Object], game: &Game, fov_map: &Sight) -> String {
+    // create a list with the names of all objects at the given coordinates and in FOV
+    objects.iter().filter(
+        |obj| {
+            obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y)
+        }).filter(|obj| !obj.is_hidden()).map(|obj| game.object_name(obj)).collect::<Vec<_>>().join(", ")
+}
+
+/// what the player can tell about a tile: the objects on it (with the health
+/// of monsters), or else what the tile itself is
+fn describe_tile(x: i32, y: i32, objects: &[Object], game: &Game, fov_map: &Sight) -> String {
+    let in_fov = fov_map.is_in_fov(x, y);
+    let names: Vec<_> = objects.iter()
+        .filter(|obj| obj.pos() == (x, y) && in_fov && !obj.is_hidden())
+        .map(|obj| match obj.fighter.as_ref() {
+            Some(fighter) => {
+                format!("{} ({}/{} hp)", game.object_name(obj), fighter.hp, obj.full_max_hp(game))
+            }
+            None => game.object_name(obj),
+        })
+        .collect();
+    if !names.is_empty() {
+        return names.join(", ");
+    }
+    let tile = &game.map[x as usize][y as usize];
+    let description = if !tile.explored {
+        "unexplored"
+    } else if tile.blocked {
+        "a wall"
+    } else if game.fires.iter().any(|fire| (fire.x, fire.y) == (x, y)) {
+        "burning grass"
+    } else {
+        match tile.terrain {
+            Terrain::Ground => "the floor",
+            Terrain::Grass => "grass",
+            Terrain::Water => "shallow water",
+            Terrain::Lava => "lava",
+        }
+    };
+    if in_fov || !tile.explored {
+        description.to_owned()
+    } else {
+        format!("{} (remembered)", description)
+    }
+}
+
+/// the player's bars and numbers on the panel
+fn render_stats(panel: &mut renderer::Renderer, player: &Object, game: &Game) {
+    render_bar(panel, 1, 1, BAR_WIDTH, &Bar {
+        name: "HP",
+        value: player.fighter.as_ref().map_or(0, |f| f.hp),
+        maximum: player.full_max_hp(game),
+        color: BarColor::Gradient(HP_BAR_GRADIENT),
+        back_color: colors::DARKER_RED,
+    });
+    panel.print_ex(1, 3, BackgroundFlag::None, TextAlignment::Left,
+                   &format!("Dungeon level: {}", game.dungeon_level));
+    panel.print_ex(1, 4, BackgroundFlag::None, TextAlignment::Left,
+                   &format!("Explored: {}%", explored_percentage(&game.map)));
+    panel.print_ex(1, 2, BackgroundFlag::None, TextAlignment::Left,
+                   &format!("Gold: {}", game.gold));
+    // a letter for each status effect, lined up under the right end of the HP bar
+    let effect_count = player.effects.len() as i32;
+    for (i, effect) in player.effects.iter().enumerate() {
+        let (icon, color) = effect.kind.icon();
+        panel.set_default_foreground(color);
+        panel.put_char(BAR_WIDTH - effect_count + i as i32 + 1, 2, icon, BackgroundFlag::None);
+    }
+    let (hunger, hunger_color) = hunger_status(game.hunger);
+    panel.set_default_foreground(hunger_color);
+    panel.print_ex(1, 5, BackgroundFlag::None, TextAlignment::Left, hunger);
+    panel.set_default_foreground(colors::LIGHT_GREY);
+    panel.print_ex(BAR_WIDTH, 5, BackgroundFlag::None, TextAlignment::Right,
+                   &format!("Turn {}", game.turn_count));
+    render_bar(panel, 1, 6, BAR_WIDTH, &Bar {
+        name: "XP",
+        value: player.fighter.as_ref().map_or(0, |f| f.xp),
+        maximum: game.level_up_xp(player.level),
+        color: BarColor::Solid(colors::LIGHT_VIOLET),
+        back_color: colors::DARKER_VIOLET,
+    });
+}
+
+fn render_all(objects: &[Object], game: &mut Game, tcod: &mut TcodState) {
+    let player = &objects[PLAYER];
+    if game.fov_recompute {
+        game.fov_recompute = false;
+        let (player_x, player_y) = player.pos();
+        // opening a door changes what can be seen through its tile
+        for door in objects.iter().filter(|object| object.door.is_some()) {
+            let tile = game.map[door.x as usize][door.y as usize];
+            tcod.fov_map.set(door.x, door.y, !tile.block_sight, !tile.blocked);
+        }
+        let lights: Vec<_> = objects.iter()
+            .filter_map(|object| object.light_radius.map(|radius| (object.pos(), radius)))
+            .collect();
+        tcod.fov_map.compute((player_x, player_y),
+                             game.settings.torch_radius,
+                             game.settings.fov_algorithm.algorithm(),
+                             &lights);
+
+        // go through all tiles, and set their background color according to the FOV
+        for y in 0..tcod.config.map_height {
+            for x in 0..tcod.config.map_width {
+                let visible = tcod.fov_map.is_in_fov(x, y);
+                let tile = game.map[x as usize][y as usize];
+                let wall = tile.block_sight;
+                let burning = game.fires.iter().any(|fire| (fire.x, fire.y) == (x, y));
+                // outside of FOV, and inside it
+                let (dark, light) = match (wall, tile.terrain) {
+                    (true, _) => (game.theme.dark_wall, game.theme.light_wall),
+                    (false, Terrain::Ground) => (game.theme.dark_ground, game.theme.light_ground),
+                    (false, Terrain::Grass) => (COLOR_DARK_GRASS, COLOR_LIGHT_GRASS),
+                    (false, Terrain::Water) => (COLOR_DARK_WATER, COLOR_LIGHT_WATER),
+                    (false, Terrain::Lava) => (COLOR_DARK_LAVA, COLOR_LIGHT_LAVA),
+                };
+                let color = if !visible {
+                    dark
+                } else if burning && !wall {
+                    COLOR_FIRE
+                } else if game.settings.light_falloff && !tcod.fov_map.is_lit(x, y) {
+                    // the torch light fades toward the edge of its radius
+                    let distance = player.distance(x, y) / game.settings.torch_radius as f32;
+                    colors::lerp(light, dark, distance.min(1.0) * LIGHT_FALLOFF)
+                } else {
+                    light
+                };
+                if !visible {
+                    // if it's not visible right now, the player can only see if it's explored
+                    if tile.explored {
+                        tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+                    }
+                } else {
+                    // it's visible
+                    tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+                    // since it's visible, explore it
+                    game.map[x as usize][y as usize].explored = true;
+                }
+            }
+        }
+    }
+
+    // Grab all renderable objects
+    let mut render_objects: Vec<_> = objects.iter().collect();
+    // Put the fighters first, then items, then everything else. This will not
+    // affect the order of the original objects vector.
+    render_objects.sort_by(|o1, o2| {
+        if o1.fighter.is_some() || o2.fighter.is_some() {
+            return o1.fighter.is_some().cmp(&o2.fighter.is_some());
+        }
+        if o1.item.is_some() || o2.item.is_some() {
+            return o1.item.is_some().cmp(&o2.item.is_some());
+        }
+        Ordering::Equal
+    });
+    for object in &render_objects {
+        object.draw(&mut *tcod.con, game, &tcod.fov_map);
+    }
+
+    // blit the contents of "con" to the root console
+    renderer::blit(&*tcod.con,
+                   (0, 0),
+                   (tcod.config.map_width, tcod.config.map_height),
+                   &mut *tcod.root,
+                   (0, 0),
+                   1.0,
+                   1.0);
+
+    if game.settings.show_health_bars {
+        render_health_bars(objects, &mut *tcod.root, &tcod.fov_map, tcod.config.map_width);
+    }
+
+    // highlight the keyboard cursor, if it's active
+    if let Some((x, y)) = tcod.cursor {
+        tcod.root.set_char_background(x, y, colors::LIGHT_GREY, BackgroundFlag::Set);
+    }
+
+    // prepare to render the GUI panel
     tcod.panel.set_default_background(colors::BLACK);
     tcod.panel.clear();
 
     // print the game messages, one line at a time
+    let msg_width = tcod.config.msg_width();
     let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in game.log.messages().iter().rev() {
-        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    for &(ref msg, color, count, turn) in game.log.messages().iter().rev() {
+        let msg = &message_text(msg, count, turn, game.settings.panel_turns);
+        let msg_height = tcod.panel.get_height_rect(MSG_X, y, msg_width, 0, msg);
         y -= msg_height;
-        // TODO: this won't print a partial message if it crosses multiple lines. Can we fix that?
         if y < 0 {
+            if y + msg_height <= 0 {
+                break;
+            }
+            // only the bottom lines of this one fit: print it somewhere else and
+            // copy just those over
+            let mut message = tcod.root.offscreen(msg_width, msg_height);
+            message.set_default_foreground(color);
+            message.print_rect_ex(0, 0, msg_width, 0, BackgroundFlag::None, TextAlignment::Left, msg);
+            renderer::blit(&*message,
+                           (0, -y),
+                           (msg_width, msg_height + y),
+                           &mut *tcod.panel,
+                           (MSG_X, 0),
+                           1.0,
+                           0.0);
             break;
         }
         tcod.panel.set_default_foreground(color);
-        tcod.panel.print_rect_ex(MSG_X, y, MSG_WIDTH, 0,
+        tcod.panel.print_rect_ex(MSG_X, y, msg_width, 0,
                             BackgroundFlag::None, TextAlignment::Left, msg);
     }
 
     // show the player's stats
-    render_bar(&mut tcod.panel,
-               1,
-               1,
-               BAR_WIDTH,
-               "HP",
-               player.fighter.as_ref().map_or(0, |f| f.hp),
-               player.full_max_hp(game),
-               colors::LIGHT_RED,
-               colors::DARKER_RED);
-    tcod.panel.print_ex(1, 3, BackgroundFlag::None, TextAlignment::Left,
-                        format!("Dungeon level: {}", game.dungeon_level));
-
-    // display names of objects under the mouse
+    render_stats(&mut *tcod.panel, player, game);
+
+    // display names of objects under the mouse (or describe what's under the
+    // cursor, when there is one)
     tcod.panel.set_default_foreground(colors::LIGHT_GREY);
-    let names = get_names_under_mouse(tcod.mouse, objects, &tcod.fov_map);
-    tcod.panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, names);
+    let names = match tcod.cursor {
+        Some((x, y)) => describe_tile(x, y, objects, game, &tcod.fov_map),
+        None => get_names_under_mouse(tcod.mouse, objects, game, &tcod.fov_map),
+    };
+    tcod.panel.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, &names);
 
     // blit the contents of `panel` to the root console
-    tcod::console::blit(&mut tcod.panel,
-                        (0, 0),
-                        (SCREEN_WIDTH, PANEL_HEIGHT),
-                        &mut tcod.root,
-                        (0, PANEL_Y),
-                        1.0,
-                        1.0);
+    renderer::blit(&*tcod.panel,
+                   (0, 0),
+                   (tcod.config.screen_width, PANEL_HEIGHT),
+                   &mut *tcod.root,
+                   (0, tcod.config.panel_y()),
+                   1.0,
+                   1.0);
+
+    if tcod.show_debug {
+        render_debug_overlay(objects, game, tcod);
+    }
+}
+
+/// print some internal state useful for debugging in the top right corner
+fn render_debug_overlay(objects: &[Object], game: &Game, tcod: &mut TcodState) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (x, y) = tcod.cursor.unwrap_or((tcod.mouse.cx as i32, tcod.mouse.cy as i32));
+    let tile = if in_map(x, y, &game.map) {
+        let tile = game.map[x as usize][y as usize];
+        format!("Tile {},{}: blocked {}, block_sight {}, explored {}",
+                x, y, tile.blocked, tile.block_sight, tile.explored)
+    } else {
+        format!("Tile {},{}: outside the map", x, y)
+    };
+    let lines = [format!("Player: {},{}", player_x, player_y),
+                 format!("Objects: {}", objects.len()),
+                 format!("Map style: {:?}", game.map_style),
+                 format!("FPS: {}", tcod::system::get_fps()),
+                 tile];
+    tcod.root.set_default_foreground(colors::WHITE);
+    tcod.root.set_default_background(colors::BLACK);
+    for (line, text) in lines.iter().enumerate() {
+        tcod.root.print_ex(tcod.config.screen_width - 1, line as i32, BackgroundFlag::Set, TextAlignment::Right, text);
+    }
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, objects: &mut [Object], game: &mut Game) {
+fn player_move_or_attack(dx: i32, dy: i32, objects: &mut Vec<Object>, game: &mut Game) {
+    // a confused player stumbles around, but always takes a step
+    let (dx, dy) = if objects[PLAYER].has_effect(StatusEffectKind::Confused) {
+        loop {
+            let step = (game.rng.gen_range(-1, 2), game.rng.gen_range(-1, 2));
+            if step != (0, 0) {
+                break step;
+            }
+        }
+    } else {
+        (dx, dy)
+    };
     // the coordinates the player is moving to/attacking
     let (x, y) = {
         let player = &objects[PLAYER];
         (player.x + dx, player.y + dy)
     };
 
-    // try to find an attackable object there
-    let target_id = objects.iter().position(|object| {
-        object.fighter.is_some() && object.pos() == (x, y)
+    // try to find an attackable object there (other than the player)
+    let target_id = objects.iter().enumerate().position(|(id, object)| {
+        id != PLAYER && object.fighter.is_some() && object.pos() == (x, y)
     });
 
     // attack if target found, move otherwise
     match target_id {
+        Some(target_id) if objects[target_id].is_ally() => {
+            // allies make way for the player
+            let (player_x, player_y) = objects[PLAYER].pos();
+            objects[target_id].set_pos(player_x, player_y);
+            objects[PLAYER].set_pos(x, y);
+            game.fov_recompute = true;
+            game.log.add(format!("You swap places with your {}.", objects[target_id].name), colors::LIGHT_GREY);
+            enter_tile(objects, game);
+        }
         Some(target_id) => {
-            let (player, target) = mut_two(PLAYER, target_id, objects);
-            player.attack(target, game);
+            {
+                let (player, target) = mut_two(PLAYER, target_id, objects);
+                player.attack(target, game);
+            }
+            make_noise((x, y), ATTACK_NOISE_RADIUS, objects, game);
         }
         None => {
             move_by(PLAYER, dx, dy, objects, game);
             game.fov_recompute = true;
+            if objects[PLAYER].pos() == (x, y) {
+                enter_tile(objects, game);
+            }
         }
     }
 }
 
+/// everything that happens when the player steps onto a tile, however they got there
+fn enter_tile(objects: &mut Vec<Object>, game: &mut Game) {
+    let (x, y) = objects[PLAYER].pos();
+    game.waded = game.map[x as usize][y as usize].terrain == Terrain::Water;
+    collect_gold(objects, game);
+    auto_pick_up(objects, game);
+    trigger_trap(objects, game);
+    if game.map[x as usize][y as usize].terrain == Terrain::Lava {
+        game.log.add(format!("The lava burns you for {} hit points.", LAVA_DAMAGE), colors::ORANGE);
+        objects[PLAYER].take_damage(LAVA_DAMAGE, game);
+    }
+}
+
+/// set off the trap under the player, if there's one
+fn trigger_trap(objects: &mut Vec<Object>, game: &mut Game) {
+    let player_pos = objects[PLAYER].pos();
+    let trap_id = match objects.iter().position(|obj| obj.pos() == player_pos && obj.trap.is_some()) {
+        Some(id) => id,
+        None => return,
+    };
+    let kind = {
+        let trap = objects[trap_id].trap.as_mut().unwrap();
+        trap.discovered = true;
+        trap.kind
+    };
+    game.log.add(format!("You step on a {}!", objects[trap_id].name), colors::RED);
+    match kind {
+        TrapKind::Spikes => {
+            game.log.add(format!("Spikes pierce you for {} hit points.", SPIKE_TRAP_DAMAGE), colors::RED);
+            objects[PLAYER].take_damage(SPIKE_TRAP_DAMAGE, game);
+        }
+        TrapKind::Confusion => {
+            game.log.add("A cloud of gas makes your head spin.", colors::LIGHT_PURPLE);
+            objects[PLAYER].add_effect(StatusEffect {
+                kind: StatusEffectKind::Confused,
+                turns_left: CONFUSION_TRAP_TURNS,
+                magnitude: 0,
+            });
+        }
+        TrapKind::Teleport => {
+            // anywhere on the level that's free
+            let (width, height) = map_size(&game.map);
+            let free: Vec<_> = (0..width)
+                .flat_map(|x| (0..height).map(move |y| (x, y)))
+                .filter(|&(x, y)| !is_blocked(x, y, &game.map, objects))
+                .collect();
+            if let Some(&(x, y)) = game.rng.choose(&free) {
+                game.log.add("The world blurs around you.", colors::LIGHT_CYAN);
+                objects[PLAYER].set_pos(x, y);
+                game.fov_recompute = true;
+            }
+        }
+    }
+}
+
+/// every secret wall next to the player may be found, turning it into floor
+fn search(objects: &[Object], game: &mut Game, tcod: &mut TcodState) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for x in (player_x - 1)..(player_x + 2) {
+        for y in (player_y - 1)..(player_y + 2) {
+            if !in_map(x, y, &game.map) {
+                continue;
+            }
+            let tile = &mut game.map[x as usize][y as usize];
+            if tile.secret && game.rng.gen::<f32>() < SEARCH_CHANCE {
+                tile.secret = false;
+                tile.blocked = false;
+                tile.block_sight = false;
+                tcod.fov_map.set(x, y, true, true);
+                game.fov_recompute = true;
+                game.log.add("You found a hidden passage!", colors::LIGHT_CYAN);
+            }
+        }
+    }
+}
+
+/// the hidden traps next to the player may be noticed
+fn spot_traps(objects: &mut [Object], game: &mut Game) {
+    for id in 0..objects.len() {
+        if !objects[id].is_hidden() || objects[id].distance_to(&objects[PLAYER]) >= 2.0 {
+            continue;
+        }
+        if game.rng.gen::<f32>() < TRAP_SPOT_CHANCE {
+            objects[id].trap.as_mut().unwrap().discovered = true;
+            game.log.add(format!("You spot a {}!", objects[id].name), colors::LIGHT_CYAN);
+        }
+    }
+}
+
+/// gold is always picked up, just by walking over it
+fn collect_gold(objects: &mut Vec<Object>, game: &mut Game) {
+    let player_pos = objects[PLAYER].pos();
+    while let Some(id) = objects.iter().position(|obj| obj.pos() == player_pos && obj.gold_amount.is_some()) {
+        let amount = remove_object(id, objects).gold_amount.unwrap();
+        game.gold += amount;
+        game.log.add(format!("You pick up {} gold.", amount), colors::GOLD);
+    }
+}
+
+/// pick up every item under the player whose category is set to be picked up automatically
+fn auto_pick_up(objects: &mut Vec<Object>, game: &mut Game) {
+    let player_pos = objects[PLAYER].pos();
+    let item_ids: Vec<_> = objects.iter()
+        .enumerate()
+        .filter(|&(_id, obj)| {
+            obj.pos() == player_pos &&
+                obj.item.map_or(false, |item| game.settings.auto_pickup.contains(&item.category()))
+        })
+        .map(|(id, _obj)| id)
+        .collect();
+    // go from the highest index down so picking one up doesn't move the remaining items around
+    // (`pick_item_up` logs every item it picks up)
+    for &id in item_ids.iter().rev() {
+        // with a full inventory, just leave it there instead of complaining at every step
+        if has_room_for(&objects[id], &game.inventory) {
+            pick_item_up(id, objects, game);
+        }
+    }
+}
+
+/// is the player standing on an object with that name?
+fn stands_on(objects: &[Object], name: &str) -> bool {
+    let player_pos = objects[PLAYER].pos();
+    objects.iter().any(|object| object.pos() == player_pos && object.name == name)
+}
+
+/// the direction a movement key points to, if it is one
+fn key_direction(key: Key, vi_keys: bool) -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::*;
+    if vi_keys {
+        // lowercase, so Shift works with them too
+        let direction = match key.printable.to_ascii_lowercase() {
+            'k' => Some((0, -1)),
+            'j' => Some((0, 1)),
+            'h' => Some((-1, 0)),
+            'l' => Some((1, 0)),
+            'y' => Some((-1, -1)),
+            'u' => Some((1, -1)),
+            'b' => Some((-1, 1)),
+            'n' => Some((1, 1)),
+            _ => None,
+        };
+        if direction.is_some() {
+            return direction;
+        }
+    }
+    match key {
+        Key { code: Up, .. } | Key { code: NumPad8, .. } => Some((0, -1)),
+        Key { code: Down, .. } | Key { code: NumPad2, .. } => Some((0, 1)),
+        Key { code: Left, .. } | Key { code: NumPad4, .. } => Some((-1, 0)),
+        Key { code: Right, .. } | Key { code: NumPad6, .. } => Some((1, 0)),
+        Key { code: Home, .. } | Key { code: NumPad7, .. } => Some((-1, -1)),
+        Key { code: PageUp, .. } | Key { code: NumPad9, .. } => Some((1, -1)),
+        Key { code: End, .. } | Key { code: NumPad1, .. } => Some((-1, 1)),
+        Key { code: PageDown, .. } | Key { code: NumPad3, .. } => Some((1, 1)),
+        _ => None,
+    }
+}
+
 fn handle_keys(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState, event: Option<Event>) -> PlayerAction {
     use tcod::input::KeyCode::*;
-    let key = if let Some(Event::Key(key)) = event {
-        key
-    } else {
-        return PlayerAction::DidntTakeTurn;
+    let key = match event {
+        Some(Event::Key(key)) => key,
+        Some(Event::Mouse(m)) if m.lbutton_pressed && objects[PLAYER].alive => {
+            return click_to_move((m.cx as i32, m.cy as i32), objects, game, tcod);
+        }
+        _ => return PlayerAction::DidntTakeTurn,
     };
     // Alt+Enter: toggle fullscreen
     if let Key { code: Enter, alt: true, .. } = key {
         let fullscreen = !tcod.root.is_fullscreen();
         tcod.root.set_fullscreen(fullscreen);
     } else if key.code == Escape {
-        return PlayerAction::Exit;  // exit game
+        return pause_menu(game, tcod);
+    } else if key.code == F2 {
+        // write the level out, e.g. for a bug report
+        match dump_level(objects, game) {
+            Ok(()) => game.log.add(format!("The level was written to {}.", LEVEL_DUMP_FILE),
+                                   colors::LIGHT_GREY),
+            Err(e) => game.log.add(format!("Couldn't write {}: {}", LEVEL_DUMP_FILE, e), colors::RED),
+        }
+    } else if key.code == F3 {
+        // toggle the debug overlay
+        tcod.show_debug = !tcod.show_debug;
     }
     if objects[PLAYER].alive {
-        match key {
-            // movement keys
-            Key { code: Up, .. } | Key { code: NumPad8, .. } => {
-                player_move_or_attack(0, -1, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: Down, .. } | Key { code: NumPad2, .. } => {
-                player_move_or_attack(0, 1, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: Left, .. } | Key { code: NumPad4, .. } => {
-                player_move_or_attack(-1, 0, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: Right, .. } | Key { code: NumPad6, .. } => {
-                player_move_or_attack(1, 0, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: Home, .. } | Key { code: NumPad7, .. } => {
-                player_move_or_attack(-1, -1, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: PageUp, .. } | Key { code: NumPad9, .. } => {
-                player_move_or_attack(1, -1, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: End, .. } | Key { code: NumPad1, .. } => {
-                player_move_or_attack(-1, 1, objects, game);
-                return PlayerAction::None;
-            }
-            Key { code: PageDown, .. } | Key { code: NumPad3, .. } => {
-                player_move_or_attack(1, 1, objects, game);
-                return PlayerAction::None;
+        // movement keys
+        if let Some((dx, dy)) = key_direction(key, game.settings.vi_keys) {
+            if key.shift {
+                // Shift+direction: run that way (the turns are taken while running)
+                run(dx, dy, objects, game, tcod);
+                return PlayerAction::DidntTakeTurn;
             }
+            player_move_or_attack(dx, dy, objects, game);
+            return PlayerAction::None;
+        }
+        match key {
             Key { code: NumPad5, .. } => {
                 return PlayerAction::None;  // do nothing ie wait for the monster to come to you
             }
@@ -1068,38 +3615,154 @@ fn handle_keys(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState,
                     use_item(inventory_index, objects, game, tcod);
                 }
             }
+            Key { printable: 't', .. } => {
+                // show the inventory; if an item is selected, throw it
+                let inventory_index = tcod.inventory_menu(
+                    game,
+                    "Press the key next to an item to throw it, or any other to cancel.\n");
+                if let Some(inventory_index) = inventory_index {
+                    throw_item(inventory_index, objects, game, tcod);
+                }
+            }
             Key { printable: 'd', .. } => {
                 // show the inventory; if an item is selected, drop it
                 let inventory_index = tcod.inventory_menu(
                     game,
-                    "Press the key next to an item to drop it, or any other to cancel.\n");
+                    "Press the key next to an item to drop it, or any other to cancel.\n");
+                if let Some(inventory_index) = inventory_index {
+                    drop_item(inventory_index, objects, game);
+                }
+            }
+            Key { printable: 'c', .. } => {
+                // show character information
+                let player = &objects[PLAYER];
+                let level = player.level;
+                let level_up_xp = game.level_up_xp(level);
+                if let Some(fighter) = player.fighter.as_ref() {
+                    let mut equipped = String::new();
+                    for &slot in EQUIPMENT_SLOTS {
+                        if let Some(id) = get_equipped_in_slot(slot, &game.inventory) {
+                            let item = &game.inventory[id];
+                            let bonuses = item.equipment.as_ref().unwrap().bonus_summary();
+                            equipped.push_str(&format!("\n{}: {} ({})", slot, item.name, bonuses));
+                        }
+                    }
+                    let perks = if game.perks.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        game.perks.iter().map(|perk| perk.name()).collect::<Vec<_>>().join(", ")
+                    };
+                    let msg = format!(
+                        "Character information\n\nClass: {}\nDifficulty: {}\nLevel: {}\nExperience: {}\n\
+                         Experience to level up: {}\n\nMaximum HP: {}\nAttack: {}\nDefense: {}\nEvasion: {}\n\
+                         Spell power: {}\nPerks: {}\n{}\n\nSeed: {}",
+                        game.class, game.difficulty, level, fighter.xp, level_up_xp,
+                        player.full_max_hp(game), player.full_power(game),
+                        player.full_defense(game), player.full_evasion(game),
+                        player.full_spell_power(game),
+                        perks,
+                        equipped,
+                        game.seed);
+                    tcod.msgbox(&msg, CHARACTER_SCREEN_WIDTH);
+                }
+            }
+            Key { printable: '<', .. } if stands_on(objects, "stairs") &&
+                tcod.confirm("Descend to the next level?") => {
+                // go down stairs, if the player is on them
+                game.next_level(objects, tcod);
+            }
+            Key { printable: '>', .. } if stands_on(objects, "upstairs") => {
+                // go back up, if the player is on the up stairs
+                game.previous_level(objects, tcod);
+            }
+            Key { printable: 'f', .. } => {
+                // shoot an arrow with the wielded bow
+                if let UseResult::UsedAndKept = shoot_arrow(0, objects, game, tcod) {
+                    return PlayerAction::None;
+                }
+            }
+            Key { printable: 'a', .. } => {
+                // use a nearby altar
+                use_altar(objects, game, tcod);
+            }
+            Key { printable: 'p', .. } => {
+                // buy from a shopkeeper next to the player
+                shop(objects, game, tcod);
+            }
+            Key { printable: 's', .. } => {
+                // search the walls around the player for secret passages
+                search(objects, game, tcod);
+                return PlayerAction::None;
+            }
+            Key { printable: 'x', .. } => {
+                // look around with a cursor, Enter examines an item
+                game.log.add("Move the cursor to look around, press Enter to examine an item or \
+                              Escape when done.",
+                             colors::LIGHT_CYAN);
+                let player_pos = objects[PLAYER].pos();
+                if let Some((x, y)) = select_tile(player_pos, objects, game, tcod) {
+                    let item = objects.iter().find(|obj| {
+                        obj.pos() == (x, y) && obj.item.is_some() && tcod.fov_map.is_in_fov(x, y)
+                    });
+                    if let Some(item) = item {
+                        tcod.msgbox(&describe_item(item, game), INVENTORY_WIDTH);
+                    }
+                }
+            }
+            Key { printable: 'e', .. } => {
+                // show the inventory; if an item is selected, describe it
+                let inventory_index = tcod.inventory_menu(
+                    game,
+                    "Press the key next to an item to examine it, or any other to cancel.\n");
                 if let Some(inventory_index) = inventory_index {
-                    drop_item(inventory_index, objects, game);
+                    let text = describe_item(&game.inventory[inventory_index], game);
+                    tcod.msgbox(&text, INVENTORY_WIDTH);
                 }
             }
-            Key { printable: 'c', .. } => {
-                // show character information
-                let player = &objects[PLAYER];
-                let level = player.level;
-                let level_up_xp = LEVEL_UP_BASE + level * LEVEL_UP_FACTOR;
-                if let Some(fighter) = player.fighter.as_ref() {
-                    let msg = format!(
-                        "Character information\n\nLevel: {}\nExperience: {}\nExperience to level \
-                         up: {}\n\nMaximum HP: {}\nAttack: {}\nDefense: {}",
-                        level, fighter.xp, level_up_xp,
-                        player.full_max_hp(game), player.full_power(game),
-                        player.full_defense(game));
-                    tcod.msgbox(&msg, CHARACTER_SCREEN_WIDTH);
+            Key { printable: '_', .. } => {
+                // travel to a tile the player has already explored
+                game.log.add("Move the cursor where you want to go and press Enter, \
+                              or Escape to cancel.",
+                             colors::LIGHT_CYAN);
+                let player_pos = objects[PLAYER].pos();
+                if let Some(target) = select_tile(player_pos, objects, game, tcod) {
+                    travel_to(target, objects, game, tcod);
                 }
             }
-            Key { printable: '<', .. } => {
-                // go down stairs, if the player is on them
-                let player_pos = objects[PLAYER].pos();
-                let player_stands_on_stairs = objects.iter().any(|object| {
-                    object.pos() == player_pos && object.name == "stairs"
-                });
-                if player_stands_on_stairs {
-                    game.next_level(objects, tcod);
+            Key { printable: 'm', .. } => {
+                // list the monsters in view, and look at the chosen one
+                monster_list(objects, game, tcod);
+            }
+            Key { printable: 'v', .. } => {
+                // toggle the health bars over monsters
+                game.settings.show_health_bars = !game.settings.show_health_bars;
+            }
+            Key { printable: 'P', .. } => {
+                // look back at older messages
+                tcod.message_history(&game.log);
+            }
+            Key { printable: 'o', .. } => {
+                // explore the level until something interesting happens
+                auto_explore(objects, game, tcod);
+            }
+            Key { printable: 'D', .. } => {
+                // explore and go down the stairs, level after level
+                auto_descend(objects, game, tcod);
+            }
+            Key { printable: 'O', .. } => {
+                // show the options screen
+                options_menu(game, tcod);
+            }
+            Key { printable: 'E', .. } => {
+                // eat a corpse, when there's nothing better
+                return eat_corpse(objects, game);
+            }
+            Key { printable: 'S', .. } => {
+                // save now and keep playing
+                if game.settings.save_policy == SavePolicy::Practice {
+                    game.log.add("Nothing gets saved in a practice game.", colors::LIGHT_GREY);
+                } else {
+                    game.autosave(objects);
                 }
             }
             _ => { }
@@ -1110,7 +3773,7 @@ fn handle_keys(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState,
 
 fn check_level_up(objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) {
     let player = &mut objects[PLAYER];
-    let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
+    let level_up_xp = game.level_up_xp(player.level);
     // see if the player's experience is enough to level-up
     if player.fighter.as_ref().map_or(0, |f| f.xp) >= level_up_xp {
         // it is! level up
@@ -1118,29 +3781,202 @@ fn check_level_up(objects: &mut [Object], game: &mut Game, tcod: &mut TcodState)
         game.log.add(format!("Your battle skills grow stronger! You reached level {}!",
                              player.level),
                      colors::YELLOW);
+        // a few perks to choose from, leaving out the ones the player can only have once
+        let mut perks: Vec<_> = PERKS.iter()
+            .cloned()
+            .filter(|perk| perk.stacks() || !game.perks.contains(perk))
+            .collect();
+        game.rng.shuffle(&mut perks);
+        perks.truncate(PERK_CHOICES);
+        let options: Vec<_> = perks.iter()
+            .map(|perk| format!("{}: {}", perk.name(), perk.description()))
+            .collect();
         let mut choice = None;
         while choice.is_none() {  // keep asking until a choice is made
-            choice = tcod.menu(
-                "Level up! Choose a stat to raise:\n",
-                &[format!("Constitution (+20 HP, from {})", player.full_max_hp(game)),
-                  format!("Strength (+1 attack, from {})", player.full_power(game)),
-                  format!("Agility (+1 defense, from {})", player.full_defense(game))],
-                LEVEL_SCREEN_WIDTH);
+            choice = tcod.menu("Level up! Choose a perk:\n", &options, LEVEL_SCREEN_WIDTH);
         };
         let fighter = player.fighter.as_mut().unwrap();
         fighter.xp -= level_up_xp;
-        match choice.unwrap() {
-            0 => {
-                fighter.base_max_hp += 20;
-                fighter.hp += 20;
+        let perk = perks[choice.unwrap()];
+        perk.apply(fighter);
+        game.perks.push(perk);
+    }
+}
+
+/// buy something from a shopkeeper next to the player
+fn shop(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    let near_keeper = objects.iter().any(|object| {
+        object.shopkeeper && object.distance_to(&objects[PLAYER]) < 2.0
+    });
+    if !near_keeper {
+        game.log.add("There is no shopkeeper nearby.", colors::WHITE);
+        return;
+    }
+    let options: Vec<_> = SHOP_STOCK.iter()
+        .map(|&(item, price)| format!("{} - {} gold", game.data.item(item).name, price))
+        .collect();
+    let header = format!("\"Welcome! What will it be?\" (you have {} gold)\n", game.gold);
+    let choice = tcod.menu(&header, &options, INVENTORY_WIDTH);
+    if let Some(&(item, price)) = choice.and_then(|index| SHOP_STOCK.get(index)) {
+        if game.gold < price {
+            game.log.add("You can't afford that.", colors::RED);
+            return;
+        }
+        game.gold -= price;
+        let (x, y) = objects[PLAYER].pos();
+        let bought = make_item(game.data.item(item), x, y, &mut game.rng);
+        // the shopkeeper said what it was
+        if !game.identified.contains(&item) {
+            game.identified.push(item);
+        }
+        objects.push(bought);
+        let id = objects.len() - 1;
+        pick_item_up(id, objects, game);
+    }
+}
+
+/// combine two items from the inventory at an altar next to (or under) the player
+fn use_altar(objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) {
+    let altar_id = objects.iter().position(|object| {
+        object.altar.is_some() && object.distance_to(&objects[PLAYER]) < 2.0
+    });
+    let altar_id = match altar_id {
+        Some(id) => id,
+        None => {
+            game.log.add("There is no altar nearby.", colors::WHITE);
+            return;
+        }
+    };
+    if objects[altar_id].altar.map_or(true, |altar| altar.used) {
+        game.log.add("The altar's power has faded.", colors::WHITE);
+        return;
+    }
+
+    // find the recipes the player has the ingredients for
+    let inventory_ids = |first: Item, second: Item| {
+        let first_id = game.inventory.iter().position(|obj| obj.item == Some(first));
+        first_id.and_then(|first_id| {
+            // both can come from the same stack, if there's two in it
+            game.inventory.iter()
+                .enumerate()
+                .position(|(id, obj)| {
+                    (id != first_id || obj.count > 1) && obj.item == Some(second)
+                })
+                .map(|second_id| (first_id, second_id))
+        })
+    };
+    let available: Vec<_> = RECIPES.iter()
+        .filter_map(|&(first, second, result)| {
+            inventory_ids(first, second).map(|ids| (ids, result))
+        })
+        .collect();
+    if available.is_empty() {
+        game.log.add("You have nothing the altar can combine.", colors::WHITE);
+        return;
+    }
+
+    // only by the names the player knows them by
+    let options: Vec<_> = available.iter().map(|&((first_id, second_id), result)| {
+        format!("Combine {} and {} into a {}",
+                game.object_name(&game.inventory[first_id]),
+                game.object_name(&game.inventory[second_id]),
+                game.unidentified_name(result).unwrap_or(&game.data.item(result).name))
+    }).collect();
+    let choice = tcod.menu("The altar hums. Choose what to combine, or any other key to cancel.\n",
+                           &options, INVENTORY_WIDTH);
+    if let Some(choice) = choice {
+        let ((first_id, second_id), result) = available[choice];
+        // use up the later item first so the other index stays valid
+        use_up_one(cmp::max(first_id, second_id), game);
+        use_up_one(cmp::min(first_id, second_id), game);
+        let crafted = make_item(game.data.item(result), 0, 0, &mut game.rng);
+        let name = game.object_name(&crafted);
+        game.log.add(format!("The items melt together into a {}!", name), colors::LIGHT_PURPLE);
+        add_to_inventory(crafted, game);
+
+        let altar = &mut objects[altar_id];
+        altar.altar = Some(Altar { used: true });
+        altar.color = colors::DARK_GREY;
+        altar.name = "inert altar".into();
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+fn options_menu(game: &mut Game, tcod: &mut TcodState) {
+    // keep showing the options until the player picks something that isn't one
+    let categories = [ItemCategory::Potion, ItemCategory::Scroll, ItemCategory::Equipment,
+                      ItemCategory::Ammo, ItemCategory::Food];
+    loop {
+        let mut options: Vec<_> = categories.iter().map(|category| {
+            format!("Auto-pickup {}: {}",
+                    category, on_off(game.settings.auto_pickup.contains(category)))
+        }).collect();
+        options.push(format!("Show remembered objects: {}", on_off(game.settings.show_remembered)));
+        options.push(format!("Verbose combat log: {}", on_off(game.settings.verbose_combat)));
+        options.push(format!("Vi-style movement keys (hjklyubn): {}", on_off(game.settings.vi_keys)));
+        options.push(format!("Field of view: {}", game.settings.fov_algorithm));
+        options.push(format!("Torch radius: {}", game.settings.torch_radius));
+        options.push(format!("Light fading with distance: {}", on_off(game.settings.light_falloff)));
+        options.push(format!("Armor: {}", game.settings.combat_model));
+        options.push(format!("Friendly fire: {}", on_off(game.settings.friendly_fire)));
+        options.push(format!("Turn numbers in the panel: {}", on_off(game.settings.panel_turns)));
+        options.push(format!("Messages kept: {}", game.log.capacity));
+        options.push(format!("Corpses rot away: {}", on_off(game.settings.corpse_decay)));
+        let choice = tcod.menu("Options (press a key to toggle, any other to go back):\n",
+                               &options, LEVEL_SCREEN_WIDTH);
+        let n = categories.len();
+        match choice {
+            Some(index) if index < n => {
+                let category = categories[index];
+                if let Some(pos) = game.settings.auto_pickup.iter().position(|&c| c == category) {
+                    game.settings.auto_pickup.remove(pos);
+                } else {
+                    game.settings.auto_pickup.push(category);
+                }
             }
-            1 => {
-                fighter.base_power += 1;
+            Some(index) if index == n => {
+                game.settings.show_remembered = !game.settings.show_remembered;
             }
-            2 => {
-                fighter.base_defense += 1;
+            Some(index) if index == n + 1 => {
+                game.settings.verbose_combat = !game.settings.verbose_combat;
             }
-            _ => unreachable!(),
+            Some(index) if index == n + 2 => {
+                game.settings.vi_keys = !game.settings.vi_keys;
+            }
+            Some(index) if index == n + 3 => {
+                game.settings.fov_algorithm = cycle(FOV_STYLES, game.settings.fov_algorithm);
+                game.fov_recompute = true;
+            }
+            Some(index) if index == n + 4 => {
+                game.settings.torch_radius = cycle(TORCH_RADII, game.settings.torch_radius);
+                game.fov_recompute = true;
+            }
+            Some(index) if index == n + 5 => {
+                // the tiles only get redrawn along with the FOV
+                game.settings.light_falloff = !game.settings.light_falloff;
+                game.fov_recompute = true;
+            }
+            Some(index) if index == n + 6 => {
+                game.settings.combat_model = cycle(COMBAT_MODELS, game.settings.combat_model);
+            }
+            Some(index) if index == n + 7 => {
+                game.settings.friendly_fire = !game.settings.friendly_fire;
+            }
+            Some(index) if index == n + 8 => {
+                game.settings.panel_turns = !game.settings.panel_turns;
+            }
+            Some(index) if index == n + 9 => {
+                // older messages only go once a new one comes in
+                game.log.capacity = cycle(LOG_CAPACITIES, game.log.capacity);
+            }
+            Some(index) if index == n + 10 => {
+                game.settings.corpse_decay = !game.settings.corpse_decay;
+            }
+            Some(_) => unreachable!(),
+            None => break,
         }
     }
 }
@@ -1150,11 +3986,33 @@ enum PlayerAction {
     None,
     DidntTakeTurn,
     Exit,
+    ExitWithoutSaving,
+}
+
+/// ask what to do with the game, until the player goes back to it or leaves
+fn pause_menu(game: &mut Game, tcod: &mut TcodState) -> PlayerAction {
+    let choices = &["Resume", "Options", "Save and quit", "Quit without saving"];
+    loop {
+        match tcod.menu("Paused\n", choices, PAUSE_MENU_WIDTH) {
+            Some(1) => options_menu(game, tcod),
+            Some(2) => return PlayerAction::Exit,
+            Some(3) => {
+                if tcod.confirm("Quit and lose the progress since the last save?") {
+                    return PlayerAction::ExitWithoutSaving;
+                }
+            }
+            _ => return PlayerAction::DidntTakeTurn,
+        }
+    }
 }
 
 fn player_death(player: &mut Object, game: &mut Game) {
     // the game ended!
     game.log.add("You died!", colors::RED);
+    // loading the save would only show the corpse
+    if game.settings.save_policy != SavePolicy::Practice {
+        delete_save_game();
+    }
 
     // for added effect, transform the player into a corpse!
     player.char = '%';
@@ -1162,6 +4020,197 @@ fn player_death(player: &mut Object, game: &mut Game) {
     player.alive = false;
 }
 
+/// how far a dead character got, one line of the scores file
+#[derive(Clone, Debug, PartialEq)]
+struct Score {
+    class: String,
+    dungeon_level: i32,
+    level: i32,
+    turns: u64,
+    kills: u32,
+}
+
+impl Score {
+    fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\t{}\t{}", self.class, self.dungeon_level, self.level, self.turns, self.kills)
+    }
+
+    /// None if the line isn't a score
+    fn from_line(line: &str) -> Option<Score> {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        match (fields[1].parse(), fields[2].parse(), fields[3].parse(), fields[4].parse()) {
+            (Ok(dungeon_level), Ok(level), Ok(turns), Ok(kills)) => Some(Score {
+                class: fields[0].to_owned(),
+                dungeon_level: dungeon_level,
+                level: level,
+                turns: turns,
+                kills: kills,
+            }),
+            _ => None,
+        }
+    }
+
+    /// better scores come first: deeper down, then a higher level, then more kills,
+    /// then fewer turns
+    fn rank(&self, other: &Score) -> Ordering {
+        other.dungeon_level.cmp(&self.dungeon_level)
+            .then(other.level.cmp(&self.level))
+            .then(other.kills.cmp(&self.kills))
+            .then(self.turns.cmp(&other.turns))
+    }
+}
+
+/// add a score to the scores file and return the best ones, including it
+fn record_score(score: &Score) -> Vec<Score> {
+    let mut contents = String::new();
+    if let Ok(mut file) = File::open(SCORES_FILE) {
+        // an unreadable file just means there are no scores yet
+        let _ = file.read_to_string(&mut contents);
+    }
+    let mut scores: Vec<_> = contents.lines().filter_map(Score::from_line).collect();
+    // not being able to write it only loses the score, the game goes on
+    if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(SCORES_FILE) {
+        let _ = writeln!(file, "{}", score.to_line());
+    }
+    scores.push(score.clone());
+    scores.sort_by(|a, b| a.rank(b));
+    scores.truncate(MAX_SCORES);
+    scores
+}
+
+/// The whole level as text, explored or not, under a line with the dungeon
+/// level and the seed: the glyph of whatever is on a tile, or else of the tile
+/// itself (see `LEVEL_FILE_GLYPHS`). Scrolls are written as `?`, like in
+/// vaults, so they can't be mistaken for walls when the file is loaded back,
+/// and corpses are left out, they'd only come back as something else.
+fn level_text(objects: &[Object], game: &Game) -> String {
+    let (width, height) = map_size(&game.map);
+    let mut rows: Vec<Vec<char>> = (0..height).map(|y| {
+        (0..width).map(|x| tile_glyph(&game.map[x as usize][y as usize])).collect()
+    }).collect();
+    // like on screen, fighters go over items, which go over everything else
+    let mut sorted: Vec<_> = objects.iter().filter(|obj| obj.decay_turns.is_none()).collect();
+    sorted.sort_by_key(|obj| (obj.fighter.is_some(), obj.item.is_some()));
+    for obj in sorted {
+        let is_scroll = obj.item.map_or(false, |item| item.category() == ItemCategory::Scroll);
+        rows[obj.y as usize][obj.x as usize] = if is_scroll {
+            '?'
+        } else if obj.shopkeeper {
+            SHOPKEEPER_GLYPH
+        } else {
+            obj.char
+        };
+    }
+    let mut text = format!("Dungeon level {}, seed {}\n", game.dungeon_level, game.seed);
+    for row in rows {
+        text.extend(row);
+        text.push('\n');
+    }
+    text
+}
+
+/// write the level to `LEVEL_DUMP_FILE`, see `level_text`
+fn dump_level(objects: &[Object], game: &Game) -> Result<(), Error> {
+    let mut file = try!{ File::create(LEVEL_DUMP_FILE) };
+    file.write_all(level_text(objects, game).as_bytes())
+}
+
+/// what a tile without anything on it looks like in a level file
+fn tile_glyph(tile: &Tile) -> char {
+    match (tile.blocked, tile.secret, tile.terrain) {
+        (true, true, _) => '*',
+        (true, false, _) => '#',
+        (false, _, Terrain::Ground) => '.',
+        (false, _, Terrain::Grass) => '"',
+        (false, _, Terrain::Water) => '~',
+        (false, _, Terrain::Lava) => '=',
+    }
+}
+
+/// A level read from a file given with `--load-level`. It's only checked
+/// against the game data here, the objects are made when a game starts on it.
+struct FixedLevel {
+    // the seed from the header line, if there was one
+    seed: Option<u64>,
+    grid: Vec<Vec<char>>,
+}
+
+// what the glyphs of a level file stand for, besides monsters and items: the
+// tiles of `tile_glyph`, the player, stairs, doors, braziers, altars, traps,
+// gold, the dagger everyone starts with and the shopkeeper
+const LEVEL_FILE_GLYPHS: &'static str = "#*.\"~=@<>+'&_^$-K";
+// the shopkeeper looks like the player, but mustn't be mistaken for them
+const SHOPKEEPER_GLYPH: char = 'K';
+// the most gold a `$` of a level file stands for, the amount isn't written down
+const LEVEL_FILE_MAX_GOLD: i32 = 10;
+
+/// Parse a level in the format `dump_level` writes: an optional `Dungeon level
+/// N, seed S` line, then exactly as many rows of glyphs as the map of `config`
+/// is high, each as long as it is wide.
+fn parse_level(text: &str, data: &GameData, config: &Config) -> Result<FixedLevel, String> {
+    let mut lines: Vec<_> = text.lines().map(|line| line.trim_end()).collect();
+    let mut seed = None;
+    if lines.first().map_or(false, |line| line.starts_with("Dungeon level")) {
+        let header = lines.remove(0);
+        let value = header.split("seed ").nth(1).and_then(|s| s.trim().parse().ok());
+        match value {
+            Some(value) => seed = Some(value),
+            None => return Err(format!("line 1: no seed in the header \"{}\"", header)),
+        }
+    }
+    // the line numbers in errors are the ones of the file
+    let first_line = if seed.is_some() { 2 } else { 1 };
+    // trailing empty lines don't count as rows
+    while lines.last().map_or(false, |line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.len() != config.map_height as usize {
+        return Err(format!("expected {} rows, found {}", config.map_height, lines.len()));
+    }
+    let mut grid = vec![];
+    let mut players = 0;
+    for (y, line) in lines.iter().enumerate() {
+        let row: Vec<char> = line.chars().collect();
+        if row.len() != config.map_width as usize {
+            return Err(format!("line {}: expected {} characters, found {}",
+                               y + first_line, config.map_width, row.len()));
+        }
+        for (x, &c) in row.iter().enumerate() {
+            let known = LEVEL_FILE_GLYPHS.contains(c) ||
+                data.monsters.iter().any(|def| def.char == c) ||
+                !vault_item_defs(c, data).is_empty();
+            if !known {
+                return Err(format!("line {}, column {}: unknown glyph '{}'", y + first_line, x + 1, c));
+            }
+            if c == '@' {
+                players += 1;
+            }
+        }
+        grid.push(row);
+    }
+    if players != 1 {
+        return Err(format!("there should be exactly one player ('@'), found {}", players));
+    }
+    Ok(FixedLevel { seed: seed, grid: grid })
+}
+
+fn read_level_file(path: &str, data: &GameData, config: &Config) -> Result<FixedLevel, String> {
+    let mut contents = String::new();
+    try!{ File::open(path)
+          .and_then(|mut file| file.read_to_string(&mut contents))
+          .map_err(|e| e.to_string()) };
+    parse_level(&contents, data, config)
+}
+
+/// remove the saved game, if there is one
+fn delete_save_game() {
+    // there's nothing to do when it doesn't exist
+    let _ = std::fs::remove_file(SAVE_FILE);
+}
+
 fn monster_death(monster: &mut Object, game: &mut Game) {
     // transform it into a nasty corpse! it doesn't block, can't be
     // attacked and doesn't move
@@ -1169,6 +4218,52 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
                          monster.name,
                          monster.fighter.as_ref().unwrap().xp),
                  colors::ORANGE);
+    game.kills += 1;
+    // the corpse is where it died, there's nothing to remember
+    monster.last_known_pos = None;
+    let xp = monster.fighter.as_ref().unwrap().xp;
+    if game.rng.gen::<f32>() < GOLD_DROP_CHANCE {
+        let amount = game.rng.gen_range(1, xp / 5 + 2);
+        game.spawned.push(make_gold(monster.x, monster.y, amount));
+    }
+    let drop = {
+        use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
+        let def = game.data.monsters.iter().find(|def| def.name == monster.name);
+        match def {
+            Some(def) if game.rng.gen::<f32>() < def.drop_chance => {
+                let drops = &mut def.drops.iter()
+                    .filter(|&&(_, weight)| weight > 0)
+                    .map(|&(item, weight)| Weighted {weight: weight, item: item})
+                    .collect::<Vec<_>>();
+                // `WeightedChoice` can't choose between nothing
+                if drops.is_empty() {
+                    None
+                } else {
+                    Some(WeightedChoice::new(drops).ind_sample(&mut game.rng))
+                }
+            }
+            _ => None,
+        }
+    };
+    if let Some(item) = drop {
+        let item = make_item(game.data.item(item), monster.x, monster.y, &mut game.rng);
+        let article = if item.count > 1 { "" } else { "a " };
+        game.log.add(format!("The {} drops {}{}.", monster.name, article, game.object_name(&item)),
+                     colors::LIGHT_GREY);
+        game.spawned.push(item);
+    }
+    become_corpse(monster);
+}
+
+fn ally_death(ally: &mut Object, game: &mut Game) {
+    game.log.add(format!("Your {} is dead!", ally.name), colors::LIGHT_RED);
+    ally.last_known_pos = None;
+    ally.expires_in = None;
+    become_corpse(ally);
+}
+
+/// what's left of a dead monster doesn't block, can't be attacked and doesn't move
+fn become_corpse(monster: &mut Object) {
     monster.char = '%';
     monster.color = colors::DARK_RED;
     monster.blocks = false;
@@ -1176,6 +4271,60 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
     monster.ai = None;
     monster.alive = false;
     monster.name = format!("remains of {}", monster.name);
+    monster.decay_turns = Some(CORPSE_DECAY_TURNS);
+}
+
+/// let the corpses rot, and get rid of the ones that are gone
+fn tick_corpses(objects: &mut Vec<Object>, game: &mut Game) {
+    if !game.settings.corpse_decay {
+        return;
+    }
+    for object in objects.iter_mut() {
+        if let Some(turns) = object.decay_turns {
+            object.decay_turns = Some(turns - 1);
+            if turns - 1 == CORPSE_ROTTEN_TURNS {
+                object.name = format!("rotting {}", object.name);
+            }
+        }
+    }
+    objects.retain(|object| object.decay_turns.map_or(true, |turns| turns > 0));
+}
+
+/// eat a corpse under or next to the player
+fn eat_corpse(objects: &mut Vec<Object>, game: &mut Game) -> PlayerAction {
+    let corpse_id = {
+        let player = &objects[PLAYER];
+        let mut corpses: Vec<_> = (0..objects.len())
+            .filter(|&id| objects[id].decay_turns.is_some() && objects[id].distance_to(player) < 1.5)
+            .collect();
+        // the one underfoot first
+        corpses.sort_by_key(|&id| objects[id].pos() != player.pos());
+        corpses.first().cloned()
+    };
+    let corpse_id = match corpse_id {
+        Some(id) => id,
+        None => {
+            game.log.add("There is nothing here to eat.", colors::WHITE);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+    if game.hunger >= MAX_HUNGER {
+        game.log.add("You are too full to eat.", colors::WHITE);
+        return PlayerAction::DidntTakeTurn;
+    }
+    let rotten = objects[corpse_id].decay_turns.map_or(false, |turns| turns <= CORPSE_ROTTEN_TURNS);
+    game.log.add(format!("You eat the {}.", objects[corpse_id].name), colors::DARK_AMBER);
+    feed(CORPSE_NUTRITION, objects, game);
+    if rotten {
+        game.log.add("It was rotten. You feel sick!", colors::LIME);
+        objects[PLAYER].add_effect(StatusEffect {
+            kind: StatusEffectKind::Poison,
+            turns_left: POISON_TURNS,
+            magnitude: ROTTEN_CORPSE_POISON,
+        });
+    }
+    remove_object(corpse_id, objects);
+    PlayerAction::None
 }
 
 /// return the position of a tile left-clicked in player's FOV (optionally in a
@@ -1190,7 +4339,7 @@ fn target_tile(objects: &[Object],
         // render the screen. this erases the inventory and shows the names of
         // objects under the mouse.
         tcod.root.flush();
-        let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
+        let event = tcod.check_for_event(input::KEY_PRESS | input::MOUSE);
         let mut key = None;
         match event {
             Some(Event::Mouse(m)) => tcod.mouse = m,
@@ -1235,6 +4384,376 @@ fn target_monster(objects: &[Object], game: &mut Game, tcod: &mut TcodState, max
     }
 }
 
+/// let the player pick a tile with a keyboard-driven cursor (or a left-click),
+/// returns None if cancelled with Escape or a right-click
+fn select_tile(start: (i32, i32), objects: &[Object], game: &mut Game, tcod: &mut TcodState)
+               -> Option<(i32, i32)> {
+    let selected = move_cursor(start, objects, game, tcod);
+    tcod.cursor = None;
+    selected
+}
+
+fn move_cursor(start: (i32, i32), objects: &[Object], game: &mut Game, tcod: &mut TcodState)
+               -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::{Enter, Escape};
+    let (mut x, mut y) = start;
+    loop {
+        tcod.cursor = Some((x, y));
+        render_all(objects, game, tcod);
+        tcod.root.flush();
+        match tcod.check_for_event(input::KEY_PRESS | input::MOUSE) {
+            Some(Event::Mouse(m)) => {
+                tcod.mouse = m;
+                let (mouse_x, mouse_y) = (m.cx as i32, m.cy as i32);
+                if m.lbutton_pressed && mouse_x < tcod.config.map_width && mouse_y < tcod.config.map_height {
+                    return Some((mouse_x, mouse_y));
+                } else if m.rbutton_pressed {
+                    return None;
+                }
+            }
+            Some(Event::Key(key)) => {
+                if key.code == Escape {
+                    return None;
+                } else if key.code == Enter || key.printable == '.' {
+                    return Some((x, y));
+                } else if let Some((dx, dy)) = key_direction(key, game.settings.vi_keys) {
+                    x = cmp::max(0, cmp::min(tcod.config.map_width - 1, x + dx));
+                    y = cmp::max(0, cmp::min(tcod.config.map_height - 1, y + dy));
+                }
+            }
+            None => {}
+        }
+        if tcod.root.window_closed() {
+            return None;
+        }
+    }
+}
+
+/// is there a monster the player can see?
+fn monster_in_view(objects: &[Object], tcod: &TcodState) -> bool {
+    objects.iter().any(|object| {
+        object.faction == Faction::Monster && object.fighter.is_some() && tcod.fov_map.is_in_fov(object.x, object.y)
+    })
+}
+
+/// take a single automatic step (e.g. while travelling) and let the monsters act.
+/// Returns false if the player couldn't move there.
+fn auto_step(dx: i32, dy: i32, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> bool {
+    let old_pos = objects[PLAYER].pos();
+    // opening a door on the way counts as getting somewhere too
+    let door = closed_door_at(old_pos.0 + dx, old_pos.1 + dy, objects).map(|id| objects[id].uid);
+    for object in objects.iter() {
+        object.clear(&mut *tcod.con);
+    }
+    player_move_or_attack(dx, dy, objects, game);
+    advance_turn(objects, game, tcod);
+    render_all(objects, game, tcod);
+    tcod.root.flush();
+    mark_seen_objects(objects, &tcod.fov_map);
+    let opened_door = door.and_then(|uid| find_by_uid(uid, objects))
+        .map_or(false, |id| !objects[id].is_closed_door());
+    objects[PLAYER].pos() != old_pos || opened_door
+}
+
+/// how many dangers the player knows of: traps they found, explored lava and
+/// fires burning on explored tiles
+fn known_hazards(objects: &[Object], game: &Game) -> usize {
+    let traps = objects.iter().filter(|obj| obj.trap.map_or(false, |trap| trap.discovered)).count();
+    let lava = game.map.iter()
+        .flat_map(|column| column.iter())
+        .filter(|tile| tile.explored && tile.terrain == Terrain::Lava)
+        .count();
+    let fires = game.fires.iter().filter(|fire| game.map[fire.x as usize][fire.y as usize].explored).count();
+    traps + lava + fires
+}
+
+/// what the player knew before an automatic step, to tell whether to keep going
+struct AutoWatch {
+    hp: i32,
+    seen_items: usize,
+    hazards: usize,
+}
+
+impl AutoWatch {
+    fn new(objects: &[Object], game: &Game) -> Self {
+        AutoWatch {
+            hp: objects[PLAYER].fighter.as_ref().map_or(0, |f| f.hp),
+            seen_items: objects.iter().filter(|obj| obj.item.is_some() && obj.seen).count(),
+            hazards: known_hazards(objects, game),
+        }
+    }
+
+    /// the reason to stop moving automatically, if there is one
+    fn interruption(&self, objects: &[Object], game: &Game, tcod: &mut TcodState) -> Option<&'static str> {
+        let player = &objects[PLAYER];
+        let hp = player.fighter.as_ref().map_or(0, |f| f.hp);
+        if monster_in_view(objects, tcod) {
+            Some("You stop, an enemy comes into view!")
+        } else if hp < self.hp {
+            Some("You stop, you are getting hurt!")
+        } else if (hp as f32) < player.full_max_hp(game) as f32 * AUTO_MIN_HP_FRACTION {
+            Some("You stop, your health is too low.")
+        } else if objects.iter().filter(|obj| obj.item.is_some() && obj.seen).count() > self.seen_items {
+            Some("You stop, you spot an item.")
+        } else if known_hazards(objects, game) > self.hazards {
+            Some("You stop, there is danger ahead.")
+        } else if tcod.key_interrupt() {
+            // any key stops the player
+            Some("You stop.")
+        } else {
+            None
+        }
+    }
+}
+
+/// take one step along the shortest known path towards `target`.
+/// Returns false if there is no such path or the player couldn't move.
+fn step_towards(target: (i32, i32), objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> bool {
+    let path = {
+        let map = &game.map;
+        let (width, height) = map_size(map);
+        let step_cost = |x: i32, y: i32| {
+            // known traps and lava are walked around, unless that's where the player wants to go
+            let known_trap = (x, y) != target && objects.iter().any(|obj| {
+                obj.pos() == (x, y) && obj.trap.map_or(false, |trap| trap.discovered)
+            });
+            let lava = (x, y) != target && in_map(x, y, map) &&
+                map[x as usize][y as usize].terrain == Terrain::Lava;
+            let passable = in_map(x, y, map) &&
+                map[x as usize][y as usize].explored && !known_trap && !lava &&
+                (!is_blocked(x, y, map, objects) || closed_door_at(x, y, objects).is_some());
+            if passable { Some(1) } else { None }
+        };
+        find_path(objects[PLAYER].pos(), target, (width * height) as usize, step_cost)
+    };
+    match path.as_ref().and_then(|path| path.first()) {
+        Some(&(next_x, next_y)) => {
+            let (x, y) = objects[PLAYER].pos();
+            auto_step(next_x - x, next_y - y, objects, game, tcod)
+        }
+        None => false,
+    }
+}
+
+/// a left-click on the map: attack the monster that was clicked if it's right
+/// next to the player, otherwise walk to the clicked tile
+fn click_to_move(target: (i32, i32), objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> PlayerAction {
+    let (x, y) = target;
+    if !in_map(x, y, &game.map) {
+        return PlayerAction::DidntTakeTurn;
+    }
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (dx, dy) = (x - player_x, y - player_y);
+    let monster_there = objects.iter().any(|obj| {
+        obj.pos() == target && obj.fighter.is_some() && !obj.is_player()
+    });
+    if dx.abs() <= 1 && dy.abs() <= 1 && monster_there {
+        player_move_or_attack(dx, dy, objects, game);
+        return PlayerAction::None;
+    }
+    if target != (player_x, player_y) {
+        // the turns are taken along the way
+        travel_to(target, objects, game, tcod);
+    }
+    PlayerAction::DidntTakeTurn
+}
+
+/// walk the player to an explored tile along the shortest known path, stopping
+/// as soon as something interesting happens. Returns true if the player got there.
+fn travel_to(target: (i32, i32), objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> bool {
+    let (target_x, target_y) = target;
+    if !game.map[target_x as usize][target_y as usize].explored ||
+        is_blocked(target_x, target_y, &game.map, objects) {
+        game.log.add("You don't know a way there.", colors::WHITE);
+        return false;
+    }
+    if monster_in_view(objects, tcod) {
+        game.log.add("Not with enemies in view!", colors::RED);
+        return false;
+    }
+    while objects[PLAYER].pos() != target {
+        // look for the path again every step, in case something got in the way
+        let watch = AutoWatch::new(objects, game);
+        if !step_towards(target, objects, game, tcod) {
+            game.log.add("You don't know a way there.", colors::WHITE);
+            return false;
+        }
+        if !objects[PLAYER].alive || tcod.root.window_closed() {
+            return false;
+        }
+        if let Some(reason) = watch.interruption(objects, game, tcod) {
+            game.log.add(reason, colors::LIGHT_GREY);
+            return false;
+        }
+    }
+    true
+}
+
+/// keep moving in one direction until something interesting happens: a monster
+/// shows up, the player gets hurt, spots a danger, steps on something, reaches
+/// a junction or bumps into a wall
+fn run(dx: i32, dy: i32, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    if monster_in_view(objects, tcod) {
+        game.log.add("Not with enemies in view!", colors::RED);
+        return;
+    }
+    let openings = open_neighbours(objects[PLAYER].pos(), &game.map);
+    loop {
+        let watch = AutoWatch::new(objects, game);
+        if !auto_step(dx, dy, objects, game, tcod) {
+            return;
+        }
+        if !objects[PLAYER].alive || tcod.root.window_closed() {
+            return;
+        }
+        if let Some(reason) = watch.interruption(objects, game, tcod) {
+            game.log.add(reason, colors::LIGHT_GREY);
+            return;
+        }
+        let player_pos = objects[PLAYER].pos();
+        if objects.iter().skip(PLAYER + 1).any(|obj| obj.pos() == player_pos && !obj.is_hidden()) {
+            return;
+        }
+        // the walls around opening up or closing in means a side passage or a room
+        if open_neighbours(player_pos, &game.map) != openings {
+            return;
+        }
+    }
+}
+
+/// find the closest reachable tile next to one the player hasn't explored yet
+fn explore_target(objects: &[Object], game: &Game) -> Option<(i32, i32)> {
+    use std::collections::{HashSet, VecDeque};
+    let start = objects[PLAYER].pos();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(start);
+    queue.push_back(start);
+    // flood-fill outwards from the player over the known, walkable tiles
+    while let Some((x, y)) = queue.pop_front() {
+        let mut next_to_unexplored = false;
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let (next_x, next_y) = (x + dx, y + dy);
+                if !in_map(next_x, next_y, &game.map) {
+                    continue;
+                }
+                let tile = game.map[next_x as usize][next_y as usize];
+                if !tile.explored {
+                    next_to_unexplored = true;
+                } else if !visited.contains(&(next_x, next_y)) && tile.terrain != Terrain::Lava &&
+                    (!is_blocked(next_x, next_y, &game.map, objects) ||
+                     closed_door_at(next_x, next_y, objects).is_some()) {
+                    visited.insert((next_x, next_y));
+                    queue.push_back((next_x, next_y));
+                }
+            }
+        }
+        // the tiles around the player are always in view, so never pick its own tile
+        if next_to_unexplored && (x, y) != start {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+/// keep walking towards the nearest unexplored area. Returns true once
+/// everything reachable has been explored, false if something interrupted it.
+fn auto_explore(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> bool {
+    if monster_in_view(objects, tcod) {
+        game.log.add("Not with enemies in view!", colors::RED);
+        return false;
+    }
+    loop {
+        let target = match explore_target(objects, game) {
+            Some(target) => target,
+            None => {
+                game.log.add("Nothing left to explore.", colors::LIGHT_GREY);
+                return true;
+            }
+        };
+        let watch = AutoWatch::new(objects, game);
+        if !step_towards(target, objects, game, tcod) {
+            return false;
+        }
+        if !objects[PLAYER].alive || tcod.root.window_closed() {
+            return false;
+        }
+        if let Some(reason) = watch.interruption(objects, game, tcod) {
+            game.log.add(reason, colors::LIGHT_GREY);
+            return false;
+        }
+    }
+}
+
+/// explore each level, then walk to the stairs and go down them, until something
+/// interrupts it
+fn auto_descend(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    loop {
+        if !auto_explore(objects, game, tcod) {
+            return;
+        }
+        let stairs = objects.iter().find(|obj| {
+            obj.name == "stairs" && game.map[obj.x as usize][obj.y as usize].explored
+        }).map(|obj| obj.pos());
+        let stairs = match stairs {
+            Some(stairs) => stairs,
+            None => {
+                game.log.add("You haven't found the stairs.", colors::WHITE);
+                return;
+            }
+        };
+        if !travel_to(stairs, objects, game, tcod) {
+            return;
+        }
+        game.next_level(objects, tcod);
+        // compute the new FOV before looking around the new level
+        render_all(objects, game, tcod);
+        tcod.root.flush();
+    }
+}
+
+/// name of the compass direction of the given vector, e.g. "north-east"
+fn direction_name(dx: i32, dy: i32) -> &'static str {
+    match (dx.signum(), dy.signum()) {
+        (0, -1) => "north",
+        (1, -1) => "north-east",
+        (1, 0) => "east",
+        (1, 1) => "south-east",
+        (0, 1) => "south",
+        (-1, 1) => "south-west",
+        (-1, 0) => "west",
+        (-1, -1) => "north-west",
+        _ => "here",
+    }
+}
+
+/// show every monster the player can see and put the cursor on the chosen one
+fn monster_list(objects: &[Object], game: &mut Game, tcod: &mut TcodState) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let monsters: Vec<_> = objects.iter()
+        .filter(|obj| {
+            obj.faction == Faction::Monster && obj.fighter.is_some() && tcod.fov_map.is_in_fov(obj.x, obj.y)
+        })
+        .collect();
+    if monsters.is_empty() {
+        game.log.add("There are no enemies in sight.", colors::WHITE);
+        return;
+    }
+    let options: Vec<_> = monsters.iter().map(|monster| {
+        let fighter = monster.fighter.as_ref().unwrap();
+        let (dx, dy) = (monster.x - player_x, monster.y - player_y);
+        format!("{} ({}/{} hp), {} {}",
+                monster.name, fighter.hp, fighter.base_max_hp,
+                cmp::max(dx.abs(), dy.abs()), direction_name(dx, dy))
+    }).collect();
+    let choice = tcod.paged_menu("Monsters in view (pick one to look at it):\n", &options, INVENTORY_WIDTH);
+    if let Some(choice) = choice {
+        game.log.add("Move the cursor to look around, press Escape when done.", colors::LIGHT_CYAN);
+        select_tile(monsters[choice].pos(), objects, game, tcod);
+    }
+}
+
 fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &TcodState) -> Option<usize> {
     // find closest enemy, up to a maximum range, and in the player's FOV
     let mut closest_enemy = None;
@@ -1242,7 +4761,7 @@ fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &TcodState) ->
 
     // TODO: this could be done more succinctly with Iter::min_by but that's unstable now.
     for (id, object) in objects.iter().enumerate() {
-        if !object.is_player() && object.fighter.is_some() &&
+        if object.faction == Faction::Monster && object.fighter.is_some() &&
            tcod.fov_map.is_in_fov(object.x, object.y) {
             // calculate distance between this object and the player
             let dist = objects[PLAYER].distance_to(object);
@@ -1255,34 +4774,51 @@ fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &TcodState) ->
     closest_enemy
 }
 
-fn cast_heal(_inventory_id: usize, objects: &mut [Object], game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+/// roll the monster's resistance against confusion and other control effects
+fn resists_control<R: Rng>(monster: &Object, rng: &mut R) -> bool {
+    let resistance = monster.fighter.as_ref().map_or(0, |f| f.control_resistance);
+    rng.gen_range(0, 100) < resistance
+}
+
+fn cast_heal(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
     let player = &mut objects[PLAYER];
     let max_hp = player.full_max_hp(game);
+    let heal_amount = player.spell_strength(game.data.item(Item::Heal).power, game);
     // heal the player
     if let Some(fighter) = player.fighter.as_mut() {
         if fighter.hp == max_hp {
             game.log.add("You are already at full health.", colors::RED);
             return UseResult::Cancelled;
         }
-        game.log.add("Your wounds start to feel better!", colors::LIGHT_VIOLET);
-        fighter.heal(HEAL_AMOUNT);
+        game.log.add(format!("Your wounds start to feel better! You heal {} hit points.", heal_amount),
+                     colors::LIGHT_VIOLET);
+        fighter.heal(heal_amount);
         return UseResult::UsedUp;
     }
     return UseResult::Cancelled;
 }
 
-fn cast_lightning(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> UseResult {
+fn cast_lightning(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
     // find closest enemy (inside a maximum range) and damage it
-    let monster_id = closest_monster(LIGHTNING_RANGE, objects, tcod);
+    let (range, base_damage) = {
+        let def = game.data.item(Item::Lightning);
+        (def.range, def.power)
+    };
+    let monster_id = closest_monster(range, objects, tcod);
     if let Some(monster_id) = monster_id {
         // zap it!
+        render_all(objects, game, tcod);
+        tcod.animate_line(objects[PLAYER].pos(), objects[monster_id].pos(), '*', colors::LIGHT_BLUE);
+        let damage = objects[PLAYER].spell_strength(base_damage, game);
         game.log.add(format!("A lightning bolt strikes the {} with a loud thunder! \
                               The damage is {} hit points.",
-                             objects[monster_id].name, LIGHTNING_DAMAGE),
+                             objects[monster_id].name, damage),
                      colors::LIGHT_BLUE);
-        objects[monster_id].take_damage(LIGHTNING_DAMAGE, game).map(|xp| {
+        let target_pos = objects[monster_id].pos();
+        objects[monster_id].take_damage(damage, game).map(|xp| {
             objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
         });
+        make_noise(target_pos, SPELL_NOISE_RADIUS, objects, game);
         UseResult::UsedUp
     } else {  // no enemy found within maximum range
         game.log.add("No enemy is close enough to strike.", colors::RED);
@@ -1290,7 +4826,7 @@ fn cast_lightning(_inventory_id: usize, objects: &mut [Object], game: &mut Game,
     }
 }
 
-fn cast_fireball(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> UseResult {
+fn cast_fireball(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
     // ask the player for a target tile to throw a fireball at
     game.log.add("Left-click a target tile for the fireball, or right-click to cancel.",
                  colors::LIGHT_CYAN);
@@ -1298,95 +4834,990 @@ fn cast_fireball(_inventory_id: usize, objects: &mut [Object], game: &mut Game,
         Some(tile_pos) => tile_pos,
         None => { return UseResult::Cancelled },
     };
+    let (radius, base_damage) = {
+        let def = game.data.item(Item::Fireball);
+        (def.range, def.power)
+    };
+    // make sure the player really means it
+    let friendly_fire = game.settings.friendly_fire;
+    let hits_player = friendly_fire && objects[PLAYER].distance(x, y) <= radius as f32;
+    let hits_monster = objects.iter().any(|obj| {
+        obj.faction == Faction::Monster && obj.fighter.is_some() && obj.distance(x, y) <= radius as f32
+    });
+    if hits_player && !tcod.confirm("You are inside the blast! Cast the fireball anyway?") {
+        return UseResult::Cancelled;
+    }
+    if !hits_monster && !tcod.confirm("The fireball won't hit any enemy. Cast it anyway?") {
+        return UseResult::Cancelled;
+    }
+    render_all(objects, game, tcod);
+    tcod.animate_explosion((x, y), radius, colors::ORANGE);
     game.log.add(format!("The fireball explodes, burning everything within {} tiles!",
-                         FIREBALL_RADIUS),
+                         radius),
                  colors::ORANGE);
+    make_noise((x, y), SPELL_NOISE_RADIUS, objects, game);
 
-    // find every fighter in range, including the player
-    let burned_objects: Vec<_> = objects.iter()
+    // find every fighter in range, including the player and their allies unless
+    // friendly fire is off
+    let in_blast: Vec<_> = objects.iter()
         .enumerate()
-        .filter(|&(_id, obj)| obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some())
+        .filter(|&(_id, obj)| obj.distance(x, y) <= radius as f32 && obj.fighter.is_some())
         .map(|(id, _obj)| id)
         .collect();
+    let (burned_objects, spared): (Vec<_>, Vec<_>) = in_blast.into_iter()
+        .partition(|&id| friendly_fire || objects[id].faction == Faction::Monster);
+    if !spared.is_empty() {
+        let names: Vec<_> = spared.iter()
+            .map(|&id| if id == PLAYER { "you".to_owned() } else { format!("your {}", objects[id].name) })
+            .collect();
+        game.log.add(format!("The flames spare {}.", names.join(" and ")), colors::LIGHT_ORANGE);
+    }
+    if burned_objects.is_empty() {
+        game.log.add("Nobody gets burned.", colors::LIGHT_GREY);
+    }
+    let damage = objects[PLAYER].spell_strength(base_damage, game);
     for &id in &burned_objects {
         game.log.add(format!("The {} gets burned for {} hit points.",
-                             objects[id].name, FIREBALL_DAMAGE),
+                             objects[id].name, damage),
                      colors::ORANGE);
-        objects[id].take_damage(FIREBALL_DAMAGE, game).map(|xp| {
+        objects[id].take_damage(damage, game).map(|xp| {
             if id != PLAYER {
                 objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
             }
         });
     }
+
+    // set any grass in the blast on fire
+    let mut ignited = false;
+    for fire_x in (x - radius)..(x + radius + 1) {
+        for fire_y in (y - radius)..(y + radius + 1) {
+            let in_blast =
+                (((fire_x - x).pow(2) + (fire_y - y).pow(2)) as f32).sqrt() <= radius as f32;
+            if in_blast && ignite(fire_x, fire_y, game) {
+                ignited = true;
+            }
+        }
+    }
+    if ignited {
+        game.log.add("The grass catches fire!", colors::ORANGE);
+    }
     UseResult::UsedUp
 }
 
-fn cast_confuse(_inventory_id: usize, objects: &mut [Object], game: &mut Game, tcod: &mut TcodState) -> UseResult {
+/// set the tile on fire if it's grass that isn't burning yet; returns whether it was
+fn ignite(x: i32, y: i32, game: &mut Game) -> bool {
+    if !in_map(x, y, &game.map) {
+        return false;
+    }
+    let tile = game.map[x as usize][y as usize];
+    let burning = game.fires.iter().any(|fire| (fire.x, fire.y) == (x, y));
+    if tile.terrain == Terrain::Grass && !tile.blocked && !burning {
+        game.fires.push(Fire { x: x, y: y, turns_left: FIRE_DURATION });
+        game.fov_recompute = true;  // redraw the burning tiles
+        true
+    } else {
+        false
+    }
+}
+
+/// burn everyone standing in the fire, spread it to nearby grass and put it out once it's done
+fn tick_fires(objects: &mut [Object], game: &mut Game) {
+    // anyone standing in the flames gets hurt, and catches fire
+    for id in 0..objects.len() {
+        let pos = objects[id].pos();
+        let in_flames = game.fires.iter().any(|fire| (fire.x, fire.y) == pos);
+        if !in_flames || !objects[id].alive || objects[id].fighter.is_none() {
+            continue;
+        }
+        objects[id].fighter.as_mut().unwrap().burning = BURN_NUM_TURNS;
+        game.log.add(format!("The flames burn the {} for {} hit points.", objects[id].name, FIRE_DAMAGE),
+                     colors::ORANGE);
+        objects[id].take_damage(FIRE_DAMAGE, game).map(|xp| {
+            if id != PLAYER {
+                objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+            }
+        });
+    }
+
+    // burning fighters take damage, unless they're standing in water
+    for id in 0..objects.len() {
+        let (x, y) = objects[id].pos();
+        let burning = objects[id].fighter.as_ref().map_or(false, |f| f.burning > 0);
+        if !burning || !objects[id].alive {
+            continue;
+        }
+        if game.map[x as usize][y as usize].terrain == Terrain::Water {
+            objects[id].fighter.as_mut().unwrap().burning = 0;
+            game.log.add(format!("The water puts out the flames on the {}.", objects[id].name),
+                         colors::LIGHT_BLUE);
+            continue;
+        }
+        objects[id].fighter.as_mut().unwrap().burning -= 1;
+        game.log.add(format!("The {} burns for {} hit points.", objects[id].name, BURN_DAMAGE),
+                     colors::ORANGE);
+        objects[id].take_damage(BURN_DAMAGE, game).map(|xp| {
+            if id != PLAYER {
+                objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+            }
+        });
+    }
+
+    // spread to the neighbouring grass. Water and bare ground stop it.
+    if game.fires.is_empty() {
+        return;
+    }
+    let fires = game.fires.clone();
+    for fire in &fires {
+        for dx in -1..2 {
+            for dy in -1..2 {
+                if game.rng.gen::<f32>() < FIRE_SPREAD_CHANCE {
+                    ignite(fire.x + dx, fire.y + dy, game);
+                }
+            }
+        }
+    }
+
+    // the grass burns out, leaving bare ground behind
+    for fire in game.fires.iter_mut() {
+        fire.turns_left -= 1;
+        if fire.turns_left <= 0 {
+            game.map[fire.x as usize][fire.y as usize].terrain = Terrain::Ground;
+        }
+    }
+    game.fires.retain(|fire| fire.turns_left > 0);
+    game.fov_recompute = true;
+}
+
+fn cast_confuse(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
     // ask the player for a target to confuse
     game.log.add("Left-click an enemy to confuse it, or right-click to cancel.",
                  colors::LIGHT_CYAN);
-    target_monster(objects, game, tcod, Some(CONFUSE_RANGE as f32)).map_or(UseResult::Cancelled, |id| {
-        // replace the monster's AI with a "confused" one; after some
-        // turns it will restore the old AI
-        let mut monster = &mut objects[id];
-        let old_ai = monster.ai.take().map(Box::new);
-        let confuse_ai = MonsterAI {
-            old_ai: old_ai,
-            ai_type: MonsterAIType::Confused{num_turns: CONFUSE_NUM_TURNS},
-        };
-        monster.ai = Some(confuse_ai);
-        game.log.add(format!("The eyes of the {} look vacant, as he starts to stumble around!",
-                             monster.name),
-                     colors::GREEN);
+    let (range, base_turns) = {
+        let def = game.data.item(Item::Confuse);
+        (def.range, def.power)
+    };
+    target_monster(objects, game, tcod, Some(range as f32))
+        .map_or(UseResult::Cancelled, |id| confuse(id, base_turns, objects, game))
+}
+
+/// confuse the monster for `base_turns` and then some, unless it resists
+fn confuse(id: usize, base_turns: i32, objects: &mut [Object], game: &mut Game) -> UseResult {
+    // strong monsters may shrug it off, the scroll is wasted either way
+    if resists_control(&objects[id], &mut game.rng) {
+        game.log.add(format!("The {} is unaffected!", objects[id].name), colors::LIGHT_GREY);
+        return UseResult::UsedUp;
+    }
+    // more experienced casters keep their victims confused for longer
+    let num_turns = base_turns + (objects[PLAYER].level - 1) * CONFUSE_TURNS_PER_LEVEL;
+    // replace the monster's AI with a "confused" one; after some
+    // turns it will restore the old AI
+    let monster = &mut objects[id];
+    let old_ai = monster.ai.take().map(Box::new);
+    let confuse_ai = MonsterAI {
+        old_ai: old_ai,
+        ai_type: MonsterAIType::Confused{num_turns: num_turns},
+        last_seen_player: None,
+        fleeing: false,
+    };
+    monster.ai = Some(confuse_ai);
+    game.log.add(format!("The eyes of the {} look vacant, as he starts to stumble around \
+                          for {} turns!",
+                         monster.name, num_turns),
+                 colors::GREEN);
+    UseResult::UsedUp
+}
+
+/// the inventory index of the bow the player is wielding, if any
+fn equipped_bow(game: &Game) -> Option<usize> {
+    get_equipped_in_slot(EquipmentSlot::RightHand, &game.inventory).and_then(|id| {
+        if game.inventory[id].item == Some(Item::Bow) {
+            Some(id)
+        } else {
+            None
+        }
+    })
+}
+
+fn shoot_arrow(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    let bow_id = match equipped_bow(game) {
+        Some(id) => id,
+        None => {
+            game.log.add("You need to wield a bow to shoot arrows.", colors::RED);
+            return UseResult::Cancelled;
+        }
+    };
+    if !game.inventory.iter().any(|obj| obj.item == Some(Item::Arrow)) {
+        game.log.add("You have no arrows left!", colors::RED);
+        return UseResult::Cancelled;
+    }
+    // only monsters in FOV can be picked, so there's always a line of sight
+    game.log.add("Left-click an enemy to shoot at, or right-click to cancel.", colors::LIGHT_CYAN);
+    let range = game.data.item(Item::Bow).range;
+    let monster_id = match target_monster(objects, game, tcod, Some(range as f32)) {
+        Some(id) => id,
+        None => return UseResult::Cancelled,
+    };
+
+    // before the arrow is used up: the last one leaving can move the bow in the inventory
+    let bow_bonus = game.inventory[bow_id].equipment.map_or(0, |e| e.power_bonus);
+    let arrows_id = game.inventory.iter().position(|obj| obj.item == Some(Item::Arrow)).unwrap();
+    use_up_one(arrows_id, game);
+
+    let power = game.data.item(Item::Arrow).power + bow_bonus;
+    let damage = game.settings.combat_model.base_damage(power, objects[monster_id].full_defense(game));
+    if damage > 0 {
+        game.log.add(format!("Your arrow hits the {} for {} hit points.", objects[monster_id].name, damage),
+                     colors::LIGHT_SEPIA);
+    } else {
+        game.log.add(format!("Your arrow bounces off the {}.", objects[monster_id].name),
+                     colors::LIGHT_SEPIA);
+    }
+    let (x, y) = objects[monster_id].pos();
+    objects[monster_id].take_damage(damage, game).map(|xp| {
+        objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+    });
+
+    // the arrow may survive, so the player can pick it up again
+    if game.rng.gen::<f32>() < ARROW_RECOVERY_CHANCE {
+        let mut arrow = make_item(game.data.item(Item::Arrow), x, y, &mut game.rng);
+        arrow.count = 1;
+        objects.push(arrow);
+    }
+    // this already took care of the arrow stack, so keep whatever was used
+    UseResult::UsedAndKept
+}
+
+fn cast_detect_monsters(_inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    game.log.add("You sense the presence of monsters around you!", colors::LIGHT_PURPLE);
+    game.detect_monsters_turns = game.data.item(Item::DetectMonsters).power;
+    UseResult::UsedUp
+}
+
+fn cast_summon_ally(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    // somewhere free right next to the player
+    let (x, y) = objects[PLAYER].pos();
+    let spots: Vec<_> = (-1..2)
+        .flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy)))
+        .filter(|&(x, y)| !is_blocked(x, y, &game.map, objects))
+        .collect();
+    let def = match game.data.monsters.iter().find(|def| def.name == ALLY_NAME).cloned() {
+        Some(def) => def,
+        None => {
+            game.log.add("Nothing answers your call.", colors::LIGHT_GREY);
+            return UseResult::Cancelled;
+        }
+    };
+    let (x, y) = match game.rng.choose(&spots) {
+        Some(&spot) => spot,
+        None => {
+            game.log.add("There's no room for an ally next to you.", colors::LIGHT_GREY);
+            return UseResult::Cancelled;
+        }
+    };
+    let mut ally = make_monster(&def, x, y);
+    ally.faction = Faction::Player;
+    ally.fighter.as_mut().unwrap().death = Some(DeathCallback::Ally);
+    let turns = game.data.item(Item::SummonAlly).power;
+    ally.expires_in = if turns > 0 { Some(turns) } else { None };
+    game.log.add(format!("A {} answers your call!", def.name), colors::LIGHT_BLUE);
+    objects.push(ally);
+    UseResult::UsedUp
+}
+
+/// summoned allies fade away once their time is up
+fn tick_allies(objects: &mut Vec<Object>, game: &mut Game) {
+    for object in objects.iter_mut() {
+        if let Some(turns) = object.expires_in {
+            object.expires_in = Some(turns - 1);
+            if turns <= 1 {
+                game.log.add(format!("Your {} fades away.", object.name), colors::LIGHT_BLUE);
+            }
+        }
+    }
+    objects.retain(|object| object.expires_in.map_or(true, |turns| turns > 0));
+}
+
+fn cast_poison(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    game.log.add("Left-click an enemy to throw the vial at it, or right-click to cancel.",
+                 colors::LIGHT_CYAN);
+    let (range, damage) = {
+        let def = game.data.item(Item::PoisonVial);
+        (def.range, def.power)
+    };
+    target_monster(objects, game, tcod, Some(range as f32)).map_or(UseResult::Cancelled, |id| {
+        objects[id].add_effect(StatusEffect {
+            kind: StatusEffectKind::Poison,
+            turns_left: POISON_TURNS,
+            magnitude: damage,
+        });
+        game.log.add(format!("The vial shatters and the {} is poisoned!", objects[id].name),
+                     colors::LIME);
         UseResult::UsedUp
     })
 }
 
-fn equip_or_dequip(inventory_id: usize, _objects: &mut [Object], game: &mut Game, _tcod: &mut TcodState) -> UseResult {
-    let equipment = match game.inventory[inventory_id].equipment {
-        Some(equipment) => equipment,
-        None => return UseResult::Cancelled,
-    };
-    if equipment.is_equipped {
-        game.inventory[inventory_id].dequip(&mut game.log);
-    } else {
-        if let Some(old_equipment) = get_equipped_in_slot(equipment.slot, &game.inventory) {
-            game.inventory[old_equipment].dequip(&mut game.log);
+fn cast_identify(_inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    let chosen = tcod.inventory_menu(
+        game,
+        "Press the key next to an item to identify it, or any other to cancel.\n");
+    match chosen.and_then(|id| game.inventory[id].item) {
+        Some(item) if game.unidentified_name(item).is_some() => {
+            game.identify(item);
+            UseResult::UsedUp
+        }
+        Some(_) => {
+            game.log.add("You already know what that is.", colors::WHITE);
+            UseResult::Cancelled
+        }
+        None => UseResult::Cancelled,
+    }
+}
+
+fn cast_enchant(_inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) -> UseResult {
+    let chosen = tcod.filtered_inventory_menu(
+        game,
+        "Press the key next to a piece of equipment to enchant it, or any other to cancel.\n",
+        |obj| obj.equipment.is_some());
+    let id = match chosen {
+        Some(id) => id,
+        None => return UseResult::Cancelled,
+    };
+    let power = game.data.item(Item::Enchant).power;
+    let base = game.data.base_equipment(&game.inventory[id]).unwrap();
+    let item = &mut game.inventory[id];
+    let mut equipment = item.equipment.unwrap();
+    if !base.can_be_enchanted() {
+        game.log.add(format!("The scroll can't improve the {}.", item.name), colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    if equipment.enchantment >= MAX_ENCHANTMENT {
+        game.log.add(format!("The {} can't be enchanted any further.", item.name), colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    let old_enchantment = equipment.enchantment;
+    let old_name = item.name.clone();
+    equipment.enchant(&base, cmp::min(power, MAX_ENCHANTMENT - equipment.enchantment));
+    item.equipment = Some(equipment);
+    item.name = enchanted_name(unenchanted_name(&old_name, old_enchantment), equipment.enchantment);
+    game.log.add(format!("Your {} glows blue. It is now a {}: {}.",
+                         old_name, item.name, equipment.looks().bonus_summary()),
+                 colors::LIGHT_CYAN);
+    UseResult::UsedUp
+}
+
+fn cast_mapping(_inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    for column in game.map.iter_mut() {
+        for tile in column.iter_mut() {
+            tile.explored = true;
+        }
+    }
+    game.fov_recompute = true;
+    game.log.add("A map of your surroundings forms in your mind!", colors::LIGHT_CYAN);
+    UseResult::UsedUp
+}
+
+fn cast_remove_curse(_inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    for item in game.inventory.iter_mut() {
+        if let Some(equipment) = item.equipment.as_mut() {
+            if equipment.is_equipped {
+                equipment.lift_curse();
+            }
+        }
+    }
+    game.log.add("You feel as if someone is watching over you.", colors::LIGHT_CYAN);
+    UseResult::UsedUp
+}
+
+fn eat_ration(_inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    let nutrition = game.data.item(Item::Ration).power;
+    game.log.add("That really hit the spot!", colors::DARK_AMBER);
+    feed(nutrition, objects, game);
+    // a good meal helps the player's wounds close
+    objects[PLAYER].add_effect(StatusEffect {
+        kind: StatusEffectKind::Regen,
+        turns_left: RATION_REGEN_TURNS,
+        magnitude: RATION_REGEN_HP,
+    });
+    UseResult::UsedUp
+}
+
+/// fill the player up a bit, which makes starving players quick again
+fn feed(nutrition: i32, objects: &mut [Object], game: &mut Game) {
+    game.hunger = cmp::min(game.hunger + nutrition, MAX_HUNGER);
+    if game.hunger > STARVING_THRESHOLD {
+        objects[PLAYER].effects.retain(|e| e.kind != StatusEffectKind::Slow);
+    }
+}
+
+/// make every status effect do its thing, then wear off a bit
+fn tick_effects(objects: &mut [Object], game: &mut Game) {
+    for id in 0..objects.len() {
+        if !objects[id].alive || objects[id].effects.is_empty() {
+            continue;
+        }
+        let effects = objects[id].effects.clone();
+        for effect in effects {
+            match effect.kind {
+                StatusEffectKind::Poison => {
+                    game.log.add(format!("The poison hurts the {} for {} hit points.",
+                                         objects[id].name, effect.magnitude),
+                                 colors::LIME);
+                    objects[id].take_damage(effect.magnitude, game).map(|xp| {
+                        if id != PLAYER {
+                            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+                        }
+                    });
+                }
+                StatusEffectKind::Regen => {
+                    objects[id].fighter.as_mut().map(|f| f.heal(effect.magnitude));
+                }
+                StatusEffectKind::Slow | StatusEffectKind::Confused => {}
+            }
+            if !objects[id].alive {
+                break;
+            }
+        }
+        // the dead don't need them any more
+        if !objects[id].alive {
+            objects[id].effects.clear();
+            continue;
+        }
+        for effect in objects[id].effects.iter_mut() {
+            effect.turns_left -= 1;
+        }
+        let effects = mem::replace(&mut objects[id].effects, vec![]);
+        let (expired, active): (Vec<_>, Vec<_>) = effects.into_iter().partition(|e| e.turns_left <= 0);
+        objects[id].effects = active;
+        for effect in expired {
+            game.log.add(format!("The {} {}.", objects[id].name, effect.kind.expired_message()),
+                         colors::LIGHT_GREY);
+        }
+    }
+}
+
+/// the player gets hungrier every turn, and starves once there's nothing left
+fn tick_hunger(objects: &mut [Object], game: &mut Game) {
+    if game.hunger > 0 {
+        game.hunger -= 1;
+        if game.hunger == HUNGRY_THRESHOLD {
+            game.log.add("You are getting hungry.", colors::YELLOW);
+        } else if game.hunger == STARVING_THRESHOLD {
+            game.log.add("You are starving!", colors::ORANGE);
+            // too weak to keep up, until the next meal
+            objects[PLAYER].add_effect(StatusEffect {
+                kind: StatusEffectKind::Slow,
+                turns_left: i32::max_value(),
+                magnitude: 0,
+            });
+        } else if game.hunger == 0 {
+            game.log.add("You are starving to death!", colors::RED);
+        }
+    } else if objects[PLAYER].alive {
+        objects[PLAYER].take_damage(STARVATION_DAMAGE, game);
+    }
+}
+
+/// heal the player a little every few turns, as long as no monster is in sight
+/// and they aren't starving
+fn regenerate(objects: &mut [Object], game: &mut Game, tcod: &TcodState) {
+    let interval = cmp::max(REGEN_MIN_INTERVAL,
+                            REGEN_BASE_INTERVAL - objects[PLAYER].level * REGEN_INTERVAL_PER_LEVEL);
+    if game.turn_count % interval as u64 != 0 || game.hunger <= STARVING_THRESHOLD ||
+        monster_in_view(objects, tcod) {
+        return;
+    }
+    let max_hp = objects[PLAYER].full_max_hp(game);
+    if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+        // up to the maximum with equipment, which `heal` doesn't count
+        if fighter.hp < max_hp {
+            fighter.hp += 1;
+        }
+    }
+}
+
+/// how hungry the player feels, for the panel
+fn hunger_status(hunger: i32) -> (&'static str, Color) {
+    if hunger <= STARVING_THRESHOLD {
+        ("Starving", colors::RED)
+    } else if hunger <= HUNGRY_THRESHOLD {
+        ("Hungry", colors::YELLOW)
+    } else {
+        ("Not hungry", colors::LIGHT_GREY)
+    }
+}
+
+fn equip_or_dequip(inventory_id: usize, _objects: &mut Vec<Object>, game: &mut Game, _tcod: &mut TcodState) -> UseResult {
+    let equipment = match game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    if equipment.is_equipped {
+        if !game.inventory[inventory_id].dequip(&mut game.log) {
+            return UseResult::Cancelled;
+        }
+    } else {
+        if let Some(conflict) = hand_conflict(&equipment, &game.inventory) {
+            if equipment.two_handed {
+                // a two-handed weapon needs the left hand free
+                if !game.inventory[conflict].dequip(&mut game.log) {
+                    return UseResult::Cancelled;
+                }
+            } else {
+                game.log.add(format!("You need both hands for your {}.", game.inventory[conflict].name),
+                             colors::WHITE);
+                return UseResult::Cancelled;
+            }
+        }
+        if let Some(old_equipment) = get_equipped_in_slot(equipment.slot, &game.inventory) {
+            if !game.inventory[old_equipment].dequip(&mut game.log) {
+                return UseResult::Cancelled;
+            }
+        }
+        game.inventory[inventory_id].equip(&mut game.log);
+    }
+    UseResult::UsedAndKept
+}
+
+
+/// The drawing the game does, so it can go somewhere other than a libtcod
+/// console (the root console and offscreen ones are renderers). Drawing code
+/// takes a `&mut renderer::Renderer`, and `TcodState` keeps its consoles as
+/// renderers, so the game can run without a window. It's a module of its own
+/// because the methods are named like those of `Console`, and calls on a
+/// console would be ambiguous wherever both traits are in scope.
+mod renderer {
+    use tcod::console::{self, Console, Root, Offscreen, BackgroundFlag, TextAlignment};
+    use tcod::colors::Color;
+    use tcod::input::Key;
+
+    pub trait Renderer {
+        fn set_default_foreground(&mut self, color: Color);
+        fn set_default_background(&mut self, color: Color);
+        fn put_char(&mut self, x: i32, y: i32, glyph: char, flag: BackgroundFlag);
+        fn get_char(&self, x: i32, y: i32) -> char;
+        fn set_char_background(&mut self, x: i32, y: i32, color: Color, flag: BackgroundFlag);
+        fn print_ex(&mut self, x: i32, y: i32, flag: BackgroundFlag, alignment: TextAlignment, text: &str);
+        fn print_rect_ex(&mut self, x: i32, y: i32, width: i32, height: i32,
+                         flag: BackgroundFlag, alignment: TextAlignment, text: &str);
+        /// how many lines `print_rect_ex` takes to print the text
+        fn get_height_rect(&self, x: i32, y: i32, width: i32, height: i32, text: &str) -> i32;
+        fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, clear: bool, flag: BackgroundFlag);
+        fn clear(&mut self);
+        /// show what was drawn so far, which only the screen does
+        fn flush(&mut self);
+        /// the libtcod console it draws on, if there is one
+        fn console(&self) -> Option<&Console>;
+    }
+
+    /// copy a part of one renderer onto another, like `tcod::console::blit`
+    pub fn blit<S, D>(source: &S,
+                      from: (i32, i32),
+                      size: (i32, i32),
+                      destination: &mut D,
+                      to: (i32, i32),
+                      foreground_alpha: f32,
+                      background_alpha: f32)
+        where S: Renderer + ?Sized,
+              D: Renderer + ?Sized
+    {
+        if let (Some(source), Some(mut target)) = (source.console(), destination.console()) {
+            console::blit(&source, from, size, &mut target, to, foreground_alpha, background_alpha);
+            return;
+        }
+        // without two consoles, only the characters get copied
+        let ((x, y), (width, height), (dest_x, dest_y)) = (from, size, to);
+        for dx in 0..width {
+            for dy in 0..height {
+                let glyph = source.get_char(x + dx, y + dy);
+                if glyph != ' ' {
+                    destination.put_char(dest_x + dx, dest_y + dy, glyph, BackgroundFlag::None);
+                }
+            }
+        }
+    }
+
+    /// the screen: what's drawn on it is shown in a window, which the input comes from
+    pub trait Window: Renderer {
+        fn wait_for_keypress(&mut self) -> Key;
+        fn window_closed(&self) -> bool;
+        fn is_fullscreen(&self) -> bool;
+        fn set_fullscreen(&mut self, fullscreen: bool);
+        /// a renderer of the given size to draw on before blitting it here
+        fn offscreen(&self, width: i32, height: i32) -> Box<Renderer>;
+    }
+
+    // every console draws the same way, only the root one gets flushed to the screen
+    macro_rules! console_renderer {
+        ($console:ty, $flush:expr) => {
+            impl Renderer for $console {
+                fn set_default_foreground(&mut self, color: Color) {
+                    Console::set_default_foreground(self, color);
+                }
+
+                fn set_default_background(&mut self, color: Color) {
+                    Console::set_default_background(self, color);
+                }
+
+                fn put_char(&mut self, x: i32, y: i32, glyph: char, flag: BackgroundFlag) {
+                    Console::put_char(self, x, y, glyph, flag);
+                }
+
+                fn get_char(&self, x: i32, y: i32) -> char {
+                    Console::get_char(self, x, y)
+                }
+
+                fn set_char_background(&mut self, x: i32, y: i32, color: Color, flag: BackgroundFlag) {
+                    Console::set_char_background(self, x, y, color, flag);
+                }
+
+                fn print_ex(&mut self, x: i32, y: i32, flag: BackgroundFlag, alignment: TextAlignment, text: &str) {
+                    Console::print_ex(self, x, y, flag, alignment, text);
+                }
+
+                fn print_rect_ex(&mut self, x: i32, y: i32, width: i32, height: i32,
+                                 flag: BackgroundFlag, alignment: TextAlignment, text: &str) {
+                    Console::print_rect_ex(self, x, y, width, height, flag, alignment, text);
+                }
+
+                fn get_height_rect(&self, x: i32, y: i32, width: i32, height: i32, text: &str) -> i32 {
+                    Console::get_height_rect(self, x, y, width, height, text)
+                }
+
+                fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, clear: bool, flag: BackgroundFlag) {
+                    Console::rect(self, x, y, width, height, clear, flag);
+                }
+
+                fn clear(&mut self) {
+                    Console::clear(self);
+                }
+
+                fn flush(&mut self) {
+                    let flush: fn(&mut $console) = $flush;
+                    flush(self);
+                }
+
+                fn console(&self) -> Option<&Console> {
+                    Some(self)
+                }
+            }
+        }
+    }
+
+    console_renderer!(Root, |root| root.flush());
+    console_renderer!(Offscreen, |_| {});
+
+    impl Window for Root {
+        fn wait_for_keypress(&mut self) -> Key {
+            Root::wait_for_keypress(self, true)
+        }
+
+        fn window_closed(&self) -> bool {
+            Root::window_closed(self)
+        }
+
+        fn is_fullscreen(&self) -> bool {
+            Root::is_fullscreen(self)
+        }
+
+        fn set_fullscreen(&mut self, fullscreen: bool) {
+            Root::set_fullscreen(self, fullscreen);
+        }
+
+        fn offscreen(&self, width: i32, height: i32) -> Box<Renderer> {
+            Box::new(Offscreen::new(width, height))
+        }
+    }
+}
+
+/// what the player can see: the field of view of their torch, plus whatever
+/// light sources light up in their line of sight
+struct Sight {
+    fov: FovMap,
+    width: i32,
+    height: i32,
+    // tiles lit by a light source that the player can see from where they are
+    lit: Vec<Vec<bool>>,
+}
+
+impl Sight {
+    fn new(width: i32, height: i32) -> Self {
+        Sight {
+            fov: FovMap::new(width, height),
+            width: width,
+            height: height,
+            lit: vec![vec![false; height as usize]; width as usize],
+        }
+    }
+
+    fn set(&mut self, x: i32, y: i32, transparent: bool, walkable: bool) {
+        self.fov.set(x, y, transparent, walkable);
+    }
+
+    fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        self.fov.is_in_fov(x, y) || self.lit[x as usize][y as usize]
+    }
+
+    /// whether a light source (not the torch) lights up this tile
+    fn is_lit(&self, x: i32, y: i32) -> bool {
+        self.lit[x as usize][y as usize]
+    }
+
+    /// see from `origin` as far as the torch reaches, and beyond it wherever one of the
+    /// `lights` (their position and radius) shines
+    fn compute(&mut self,
+               origin: (i32, i32),
+               torch_radius: i32,
+               algorithm: FovAlgorithm,
+               lights: &[((i32, i32), i32)]) {
+        for column in &mut self.lit {
+            for tile in column.iter_mut() {
+                *tile = false;
+            }
+        }
+        if !lights.is_empty() {
+            // everything in the line of sight, however far (a radius of 0 means no limit)
+            self.fov.compute_fov(origin.0, origin.1, 0, FOV_LIGHT_WALLS, algorithm);
+            let mut in_sight = vec![vec![false; self.height as usize]; self.width as usize];
+            for x in 0..self.width {
+                for y in 0..self.height {
+                    in_sight[x as usize][y as usize] = self.fov.is_in_fov(x, y);
+                }
+            }
+            // then what each light reaches, from where it stands
+            for &((light_x, light_y), radius) in lights {
+                self.fov.compute_fov(light_x, light_y, radius, FOV_LIGHT_WALLS, algorithm);
+                for x in cmp::max(light_x - radius, 0)..cmp::min(light_x + radius + 1, self.width) {
+                    for y in cmp::max(light_y - radius, 0)..cmp::min(light_y + radius + 1, self.height) {
+                        if in_sight[x as usize][y as usize] && self.fov.is_in_fov(x, y) {
+                            self.lit[x as usize][y as usize] = true;
+                        }
+                    }
+                }
+            }
+        }
+        self.fov.compute_fov(origin.0, origin.1, torch_radius, FOV_LIGHT_WALLS, algorithm);
+    }
+}
+
+/// The size of the window and of the map in it. The panel always takes the
+/// bottom `PANEL_HEIGHT` rows, so the map has to fit above it.
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    screen_width: i32,
+    screen_height: i32,
+    map_width: i32,
+    map_height: i32,
+}
+
+impl Config {
+    fn new(screen_width: i32, screen_height: i32, map_width: i32, map_height: i32) -> Result<Self, String> {
+        if screen_width < MIN_SCREEN_WIDTH {
+            return Err(format!("the screen has to be at least {} wide", MIN_SCREEN_WIDTH));
         }
-        game.inventory[inventory_id].equip(&mut game.log);
+        if map_width < MIN_MAP_WIDTH || map_height < MIN_MAP_HEIGHT {
+            return Err(format!("the map has to be at least {}x{}", MIN_MAP_WIDTH, MIN_MAP_HEIGHT));
+        }
+        if map_width > screen_width || map_height > screen_height - PANEL_HEIGHT {
+            return Err(format!("a {}x{} map doesn't fit above the panel of a {}x{} screen",
+                               map_width, map_height, screen_width, screen_height));
+        }
+        Ok(Config {
+            screen_width: screen_width,
+            screen_height: screen_height,
+            map_width: map_width,
+            map_height: map_height,
+        })
+    }
+
+    fn panel_y(&self) -> i32 {
+        self.screen_height - PANEL_HEIGHT
     }
-    UseResult::UsedAndKept
-}
 
+    fn msg_width(&self) -> i32 {
+        self.screen_width - MSG_X
+    }
+}
 
 struct TcodState {
-    root: Root,
-    con: Offscreen,
-    panel: Offscreen,
-    fov_map: FovMap,
+    config: Config,
+    // the screen, and what the map and the panel get drawn on before going there
+    root: Box<renderer::Window>,
+    con: Box<renderer::Renderer>,
+    panel: Box<renderer::Renderer>,
+    fov_map: Sight,
     mouse: Mouse,
+    // position of the keyboard cursor when the player is picking a tile
+    cursor: Option<(i32, i32)>,
+    show_debug: bool,
+    // the input of this session so far, if it's being recorded
+    recording: Option<Recording>,
+    // the input still to be played back instead of reading it (`--replay`)
+    replay: VecDeque<RecordedInput>,
+    // how long to wait before each key press that's played back
+    replay_delay: Duration,
 }
 
 impl TcodState {
-    fn new(root: Root, con: Offscreen, panel: Offscreen) -> Self {
+    /// open the window, with everything drawn on it sized from `config`
+    fn new(config: Config) -> Self {
+        let root = Root::initializer()
+            .font("arial10x10.png", FontLayout::Tcod)
+            .font_type(FontType::Greyscale)
+            .size(config.screen_width, config.screen_height)
+            .title("Rust/libtcod tutorial")
+            .init();
+        TcodState::with_screen(config,
+                               Box::new(root),
+                               Box::new(Offscreen::new(config.map_width, config.map_height)),
+                               Box::new(Offscreen::new(config.screen_width, PANEL_HEIGHT)))
+    }
+
+    /// draw on the given renderers instead of opening a window, `con` has to be
+    /// the size of the map and `panel` as wide as the screen
+    fn with_screen(config: Config,
+                   root: Box<renderer::Window>,
+                   con: Box<renderer::Renderer>,
+                   panel: Box<renderer::Renderer>)
+                   -> Self {
         TcodState {
+            config: config,
             root: root,
             con: con,
             panel: panel,
-            fov_map: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+            fov_map: Sight::new(config.map_width, config.map_height),
             mouse: Default::default(),
+            cursor: None,
+            show_debug: false,
+            recording: None,
+            replay: VecDeque::new(),
+            replay_delay: Duration::from_millis(REPLAY_DELAY_MS),
+        }
+    }
+
+    fn record(&mut self, input: RecordedInput) {
+        if let Some(ref mut recording) = self.recording {
+            recording.inputs.push(input);
+        }
+    }
+
+    /// the next input of the replay, or None once it's done and the player takes over
+    fn replayed(&mut self) -> Option<RecordedInput> {
+        let input = self.replay.pop_front();
+        if let Some(RecordedInput::Key(_)) = input {
+            // give the watcher a chance to follow what's happening
+            thread::sleep(self.replay_delay);
+        }
+        input
+    }
+
+    /// the pending key press or mouse click, if there is one. All the reading
+    /// of input goes through these methods, so it can be recorded and replayed.
+    fn check_for_event(&mut self, mask: input::EventFlags) -> Option<Event> {
+        let event = match self.replayed() {
+            Some(RecordedInput::Key(key)) => Some(Event::Key(key)),
+            Some(RecordedInput::Mouse(mouse)) => Some(Event::Mouse(mouse)),
+            Some(other) => panic!("The replay is out of sync: expected an event, found {:?}", other),
+            None => input::check_for_event(mask).map(|e| e.1),
+        };
+        // nothing happens when there's no event, so those aren't worth recording
+        match event {
+            Some(Event::Key(key)) => self.record(RecordedInput::Key(key)),
+            Some(Event::Mouse(mouse)) => self.record(RecordedInput::Mouse(mouse)),
+            None => {}
         }
+        event
+    }
+
+    fn wait_for_keypress(&mut self) -> Key {
+        let key = match self.replayed() {
+            Some(RecordedInput::Key(key)) => key,
+            Some(other) => panic!("The replay is out of sync: expected a key, found {:?}", other),
+            None => self.root.wait_for_keypress(),
+        };
+        self.record(RecordedInput::Key(key));
+        key
+    }
+
+    /// whether the player pressed any key to stop what they're doing
+    fn key_interrupt(&mut self) -> bool {
+        let pressed = match self.replayed() {
+            Some(RecordedInput::Interrupt(pressed)) => pressed,
+            Some(other) => panic!("The replay is out of sync: expected an interruption, found {:?}", other),
+            None => input::check_for_event(input::KEY_PRESS).is_some(),
+        };
+        // unlike other events, not pressing a key makes a difference here
+        self.record(RecordedInput::Interrupt(pressed));
+        pressed
+    }
+
+    /// the seed for a new game: the given one, or a random one
+    fn new_game_seed(&mut self, seed: Option<u64>) -> u64 {
+        let seed = match self.replayed() {
+            Some(RecordedInput::Seed(seed)) => seed,
+            Some(other) => panic!("The replay is out of sync: expected a seed, found {:?}", other),
+            None => seed.unwrap_or_else(rand::random),
+        };
+        self.record(RecordedInput::Seed(seed));
+        seed
     }
 
     fn menu<T: AsRef<str>>(&mut self, header: &str, options: &[T], width: i32) -> Option<usize> {
-        assert!(options.len() <= 26, "Cannot have a menu with more than 26 options.");
+        self.draw_menu(header, options, width);
+        // wait for a key-press
+        let key = self.wait_for_keypress();
+        if key.printable.is_alphabetic() {
+            let index = key.printable.to_ascii_uppercase() as usize - 'A' as usize;
+            if index < options.len() {
+                Some(index)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Like `menu`, but for any number of options: they're shown one page of
+    /// `MENU_PAGE_SIZE` at a time, and `<` and `>` turn the pages. The index
+    /// returned is into the whole of `options`.
+    fn paged_menu<T: AsRef<str>>(&mut self, header: &str, options: &[T], width: i32) -> Option<usize> {
+        if options.len() <= MENU_PAGE_SIZE {
+            return self.menu(header, options, width);
+        }
+        let pages = (options.len() + MENU_PAGE_SIZE - 1) / MENU_PAGE_SIZE;
+        let mut page = 0;
+        loop {
+            let start = page * MENU_PAGE_SIZE;
+            let end = cmp::min(start + MENU_PAGE_SIZE, options.len());
+            let page_header = format!("{}Page {} of {}, < and > to turn the page.\n",
+                                      header, page + 1, pages);
+            self.draw_menu(&page_header, &options[start..end], width);
+            let key = self.wait_for_keypress();
+            match key.printable {
+                // typing `<` or `>` takes a Shift press first
+                _ if key.code == tcod::input::KeyCode::Shift => {}
+                '>' => page = cmp::min(page + 1, pages - 1),
+                '<' => page = page.saturating_sub(1),
+                letter if letter.is_alphabetic() => {
+                    let index = start + (letter.to_ascii_uppercase() as usize - 'A' as usize);
+                    return if index < end { Some(index) } else { None };
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// draw a menu window in the middle of the screen, without waiting for any input
+    fn draw_menu<T: AsRef<str>>(&mut self, header: &str, options: &[T], width: i32) {
+        assert!(options.len() <= MENU_PAGE_SIZE,
+                "Cannot have a menu with more than {} options.", MENU_PAGE_SIZE);
 
         // calculate total height for the header (after auto-wrap) and one line per option
-        let header_height = self.con.get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
+        let header_height = self.con.get_height_rect(0, 0, width, self.config.screen_height, header);
         let height = options.len() as i32 + header_height;
 
         // create an off-screen console that represents the menu's window
-        let mut window = Offscreen::new(width, height);
+        let mut window = self.root.offscreen(width, height);
 
         // print the header, with auto-wrap
         window.set_default_foreground(colors::WHITE);
@@ -1397,169 +5828,939 @@ impl TcodState {
         for (index, option_text) in options.iter().enumerate() {
             let text = format!("({}) {}", (first_letter + index as u8) as char, option_text.as_ref());
             window.print_ex(0, header_height + index as i32,
-                            BackgroundFlag::None, TextAlignment::Left, text);
+                            BackgroundFlag::None, TextAlignment::Left, &text);
         }
 
         // blit the contents of "window" to the root console
-        let x = SCREEN_WIDTH / 2 - width / 2;
-        let y = SCREEN_HEIGHT / 2 - height / 2;
-        tcod::console::blit(&mut window, (0, 0), (width, height), &mut self.root, (x, y), 1.0, 0.7);
+        let x = self.config.screen_width / 2 - width / 2;
+        let y = self.config.screen_height / 2 - height / 2;
+        renderer::blit(&*window, (0, 0), (width, height), &mut *self.root, (x, y), 1.0, 0.7);
 
-        // present the root console to the player and wait for a key-press
+        // present the root console to the player
         self.root.flush();
-        let key = self.root.wait_for_keypress(true);
-        if key.printable.is_alphabetic() {
-            let index = key.printable.to_ascii_uppercase() as usize - 'A' as usize;
-            if index < options.len() {
-                Some(index)
-            } else {
-                None
+    }
+
+    /// draw `char` moving along the line between two tiles, one tile per frame.
+    /// The screen is expected to be up to date already.
+    fn animate_line(&mut self, from: (i32, i32), to: (i32, i32), char: char, color: Color) {
+        if ANIMATION_FRAME_MS == 0 {
+            return;
+        }
+        self.root.set_default_foreground(color);
+        for (x, y) in Line::new(from, to) {
+            self.root.put_char(x, y, char, BackgroundFlag::None);
+            self.root.flush();
+            thread::sleep(Duration::from_millis(ANIMATION_FRAME_MS));
+        }
+    }
+
+    /// draw a ring of `*` growing from `center` until it reaches `radius`,
+    /// over the tiles the player can see
+    fn animate_explosion(&mut self, center: (i32, i32), radius: i32, color: Color) {
+        if ANIMATION_FRAME_MS == 0 {
+            return;
+        }
+        let (center_x, center_y) = center;
+        self.root.set_default_foreground(color);
+        for r in 0..radius + 1 {
+            for x in center_x - r..center_x + r + 1 {
+                for y in center_y - r..center_y + r + 1 {
+                    let distance = (((x - center_x).pow(2) + (y - center_y).pow(2)) as f32).sqrt();
+                    if x < 0 || y < 0 || x >= self.config.map_width || y >= self.config.map_height || distance > r as f32 {
+                        continue;
+                    }
+                    if self.fov_map.is_in_fov(x, y) {
+                        self.root.put_char(x, y, '*', BackgroundFlag::None);
+                    }
+                }
+            }
+            self.root.flush();
+            // a little slower than a bolt, there is more to see
+            thread::sleep(Duration::from_millis(ANIMATION_FRAME_MS * 2));
+        }
+    }
+
+    /// show all the messages of the game so far, newest at the bottom, until Escape is pressed
+    fn message_history(&mut self, log: &MessageLog) {
+        use tcod::input::KeyCode::*;
+        let history = log.history();
+        let (width, height) = (self.config.screen_width, self.config.screen_height);
+        // the first two lines are for the title
+        let page = (height - 2) as usize;
+        // how many of the newest messages are scrolled past
+        let mut scroll = 0;
+        loop {
+            let mut window = self.root.offscreen(width, height);
+            window.set_default_foreground(colors::WHITE);
+            window.print_ex(0, 0, BackgroundFlag::None, TextAlignment::Left,
+                            "Message log (arrows or PageUp/PageDown to scroll, Escape to go back)");
+            let mut y = height;
+            for &(ref msg, color, count, turn) in history.iter().rev().skip(scroll) {
+                let msg = &message_text(msg, count, turn, true);
+                y -= window.get_height_rect(0, 0, width, 0, msg);
+                if y < 2 {
+                    break;
+                }
+                window.set_default_foreground(color);
+                window.print_rect_ex(0, y, width, 0, BackgroundFlag::None, TextAlignment::Left, msg);
+            }
+            renderer::blit(&*window, (0, 0), (width, height),
+                           &mut *self.root, (0, 0), 1.0, 1.0);
+            self.root.flush();
+
+            let last = history.len().saturating_sub(1);
+            match self.wait_for_keypress().code {
+                Up => scroll = cmp::min(scroll + 1, last),
+                PageUp => scroll = cmp::min(scroll + page, last),
+                Down => scroll = scroll.saturating_sub(1),
+                PageDown => scroll = scroll.saturating_sub(page),
+                Escape => break,
+                _ => {}
             }
-        } else {
-            None
         }
     }
 
     fn inventory_menu(&mut self, game: &mut Game, header: &str) -> Option<usize> {
+        self.filtered_inventory_menu(game, header, |_| true)
+    }
+
+    /// like `inventory_menu`, listing only the items that are accepted, but
+    /// still returning their index in the whole inventory
+    fn filtered_inventory_menu<F>(&mut self, game: &mut Game, header: &str, accept: F) -> Option<usize>
+        where F: Fn(&Object) -> bool
+    {
+        let ids: Vec<_> = (0..game.inventory.len()).filter(|&id| accept(&game.inventory[id])).collect();
         // how a menu with each item of the inventory as an option
-        let options = if game.inventory.len() == 0 {
+        let options = if ids.len() == 0 {
             vec!["Inventory is empty.".into()]
         } else {
-            game.inventory.iter().map(|item| {
+            ids.iter().map(|&id| &game.inventory[id]).map(|item| {
                 // show additional information, in case it's equipped
                 let text = match item.equipment.as_ref() {
+                    Some(equipment) if equipment.is_equipped && equipment.cursed => {
+                        format!("{} (on {}, cursed)", item.name, equipment.slot)
+                    }
                     Some(equipment) if equipment.is_equipped => {
                         format!("{} (on {})", item.name, equipment.slot)
                     }
                     _ => {
-                        item.name.clone()
+                        game.object_name(item)
                     }
                 };
                 text
             }).collect()
         };
-        let inventory_index = self.menu(header, &options, INVENTORY_WIDTH);
+        let inventory_index = self.paged_menu(header, &options, INVENTORY_WIDTH);
 
         // if an item was chosen, return it
-        if game.inventory.len() > 0 {
-            inventory_index
-        } else {
-            None
+        inventory_index.and_then(|index| ids.get(index).cloned())
+    }
+
+    fn msgbox(&mut self, text: &str, width: i32) {
+        let options: &[&str; 0] = &[];  // Need to annotate the type here else Rust gets confused :-(
+        self.menu(text, options, width);  // use menu() as a sort of "message_box"
+    }
+
+    /// ask a yes/no question, returns true only if the player presses 'y'
+    fn confirm(&mut self, question: &str) -> bool {
+        let options: &[&str; 0] = &[];
+        self.draw_menu(&format!("{} (y/n)", question), options, LEVEL_SCREEN_WIDTH);
+        let key = self.wait_for_keypress();
+        key.printable == 'y' || key.printable == 'Y'
+    }
+}
+
+/// The messages of the game, as their text, their color, how many times in a
+/// row they came up and the turn they (first) came up on. Only the last
+/// `MSG_HEIGHT` fit in the panel, the rest are kept for the message history.
+#[derive(Serialize, Deserialize)]
+struct MessageLog {
+    // the turn new messages are stamped with, the one being played. It's kept
+    // up to date by `advance_turn`
+    #[serde(default)]
+    turn: u64,
+    // how many messages are kept, the oldest ones go first
+    #[serde(default = "log_capacity")]
+    capacity: usize,
+    #[serde(serialize_with = "serialize_messages", deserialize_with = "deserialize_messages")]
+    history: Vec<(String, Color, u32, u64)>,
+}
+
+fn log_capacity() -> usize {
+    LOG_CAPACITY
+}
+
+// a message as it's saved, since `ColorDef` can't be applied inside the tuple
+#[derive(Serialize, Deserialize)]
+struct SavedMessage {
+    text: String,
+    #[serde(with = "ColorDef")]
+    color: Color,
+    // saves from before messages were counted have every one just once
+    #[serde(default = "once")]
+    count: u32,
+    #[serde(default)]
+    turn: u64,
+}
+
+fn once() -> u32 {
+    1
+}
+
+/// the text of a message, with how often it was repeated and, if `stamped`, the
+/// turn it came up on
+fn message_text(text: &str, count: u32, turn: u64, stamped: bool) -> String {
+    let text = if count > 1 {
+        format!("{} (x{})", text, count)
+    } else {
+        text.to_owned()
+    };
+    if stamped {
+        format!("[{}] {}", turn, text)
+    } else {
+        text
+    }
+}
+
+fn serialize_messages<S: Serializer>(messages: &[(String, Color, u32, u64)], serializer: S) -> Result<S::Ok, S::Error> {
+    let saved: Vec<_> = messages.iter()
+        .map(|&(ref text, color, count, turn)| {
+            SavedMessage { text: text.clone(), color: color, count: count, turn: turn }
+        })
+        .collect();
+    saved.serialize(serializer)
+}
+
+fn deserialize_messages<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, Color, u32, u64)>, D::Error> {
+    let saved = try!{ Vec::<SavedMessage>::deserialize(deserializer) };
+    Ok(saved.into_iter().map(|message| (message.text, message.color, message.count, message.turn)).collect())
+}
+
+impl MessageLog {
+    fn new() -> Self {
+        MessageLog { turn: 0, capacity: LOG_CAPACITY, history: vec![] }
+    }
+
+    fn add<T: Into<String>>(&mut self, message: T, color: Color) {
+        let message = message.into();
+        // the same message again only counts it once more
+        if let Some(last) = self.history.last_mut() {
+            if last.0 == message {
+                last.2 += 1;
+                return;
+            }
+        }
+        // if the log is full, remove the oldest messages to make room for the new one
+        if self.history.len() >= self.capacity {
+            let excess = self.history.len() + 1 - self.capacity;
+            self.history.drain(..excess);
+        }
+        // add the new line as a tuple, with the text, the color, the count and the turn
+        self.history.push((message, color, 1, self.turn));
+    }
+
+    /// the newest messages, as many as the panel has room for
+    fn messages(&self) -> &[(String, Color, u32, u64)] {
+        &self.history[self.history.len().saturating_sub(MSG_HEIGHT)..]
+    }
+
+    fn history(&self) -> &[(String, Color, u32, u64)] {
+        &self.history
+    }
+}
+
+/// player preferences, saved together with the game
+#[derive(Serialize, Deserialize)]
+struct Settings {
+    // item categories picked up just by walking over them
+    auto_pickup: Vec<ItemCategory>,
+    // draw remembered items and features outside of FOV
+    show_remembered: bool,
+    // show how the damage of every attack was computed
+    verbose_combat: bool,
+    // move with hjklyubn as well
+    vi_keys: bool,
+    // draw a bar over hurt monsters
+    show_health_bars: bool,
+    save_policy: SavePolicy,
+    fov_algorithm: FovStyle,
+    // how far the player sees without other light
+    torch_radius: i32,
+    // the torch light fades with distance, instead of being the same everywhere
+    light_falloff: bool,
+    combat_model: CombatModel,
+    // area spells hurt the player and their allies as well
+    friendly_fire: bool,
+    // the panel shows the turn of every message, like the message history does
+    panel_turns: bool,
+    // corpses rot and go away after a while, instead of staying forever
+    corpse_decay: bool,
+}
+
+/// the FOV algorithms of libtcod the player can pick from (`FovAlgorithm` itself
+/// can't be saved)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum FovStyle {
+    Basic,
+    Diamond,
+    Shadow,
+    Permissive,
+    Restrictive,
+}
+
+/// in the order the option goes through them
+const FOV_STYLES: &[FovStyle] = &[FovStyle::Basic, FovStyle::Diamond, FovStyle::Shadow,
+                                  FovStyle::Permissive, FovStyle::Restrictive];
+
+impl FovStyle {
+    fn algorithm(&self) -> FovAlgorithm {
+        match *self {
+            FovStyle::Basic => FovAlgorithm::Basic,
+            FovStyle::Diamond => FovAlgorithm::Diamond,
+            FovStyle::Shadow => FovAlgorithm::Shadow,
+            // the middle of the permissiveness range
+            FovStyle::Permissive => FovAlgorithm::Permissive4,
+            FovStyle::Restrictive => FovAlgorithm::Restrictive,
+        }
+    }
+}
+
+impl std::fmt::Display for FovStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            FovStyle::Basic => write!(f, "basic"),
+            FovStyle::Diamond => write!(f, "diamond"),
+            FovStyle::Shadow => write!(f, "shadow casting"),
+            FovStyle::Permissive => write!(f, "permissive"),
+            FovStyle::Restrictive => write!(f, "restrictive"),
+        }
+    }
+}
+
+/// the element after `current` in `values`, starting over after the last one
+fn cycle<T: Copy + PartialEq>(values: &[T], current: T) -> T {
+    let index = values.iter().position(|&value| value == current).map_or(0, |index| index + 1);
+    values[index % values.len()]
+}
+
+/// when (and whether) the game gets saved
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum SavePolicy {
+    // save on exit, the save is deleted when the player dies
+    Normal,
+    // like `Normal`, but the save is also deleted when it's loaded, so it can
+    // only be used to pick the game up again once
+    Permadeath,
+    // never save (nor delete an older save)
+    Practice,
+}
+
+/// A bonus the player picks when levelling up. Most just raise a stat once,
+/// the others are looked up where they matter.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Perk {
+    Toughness,
+    Might,
+    ThickSkin,
+    Agility,
+    Arcana,
+    Berserker,
+    Lifesteal,
+    KeenEye,
+}
+
+/// every perk there is, as offered on level-up
+const PERKS: &[Perk] = &[Perk::Toughness, Perk::Might, Perk::ThickSkin, Perk::Agility,
+                         Perk::Arcana, Perk::Berserker, Perk::Lifesteal, Perk::KeenEye];
+
+impl Perk {
+    fn name(&self) -> &'static str {
+        match *self {
+            Perk::Toughness => "Toughness",
+            Perk::Might => "Might",
+            Perk::ThickSkin => "Thick skin",
+            Perk::Agility => "Agility",
+            Perk::Arcana => "Arcana",
+            Perk::Berserker => "Berserker",
+            Perk::Lifesteal => "Lifesteal",
+            Perk::KeenEye => "Keen eye",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            Perk::Toughness => "+10 max HP",
+            Perk::Might => "+1 attack",
+            Perk::ThickSkin => "+1 defense",
+            Perk::Agility => "+1 evasion",
+            Perk::Arcana => "+1 spell power",
+            Perk::Berserker => "+2 attack, -1 defense",
+            Perk::Lifesteal => "melee hits heal 10%",
+            Perk::KeenEye => "+5% critical hits",
+        }
+    }
+
+    /// whether it can be picked more than once
+    fn stacks(&self) -> bool {
+        match *self {
+            Perk::Berserker | Perk::Lifesteal | Perk::KeenEye => false,
+            _ => true,
+        }
+    }
+
+    /// raise the stats this perk raises
+    fn apply(&self, fighter: &mut Fighter) {
+        match *self {
+            Perk::Toughness => {
+                fighter.base_max_hp += 10;
+                fighter.hp += 10;
+            }
+            Perk::Might => fighter.base_power += 1,
+            Perk::ThickSkin => fighter.base_defense += 1,
+            Perk::Agility => fighter.base_evasion += 1,
+            Perk::Arcana => fighter.base_spell_power += 1,
+            Perk::Berserker => {
+                fighter.base_power += 2;
+                fighter.base_defense -= 1;
+            }
+            Perk::Lifesteal | Perk::KeenEye => {}
+        }
+    }
+}
+
+/// what the player chose to play as, which decides how they start
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum PlayerClass {
+    Warrior,
+    Rogue,
+    Mage,
+}
+
+impl PlayerClass {
+    /// the player's stats at level 1
+    fn fighter(&self) -> Fighter {
+        let (hp, power, evasion, spell_power) = match *self {
+            PlayerClass::Warrior => (120, 3, 0, 0),
+            PlayerClass::Rogue => (90, 2, 2, 0),
+            PlayerClass::Mage => (80, 2, 0, 1),
+        };
+        Fighter{
+            hp: hp, base_max_hp: hp, base_defense: 1, base_evasion: evasion, base_power: power,
+            base_spell_power: spell_power, xp: 0, control_resistance: 0, burning: 0, splits_at: None,
+            death: Some(DeathCallback::Player)}
+    }
+
+    /// what the player carries at the start, besides the dagger everyone gets
+    fn starting_items(&self) -> &'static [Item] {
+        match *self {
+            PlayerClass::Warrior => &[],
+            PlayerClass::Rogue => &[Item::Heal],
+            PlayerClass::Mage => &[Item::Lightning, Item::Fireball],
+        }
+    }
+}
+
+impl std::fmt::Display for PlayerClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PlayerClass::Warrior => write!(f, "Warrior"),
+            PlayerClass::Rogue => write!(f, "Rogue"),
+            PlayerClass::Mage => write!(f, "Mage"),
+        }
+    }
+}
+
+/// how hard the game is, chosen when starting a new one
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// the percentage of the usual number of monsters per room
+    fn monster_percent(&self) -> i32 {
+        match *self {
+            Difficulty::Easy => 70,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 140,
+        }
+    }
+
+    /// the percentage of the usual number of items per room
+    fn item_percent(&self) -> i32 {
+        match *self {
+            Difficulty::Easy => 130,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 80,
+        }
+    }
+
+    /// the percentage of the class's starting HP the player gets
+    fn hp_percent(&self) -> i32 {
+        match *self {
+            Difficulty::Easy => 125,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 80,
+        }
+    }
+
+    /// the percentage of the usual experience needed to level up
+    fn xp_percent(&self) -> i32 {
+        match *self {
+            Difficulty::Easy => 75,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 125,
+        }
+    }
+
+    /// whether the player gets to rest on the stairs to a new level
+    fn rests_on_stairs(&self) -> bool {
+        *self != Difficulty::Hard
+    }
+}
+
+impl std::fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Difficulty::Easy => write!(f, "Easy"),
+            Difficulty::Normal => write!(f, "Normal"),
+            Difficulty::Hard => write!(f, "Hard"),
+        }
+    }
+}
+
+/// a percentage of a value, rounded to the nearest
+fn scale(value: i32, percent: i32) -> i32 {
+    (value * percent + 50) / 100
+}
+
+impl Settings {
+    fn new() -> Self {
+        Settings {
+            // only ammo by default, so the inventory doesn't fill up with things the
+            // player didn't ask for
+            auto_pickup: vec![ItemCategory::Ammo],
+            show_remembered: false,
+            verbose_combat: false,
+            vi_keys: false,
+            show_health_bars: true,
+            save_policy: SavePolicy::Normal,
+            fov_algorithm: FovStyle::Basic,
+            torch_radius: TORCH_RADIUS,
+            light_falloff: true,
+            combat_model: CombatModel::Classic,
+            friendly_fire: true,
+            panel_turns: false,
+            corpse_decay: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Game {
+    dungeon_level: i32,
+    map: Map,
+    fov_recompute: bool,
+    // the player's move this turn went into water, see `advance_turn`
+    waded: bool,
+    log: MessageLog,
+    inventory: Vec<Object>,
+    settings: Settings,
+    // tiles that are currently burning
+    fires: Vec<Fire>,
+    // number of turns all monsters stay visible
+    detect_monsters_turns: i32,
+    // how full the player is, see `MAX_HUNGER`
+    hunger: i32,
+    // the number of turns the player has taken
+    turn_count: u64,
+    // the number of monsters that died
+    kills: u32,
+    class: PlayerClass,
+    difficulty: Difficulty,
+    gold: i32,
+    // things that appeared this turn (gold dropped by dead monsters, summoned
+    // monsters...), put on the map in `advance_turn`
+    spawned: Vec<Object>,
+    // uids of the monsters that were hit and have to split, see `split_monsters`
+    splits: Vec<usize>,
+    // every level is generated from this, so the same seed gives the same dungeon
+    seed: u64,
+    // how the current level was generated
+    map_style: MapStyle,
+    // the colors of the current level
+    theme: Theme,
+    // the map and objects (without the player) of every visited level, at index
+    // `dungeon_level - 1`. The entry of the current level is left empty.
+    levels: Vec<(Map, Vec<Object>)>,
+    // monster and item definitions, replaced by the current ones in `load_game`
+    #[serde(skip)]
+    data: GameData,
+    // for everything random that happens during play
+    rng: GameRng,
+    // what each potion and scroll looks like until it's identified, shuffled for every game
+    flavors: Vec<(Item, String)>,
+    // the kinds of item the player has identified
+    identified: Vec<Item>,
+    // picked on level-up, in that order
+    perks: Vec<Perk>,
+}
+
+/// The random number generator used during play. Its state can't be saved, so
+/// the seed and the number of values drawn so far are saved instead: a loaded
+/// game draws as many again to end up in the same state.
+struct GameRng {
+    seed: u64,
+    draws: u64,
+    rng: Isaac64Rng,
+}
+
+impl GameRng {
+    fn new(seed: u64) -> Self {
+        GameRng {
+            seed: seed,
+            draws: 0,
+            rng: Isaac64Rng::from_seed(&[seed][..]),
         }
     }
 
-    fn msgbox(&mut self, text: &str, width: i32) {
-        let options: &[&str; 0] = &[];  // Need to annotate the type here else Rust gets confused :-(
-        self.menu(text, options, width);  // use menu() as a sort of "message_box"
+    fn restore(seed: u64, draws: u64) -> Self {
+        let mut rng = GameRng::new(seed);
+        while rng.draws < draws {
+            rng.next_u64();
+        }
+        rng
+    }
+}
+
+impl Rng for GameRng {
+    // `Isaac64Rng` uses up one 64 bit value for a `u32` too, so these can be counted the same
+    fn next_u32(&mut self) -> u32 {
+        self.draws += 1;
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.draws += 1;
+        self.rng.next_u64()
+    }
+}
+
+impl Serialize for GameRng {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.seed, self.draws).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GameRng {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (seed, draws) = try!{ <(u64, u64)>::deserialize(deserializer) };
+        Ok(GameRng::restore(seed, draws))
     }
 }
 
-#[derive(RustcDecodable, RustcEncodable)]
-struct MessageLog {
-    messages: Vec<(String, Color)>,
+/// the generator for the given dungeon level: it depends only on the seed and the level
+fn level_rng(seed: u64, level: i32) -> StdRng {
+    StdRng::from_seed(&[seed as usize, (seed >> 32) as usize, level as usize][..])
 }
 
-impl MessageLog {
-    fn new() -> Self {
-        MessageLog { messages: vec![] }
+/// give every kind of potion and scroll a random look, so what they are has to
+/// be found out again in every game
+fn assign_flavors<R: Rng>(data: &GameData, rng: &mut R) -> Vec<(Item, String)> {
+    let mut flavors = vec![];
+    for &(category, names) in &[(ItemCategory::Potion, POTION_FLAVORS), (ItemCategory::Scroll, SCROLL_FLAVORS)] {
+        let mut names = names.to_vec();
+        rng.shuffle(&mut names);
+        let kinds = data.items.iter().map(|def| def.item).filter(|item| item.category() == category);
+        // kinds beyond the number of flavors are simply always known
+        for (kind, name) in kinds.zip(names) {
+            flavors.push((kind, name.to_owned()));
+        }
+    }
+    flavors
+}
+
+impl Game {
+    /// the experience needed to get past the given level
+    fn level_up_xp(&self, level: i32) -> i32 {
+        scale(LEVEL_UP_BASE + level * LEVEL_UP_FACTOR, self.difficulty.xp_percent())
     }
 
-    fn add<T: Into<String>>(&mut self, message: T, color: Color) {
-        // if the buffer is full, remove the first message to make room for the new one
-        if self.messages.len() == MSG_HEIGHT {
-            self.messages.remove(0);
+    /// what an unidentified kind of item is called, None once it's identified
+    fn unidentified_name(&self, item: Item) -> Option<&str> {
+        if !item.needs_identifying() || self.identified.contains(&item) {
+            return None;
         }
-        // add the new line as a tuple, with the text and the color
-        self.messages.push((message.into(), color));
+        self.flavors.iter().find(|&&(kind, _)| kind == item).map(|&(_, ref name)| name.as_str())
     }
 
-    fn messages(&self) -> &Vec<(String, Color)> {
-        &self.messages
+    /// whether there's anything in the inventory the player doesn't know yet
+    fn carries_unidentified(&self) -> bool {
+        self.inventory.iter().any(|obj| obj.item.map_or(false, |item| self.unidentified_name(item).is_some()))
     }
-}
 
-#[derive(RustcDecodable, RustcEncodable)]
-struct Game {
-    dungeon_level: i32,
-    map: Map,
-    fov_recompute: bool,
-    log: MessageLog,
-    inventory: Vec<Object>,
-}
+    /// whether the player has any cursed equipment on
+    fn wears_cursed(&self) -> bool {
+        self.inventory.iter().any(|obj| obj.equipment.map_or(false, |e| e.is_equipped && e.cursed))
+    }
+
+    /// the name the player knows an object by
+    fn object_name(&self, object: &Object) -> String {
+        match object.item.and_then(|item| self.unidentified_name(item)) {
+            Some(name) if object.count > 1 => format!("{} (x{})", name, object.count),
+            Some(name) => name.to_owned(),
+            None => object.display_name(),
+        }
+    }
+
+    /// let the player know what a kind of item really is
+    fn identify(&mut self, item: Item) {
+        if let Some(name) = self.unidentified_name(item).map(|name| name.to_owned()) {
+            let real_name = self.data.item(item).name.clone();
+            self.log.add(format!("The {} was a {}!", name, real_name), colors::LIGHT_CYAN);
+            self.identified.push(item);
+        }
+    }
 
-impl Game {
     // TODO: this should not return the objects vec as well!
-    fn new(tcod: &mut TcodState) -> (Self, Vec<Object>) {
+    fn new(save_policy: SavePolicy,
+           class: PlayerClass,
+           difficulty: Difficulty,
+           seed: u64,
+           data: GameData,
+           tcod: &mut TcodState)
+           -> (Self, Vec<Object>) {
         // create object representing the player
         let mut player = Object::new(0, 0, '@', "player", colors::WHITE, true);
         player.alive = true;
-        player.fighter = Some(
-            Fighter{
-                hp: 100, base_max_hp: 100, base_defense: 1, base_power: 2, xp: 0,
-                death: Some(DeathCallback::Player)});
+        player.faction = Faction::Player;
+        let mut fighter = class.fighter();
+        fighter.base_max_hp = scale(fighter.base_max_hp, difficulty.hp_percent());
+        fighter.hp = fighter.base_max_hp;
+        player.fighter = Some(fighter);
         player.level = 1;
 
         let mut objects = vec![player];
         let dungeon_level = 1;
+        let map_style = MapStyle::for_level(dungeon_level);
 
         // Generate map (at this point it's not drawn to the screen)
         let mut game = Game {
             dungeon_level: dungeon_level,
-            map: make_map(&mut objects,
-                          dungeon_level),
+            map: make_level_map(map_style,
+                                &tcod.config,
+                                &data,
+                                &mut objects,
+                                dungeon_level,
+                                difficulty,
+                                &mut level_rng(seed, dungeon_level)),
             fov_recompute: false,
+            waded: false,
             // create the list of game messages and their colors, starts empty
             log: MessageLog::new(),
             inventory: vec![],
+            settings: Settings::new(),
+            fires: vec![],
+            detect_monsters_turns: 0,
+            hunger: MAX_HUNGER,
+            turn_count: 0,
+            kills: 0,
+            class: class,
+            difficulty: difficulty,
+            gold: 0,
+            spawned: vec![],
+            splits: vec![],
+            seed: seed,
+            map_style: map_style,
+            theme: Theme::for_level(seed, dungeon_level),
+            levels: vec![],
+            data: data,
+            rng: GameRng::new(seed),
+            flavors: vec![],
+            identified: vec![],
+            perks: vec![],
         };
+        game.flavors = assign_flavors(&game.data, &mut game.rng);
+        game.settings.save_policy = save_policy;
         game.initialize_fov(tcod);
         // a warm welcoming message!
         game.log.add("Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
                           colors::RED);
+        game.log.turn = 1;
 
         // initial equipment: a dagger
-        let mut dagger = Object::new(0, 0, '-', "dagger", colors::SKY, false);
-        let equipment_component = Equipment {
-            slot: EquipmentSlot::RightHand,
-            is_equipped: true,
-            power_bonus: 2,
-            defense_bonus: 0,
-            max_hp_bonus: 0,
-        };
-        dagger.equipment = Some(equipment_component);
-        dagger.item = Some(Item::Sword);
+        let mut dagger = make_dagger(0, 0);
+        dagger.equipment.as_mut().unwrap().is_equipped = true;
         game.inventory.push(dagger);
+        for &item in class.starting_items() {
+            let object = make_item(game.data.item(item), 0, 0, &mut game.rng);
+            // the player knows what they brought along
+            if !game.identified.contains(&item) {
+                game.identified.push(item);
+            }
+            add_to_inventory(object, &mut game);
+        }
 
         (game, objects)
     }
 
     fn next_level(&mut self, objects: &mut Vec<Object>, tcod: &mut TcodState) {
+        self.autosave(objects);
         // advance to the next level
-        self.log.add(
-            "You take a moment to rest, and recover your strength.", colors::LIGHT_VIOLET);
-        {
-            let player = &mut objects[PLAYER];
-            let max_hp = player.full_max_hp(self);
-            player.fighter.as_mut().map(|f| {
-                let heal_hp = max_hp / 2;
-                f.heal(heal_hp);
-            });  // heal the player by 50%
+        self.leave_level(objects);
+        self.dungeon_level += 1;
+        self.map_style = MapStyle::for_level(self.dungeon_level);
+        self.theme = Theme::for_level(self.seed, self.dungeon_level);
+
+        if self.restore_level(objects) {
+            self.log.add("You descend the stairs again.", colors::RED);
+            let up_stairs = objects.iter().position(|object| object.name == "upstairs");
+            if let Some(up_stairs) = up_stairs {
+                let (x, y) = objects[up_stairs].pos();
+                objects[PLAYER].set_pos(x, y);
+            }
+            unstrand_player(objects, &self.map);
+        } else {
+            if self.difficulty.rests_on_stairs() {
+                self.log.add(
+                    "You take a moment to rest, and recover your strength.", colors::LIGHT_VIOLET);
+                let player = &mut objects[PLAYER];
+                let max_hp = player.full_max_hp(self);
+                player.fighter.as_mut().map(|f| {
+                    let heal_hp = max_hp / 2;
+                    f.heal(heal_hp);
+                });  // heal the player by 50%
+            }
+
+            self.log.add(
+                "After a rare moment of peace, you descend deeper into the heart of the dungeon...",
+                colors::RED);
+            // create a fresh new level!
+            self.map = make_level_map(self.map_style, &tcod.config, &self.data, objects, self.dungeon_level,
+                                      self.difficulty, &mut level_rng(self.seed, self.dungeon_level));
+            unstrand_player(objects, &self.map);
+            // the way back up is where the player arrives
+            let (x, y) = objects[PLAYER].pos();
+            let mut up_stairs = Object::new(x, y, '>', "upstairs", colors::WHITE, false);
+            up_stairs.always_visible = true;
+            objects.push(up_stairs);
+        }
+        self.initialize_fov(tcod);
+    }
+
+    fn previous_level(&mut self, objects: &mut Vec<Object>, tcod: &mut TcodState) {
+        self.leave_level(objects);
+        self.dungeon_level -= 1;
+        self.map_style = MapStyle::for_level(self.dungeon_level);
+        self.theme = Theme::for_level(self.seed, self.dungeon_level);
+        // the levels above are always stored, the player came through them
+        let restored = self.restore_level(objects);
+        assert!(restored, "level {} was never visited", self.dungeon_level);
+
+        let stairs = objects.iter().position(|object| object.name == "stairs");
+        if let Some(stairs) = stairs {
+            let (x, y) = objects[stairs].pos();
+            objects[PLAYER].set_pos(x, y);
         }
+        unstrand_player(objects, &self.map);
+        self.log.add(format!("You climb back up to level {}.", self.dungeon_level),
+                     colors::LIGHT_VIOLET);
+        self.initialize_fov(tcod);
+    }
 
-        self.log.add(
-            "After a rare moment of peace, you descend deeper into the heart of the dungeon...",
-            colors::RED);
-        self.dungeon_level += 1;
-        // create a fresh new level!
-        self.map = make_map(objects, self.dungeon_level);
+    /// store the current level so it can be come back to, as it is now
+    fn leave_level(&mut self, objects: &mut Vec<Object>) {
+        let left_behind = objects.split_off(PLAYER + 1);
+        let map = mem::replace(&mut self.map, vec![]);
+        let index = (self.dungeon_level - 1) as usize;
+        if index < self.levels.len() {
+            self.levels[index] = (map, left_behind);
+        } else {
+            self.levels.push((map, left_behind));
+        }
+        // fires go out while nobody watches them
+        self.fires.clear();
+    }
+
+    /// bring back the current level if it was visited before, returns `false`
+    /// if it still has to be generated
+    fn restore_level(&mut self, objects: &mut Vec<Object>) -> bool {
+        let index = (self.dungeon_level - 1) as usize;
+        match self.levels.get_mut(index) {
+            Some(level) => {
+                let (map, left_behind) = mem::replace(level, (vec![], vec![]));
+                self.map = map;
+                objects.extend(left_behind);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// replace the generated first level with one read from a file
+    fn use_fixed_level(&mut self, level: &FixedLevel, objects: &mut Vec<Object>, tcod: &mut TcodState) {
+        let mut map = filled_map(tcod.config.map_width, tcod.config.map_height);
+        objects.truncate(PLAYER + 1);
+        for (y, row) in level.grid.iter().enumerate() {
+            for (x, &c) in row.iter().enumerate() {
+                let (x, y) = (x as i32, y as i32);
+                match c {
+                    '#' => continue,
+                    '*' => {
+                        map[x as usize][y as usize].secret = true;
+                        continue;
+                    }
+                    _ => {}
+                }
+                map[x as usize][y as usize].blocked = false;
+                map[x as usize][y as usize].block_sight = false;
+                match c {
+                    '.' => {}
+                    '"' => map[x as usize][y as usize].terrain = Terrain::Grass,
+                    '~' => map[x as usize][y as usize].terrain = Terrain::Water,
+                    '=' => map[x as usize][y as usize].terrain = Terrain::Lava,
+                    '@' => objects[PLAYER].set_pos(x, y),
+                    '<' => place_stairs(x, y, objects),
+                    // the game starts on it as level 1, there's nothing above
+                    '>' => {}
+                    '+' => place_door(x, y, &mut map, objects),
+                    '\'' => {
+                        let mut door = Object::new(x, y, '\'', "door", colors::SEPIA, false);
+                        door.door = Some(Door { open: true });
+                        door.always_visible = true;
+                        objects.push(door);
+                    }
+                    '&' => objects.push(make_brazier(x, y)),
+                    '_' => objects.push(make_altar(x, y)),
+                    '^' => objects.push(make_trap(x, y, TrapKind::Spikes)),
+                    '$' => {
+                        let amount = self.rng.gen_range(1, LEVEL_FILE_MAX_GOLD + 1);
+                        objects.push(make_gold(x, y, amount));
+                    }
+                    '-' => objects.push(make_dagger(x, y)),
+                    SHOPKEEPER_GLYPH => objects.push(make_shopkeeper(x, y)),
+                    _ => {
+                        if let Some(def) = self.data.monsters.iter().find(|def| def.char == c) {
+                            objects.push(make_monster(def, x, y));
+                        } else if let Some(def) = self.rng.choose(&vault_item_defs(c, &self.data)) {
+                            objects.push(make_item(def, x, y, &mut self.rng));
+                        }
+                    }
+                }
+            }
+        }
+        self.map = map;
         self.initialize_fov(tcod);
     }
 
     fn initialize_fov(&mut self, tcod: &mut TcodState) {
         self.fov_recompute = true;
         // create the FOV map, according to the generated map
-        for y in 0..MAP_HEIGHT {
-            for x in 0..MAP_WIDTH {
+        let (width, height) = map_size(&self.map);
+        for y in 0..height {
+            for x in 0..width {
                 tcod.fov_map.set(x, y,
                                  !self.map[x as usize][y as usize].block_sight,
                                  !self.map[x as usize][y as usize].blocked);
@@ -1570,27 +6771,45 @@ impl Game {
     }
 
     fn save_game(&self, objects: &[Object]) {
-        let json_save_state = json::encode(&(self, objects)).unwrap();
-        let mut file = File::create("savegame").unwrap();
+        let json_save_state = serde_json::to_string(&(self, objects)).unwrap();
+        let mut file = File::create(SAVE_FILE).unwrap();
         file.write_all(json_save_state.as_bytes()).unwrap();
     }
 
-    fn load_game(tcod: &mut TcodState) -> Result<(Self, Vec<Object>), Error> {
+    fn load_game(data: GameData, tcod: &mut TcodState) -> Result<(Self, Vec<Object>), Error> {
         use std::io::ErrorKind::InvalidData;
         let mut json_save_state = String::new();
-        let mut file = try!{ File::open("savegame") };
+        let mut file = try!{ File::open(SAVE_FILE) };
         try!{ file.read_to_string(&mut json_save_state) };
         let (mut game, objects) = try!{
-            json::decode::<(Game, Vec<Object>)>(&json_save_state).map_err(|e| Error::new(InvalidData, e))
+            serde_json::from_str::<(Game, Vec<Object>)>(&json_save_state).map_err(|e| Error::new(InvalidData, e))
         };
+        // make sure new objects don't get the uid of a loaded one
+        let max_uid = objects.iter()
+            .chain(game.inventory.iter())
+            .chain(game.levels.iter().flat_map(|&(_, ref left_behind)| left_behind.iter()))
+            .map(|obj| obj.uid)
+            .max()
+            .unwrap_or(0);
+        NEXT_OBJECT_UID.store(max_uid + 1, atomic::Ordering::SeqCst);
+        // the level has to fit the FOV map and the offscreen console
+        if map_size(&game.map) != (tcod.config.map_width, tcod.config.map_height) {
+            let (width, height) = map_size(&game.map);
+            return Err(Error::new(InvalidData, format!("the game was saved with a {}x{} map", width, height)));
+        }
+        game.data = data;
+        game.log.turn = game.turn_count + 1;
         game.initialize_fov(tcod);
+        if game.settings.save_policy == SavePolicy::Permadeath {
+            delete_save_game();
+        }
         Ok((game, objects))
     }
 
     fn play_game(&mut self, objects: &mut Vec<Object>, tcod: &mut TcodState) {
         let mut player_action;
         while !tcod.root.window_closed() {
-            let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
+            let event = tcod.check_for_event(input::KEY_PRESS | input::MOUSE);
             if let Some(Event::Mouse(m)) = event {
                 tcod.mouse = m;
             }
@@ -1599,44 +6818,216 @@ impl Game {
 
             tcod.root.flush();
 
+            mark_seen_objects(objects, &tcod.fov_map);
+
             // level up if needed
             check_level_up(objects, self, tcod);
 
             // erase all objects at their old location, before they move
             for object in objects.iter_mut() {
-                object.clear(&mut tcod.con);
+                object.clear(&mut *tcod.con);
             }
 
             // handle keys and exit game if needed
             player_action = handle_keys(objects, self, tcod, event);
             if player_action == PlayerAction::Exit {
-                self.save_game(objects);
-                break;
+                self.autosave(objects);
+                return;
+            }
+            if player_action == PlayerAction::ExitWithoutSaving {
+                return;
             }
 
             // let monsters take their turn
             if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-                // NOTE: We have to use indices here otherwise we get a double borrow of `objects`
-                for id in 0..objects.len() {
-                    if let Some(mut ai) = objects[id].ai.take() {
-                        let new_ai = ai.take_turn(id, objects, self, tcod);
-                        objects[id].ai = new_ai.or(Some(ai));
-                    }
+                advance_turn(objects, self, tcod);
+            }
+
+            if !objects[PLAYER].alive {
+                // show how it ended, then it's back to the main menu
+                render_all(objects, self, tcod);
+                tcod.root.flush();
+                self.show_death_summary(objects, tcod);
+                return;
+            }
+        }
+        // the window was closed
+        self.autosave(objects);
+    }
+
+    fn show_death_summary(&self, objects: &[Object], tcod: &mut TcodState) {
+        let player = &objects[PLAYER];
+        let xp = player.fighter.as_ref().map_or(0, |f| f.xp);
+        let msg = format!("You died!\n\nDungeon level: {}\nCharacter level: {}\nExperience: {}\n\
+                           Explored: {}% of this level\nTurns: {}\nMonsters killed: {}",
+                          self.dungeon_level, player.level, xp, explored_percentage(&self.map),
+                          self.turn_count, self.kills);
+        tcod.msgbox(&msg, CHARACTER_SCREEN_WIDTH);
+
+        let score = Score {
+            class: self.class.to_string(),
+            dungeon_level: self.dungeon_level,
+            level: player.level,
+            turns: self.turn_count,
+            kills: self.kills,
+        };
+        let mut table = format!("High scores\n\n     {:<8}{:>6}{:>6}{:>7}{:>6}",
+                                "Class", "Depth", "Level", "Turns", "Kills");
+        let mut marked = false;
+        for (rank, entry) in record_score(&score).iter().enumerate() {
+            // point out this game's score (the first equal one, if it's there twice)
+            let marker = if !marked && *entry == score {
+                marked = true;
+                '>'
+            } else {
+                ' '
+            };
+            table.push_str(&format!("\n{}{:>2}. {:<8}{:>6}{:>6}{:>7}{:>6}",
+                                    marker, rank + 1, entry.class, entry.dungeon_level, entry.level,
+                                    entry.turns, entry.kills));
+        }
+        tcod.msgbox(&table, CHARACTER_SCREEN_WIDTH);
+    }
+
+    /// save the game, if the save policy allows it right now. A dead player is
+    /// never saved, loading the game would just show their corpse.
+    fn autosave(&mut self, objects: &[Object]) {
+        let allowed = self.settings.save_policy != SavePolicy::Practice;
+        if allowed && objects[PLAYER].alive {
+            // logged first, so the message is there when the game is loaded again
+            self.log.add("Game saved.", colors::LIGHT_GREY);
+            self.save_game(objects);
+        }
+    }
+}
+
+/// let every monster take its turn
+fn monster_turns(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    // NOTE: We have to use indices here otherwise we get a double borrow of `objects`.
+    // But monsters can add objects (or get them removed) during their turn, which
+    // moves the indices around. So we decide who acts up front by their uids and
+    // look up their current index right before (and after) each turn.
+    // Anything that appears during this loop gets to act next turn.
+    let acting: Vec<_> = objects.iter().filter(|obj| obj.ai.is_some()).map(|obj| obj.uid).collect();
+    for uid in acting {
+        let id = match find_by_uid(uid, objects) {
+            Some(id) => id,
+            None => continue,  // it's gone
+        };
+        if objects[id].loses_turn() {
+            continue;
+        }
+        if let Some(mut ai) = objects[id].ai.take() {
+            let new_ai = ai.take_turn(id, objects, game, tcod);
+            if let Some(id) = find_by_uid(uid, objects) {
+                // don't bring back the AI of a monster that died during its own turn
+                if objects[id].alive {
+                    objects[id].ai = new_ai.or(Some(ai));
                 }
             }
         }
     }
 }
 
-fn main_menu(root: Root, con: Offscreen, panel: Offscreen) {
+/// split every monster that got hit this turn in two halves, if there's room
+/// next to it for the other half
+fn split_monsters(objects: &mut Vec<Object>, game: &mut Game) {
+    for uid in mem::replace(&mut game.splits, vec![]) {
+        let id = match find_by_uid(uid, objects) {
+            Some(id) if objects[id].alive => id,
+            _ => continue,  // it died after all
+        };
+        let (x, y) = objects[id].pos();
+        let spots: Vec<_> = (-1..2)
+            .flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy)))
+            .filter(|&(x, y)| {
+                in_map(x, y, &game.map) &&
+                    !is_blocked(x, y, &game.map, objects) &&
+                    !game.spawned.iter().any(|obj| obj.pos() == (x, y))
+            })
+            .collect();
+        let (split_x, split_y) = match game.rng.choose(&spots) {
+            Some(&spot) => spot,
+            None => continue,
+        };
+        {
+            let fighter = objects[id].fighter.as_mut().unwrap();
+            fighter.hp -= fighter.hp / 2;
+            fighter.base_max_hp = fighter.hp;
+            fighter.xp /= 2;
+        }
+        let mut half = objects[id].clone();
+        half.uid = NEXT_OBJECT_UID.fetch_add(1, atomic::Ordering::SeqCst);
+        half.set_pos(split_x, split_y);
+        game.log.add(format!("The {} splits in two!", half.name), colors::LIGHT_GREEN);
+        game.spawned.push(half);
+    }
+}
+
+/// the current index of the object with the given uid
+fn find_by_uid(uid: usize, objects: &[Object]) -> Option<usize> {
+    objects.iter().position(|obj| obj.uid == uid)
+}
+
+/// everything that happens after the player takes a turn
+fn advance_turn(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut TcodState) {
+    game.turn_count += 1;
+    monster_turns(objects, game, tcod);
+    // a slowed player, or one who waded into water, gives the monsters an extra
+    // turn (standing still in it doesn't)
+    let wading = mem::replace(&mut game.waded, false);
+    if objects[PLAYER].alive && (objects[PLAYER].loses_turn() || wading) {
+        monster_turns(objects, game, tcod);
+    }
+    tick_effects(objects, game);
+    tick_allies(objects, game);
+    tick_corpses(objects, game);
+    tick_fires(objects, game);
+    spot_traps(objects, game);
+    tick_hunger(objects, game);
+    if objects[PLAYER].alive {
+        regenerate(objects, game, tcod);
+    }
+    split_monsters(objects, game);
+    objects.extend(game.spawned.drain(..));
+    if game.detect_monsters_turns > 0 {
+        game.detect_monsters_turns -= 1;
+        if game.detect_monsters_turns == 0 {
+            game.log.add("You no longer sense the monsters.", colors::LIGHT_PURPLE);
+        }
+    }
+    // whatever the player does next happens on the next turn
+    game.log.turn = game.turn_count + 1;
+}
+
+/// remember everything the player has laid eyes on, and where they last saw each monster
+fn mark_seen_objects(objects: &mut [Object], fov_map: &Sight) {
+    for object in objects.iter_mut() {
+        let in_fov = fov_map.is_in_fov(object.x, object.y);
+        if in_fov {
+            object.seen = true;
+        }
+        if object.is_player() || object.fighter.is_none() || !object.alive {
+            continue;
+        }
+        if in_fov {
+            object.last_known_pos = Some(object.pos());
+        } else if object.last_known_pos.map_or(false, |(x, y)| fov_map.is_in_fov(x, y)) {
+            // the player can see it's not there anymore
+            object.last_known_pos = None;
+        }
+    }
+}
+
+fn main_menu(tcod: &mut TcodState, seed: Option<u64>, level: Option<&FixedLevel>, data: GameData) {
     let img = tcod::image::Image::from_file("menu_background.png").ok().expect(
         "Background image not found");
 
-    let mut tcod = TcodState::new(root, con, panel);
-
     while !tcod.root.window_closed() {
         // show the background image, at twice the regular console resolution
-        tcod::image::blit_2x(&img, (0, 0), (-1, -1), &mut tcod.root, (0, 0));
+        if let Some(mut root) = tcod.root.console() {
+            tcod::image::blit_2x(&img, (0, 0), (-1, -1), &mut root, (0, 0));
+        }
 
         // show options and wait for the player's choice
         let choices = &["Play a new game", "Continue last game", "Quit"];
@@ -1644,17 +7035,58 @@ fn main_menu(root: Root, con: Offscreen, panel: Offscreen) {
 
         match choice {
             Some(0) => {  // new game
-                let (mut game, mut objects) = Game::new(&mut tcod);
-                return game.play_game(&mut objects, &mut tcod);
+                let policies = &["Normal (save when you quit, lose the save when you die)",
+                                 "Permadeath (like normal, but a save can only be loaded once)",
+                                 "Practice (never save)"];
+                let save_policy = match tcod.menu("How should the game be saved?\n", policies, 64) {
+                    Some(0) => SavePolicy::Normal,
+                    Some(1) => SavePolicy::Permadeath,
+                    Some(2) => SavePolicy::Practice,
+                    _ => continue,
+                };
+                let classes = &["Warrior (more HP and attack)",
+                                "Rogue (harder to hit, starts with a healing potion)",
+                                "Mage (less HP, starts with two attack scrolls)"];
+                let class = match tcod.menu("Choose your class:\n", classes, 52) {
+                    Some(0) => PlayerClass::Warrior,
+                    Some(1) => PlayerClass::Rogue,
+                    Some(2) => PlayerClass::Mage,
+                    _ => continue,
+                };
+                let difficulties = &["Easy (fewer monsters, more HP, faster levels)",
+                                     "Normal",
+                                     "Hard (more monsters, less HP, no rest between levels)"];
+                let difficulty = match tcod.menu("Choose the difficulty:\n", difficulties, 56) {
+                    Some(0) => Difficulty::Easy,
+                    Some(1) => Difficulty::Normal,
+                    Some(2) => Difficulty::Hard,
+                    _ => continue,
+                };
+                let seed = tcod.new_game_seed(seed);
+                let (mut game, mut objects) = Game::new(save_policy, class, difficulty, seed, data.clone(), tcod);
+                if let Some(level) = level {
+                    game.use_fixed_level(level, &mut objects, tcod);
+                }
+                game.play_game(&mut objects, tcod);
+                // a game that ended in death goes back to the menu, quitting quits
+                if objects[PLAYER].alive {
+                    return;
+                }
             }
             Some(1) => {  // load last game
-                match Game::load_game(&mut tcod) {
+                match Game::load_game(data.clone(), tcod) {
                     Ok((mut game, mut objects)) => {
-                        return game.play_game(&mut objects, &mut tcod);
+                        game.play_game(&mut objects, tcod);
+                        if objects[PLAYER].alive {
+                            return;
+                        }
                     }
-                    Err(_) => {
+                    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
                         tcod.msgbox("\n No saved game to load.\n", 24);
                     }
+                    Err(e) => {
+                        tcod.msgbox(&format!("\n Can't load the saved game: {}\n", e), 50);
+                    }
                 }
             }
             Some(2) => {  // quit
@@ -1667,15 +7099,622 @@ fn main_menu(root: Root, con: Offscreen, panel: Offscreen) {
 
 
 fn main() {
-    let root = Root::initializer()
-        .font("arial10x10.png", FontLayout::Tcod)
-        .font_type(FontType::Greyscale)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("Rust/libtcod tutorial")
-        .init();
+    // `--seed <number>` generates the same dungeon every time
+    let mut seed = None;
+    // `--record <file>` saves all the input of the session to the file,
+    // `--replay <file>` plays it back (`--replay-delay <ms>` sets the speed)
+    let mut record = None;
+    let mut replay = None;
+    let mut replay_delay = REPLAY_DELAY_MS;
+    // `--load-level <file>` starts new games on a level from the file (in the
+    // format of the F2 dump) instead of a generated one
+    let mut load_level = None;
+    // `--width` and `--height` size the window, `--map-width` and `--map-height`
+    // the map in it (by default as big as fits above the panel)
+    let mut screen_width = DEFAULT_SCREEN_WIDTH;
+    let mut screen_height = DEFAULT_SCREEN_HEIGHT;
+    let mut map_width = None;
+    let mut map_height = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--seed" {
+            seed = Some(args.next().and_then(|s| s.parse().ok()).expect("--seed needs a number"));
+        } else if arg == "--record" {
+            record = Some(args.next().expect("--record needs a file name"));
+        } else if arg == "--replay" {
+            replay = Some(args.next().expect("--replay needs a file name"));
+        } else if arg == "--replay-delay" {
+            replay_delay = args.next().and_then(|s| s.parse().ok()).expect("--replay-delay needs a number");
+        } else if arg == "--load-level" {
+            load_level = Some(args.next().expect("--load-level needs a file name"));
+        } else if arg == "--width" {
+            screen_width = args.next().and_then(|s| s.parse().ok()).expect("--width needs a number");
+        } else if arg == "--height" {
+            screen_height = args.next().and_then(|s| s.parse().ok()).expect("--height needs a number");
+        } else if arg == "--map-width" {
+            map_width = Some(args.next().and_then(|s| s.parse().ok()).expect("--map-width needs a number"));
+        } else if arg == "--map-height" {
+            map_height = Some(args.next().and_then(|s| s.parse().ok()).expect("--map-height needs a number"));
+        }
+    }
+    let config = Config::new(screen_width,
+                             screen_height,
+                             map_width.unwrap_or(screen_width),
+                             map_height.unwrap_or(screen_height - PANEL_HEIGHT))
+        .unwrap_or_else(|e| panic!("Can't use that size: {}", e));
+
+    // check the level before opening the window, so mistakes are reported plainly
+    let data = GameData::load();
+    let level = load_level.map(|path| {
+        read_level_file(&path, &data, &config).unwrap_or_else(|e| panic!("Can't load the level {}: {}", path, e))
+    });
+    if seed.is_none() {
+        seed = level.as_ref().and_then(|level| level.seed);
+    }
+
+    let mut tcod = TcodState::new(config);
     tcod::system::set_fps(LIMIT_FPS);
-    let con = Offscreen::new(MAP_WIDTH, MAP_HEIGHT);
-    let panel = Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT);
+    tcod.recording = record.map(|path| Recording { path: path, inputs: vec![] });
+    if let Some(path) = replay {
+        let inputs: Vec<RecordedInput> = read_data_file(&path)
+            .unwrap_or_else(|e| panic!("Can't read the replay {}: {}", path, e));
+        tcod.replay = inputs.into_iter().collect();
+    }
+    tcod.replay_delay = Duration::from_millis(replay_delay);
+
+    main_menu(&mut tcod, seed, level.as_ref(), data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    /// A renderer that only keeps the characters drawn on it. Clones share
+    /// them, so a test can keep one to look at what the game drew on another.
+    #[derive(Clone)]
+    struct MockConsole {
+        width: i32,
+        height: i32,
+        chars: Rc<RefCell<Vec<char>>>,
+    }
+
+    impl MockConsole {
+        fn new(width: i32, height: i32) -> Self {
+            MockConsole {
+                width: width,
+                height: height,
+                chars: Rc::new(RefCell::new(vec![' '; (width * height) as usize])),
+            }
+        }
+
+        fn index(&self, x: i32, y: i32) -> Option<usize> {
+            if x >= 0 && y >= 0 && x < self.width && y < self.height {
+                Some((y * self.width + x) as usize)
+            } else {
+                None
+            }
+        }
+
+        fn char_at(&self, x: i32, y: i32) -> char {
+            self.index(x, y).map_or(' ', |i| self.chars.borrow()[i])
+        }
+
+        /// the characters of every line, top to bottom
+        fn lines(&self) -> Vec<String> {
+            (0..self.height).map(|y| (0..self.width).map(|x| self.char_at(x, y)).collect()).collect()
+        }
+    }
+
+    impl renderer::Renderer for MockConsole {
+        fn set_default_foreground(&mut self, _color: Color) {}
+
+        fn set_default_background(&mut self, _color: Color) {}
+
+        fn put_char(&mut self, x: i32, y: i32, glyph: char, _flag: BackgroundFlag) {
+            if let Some(i) = self.index(x, y) {
+                self.chars.borrow_mut()[i] = glyph;
+            }
+        }
+
+        fn get_char(&self, x: i32, y: i32) -> char {
+            self.char_at(x, y)
+        }
+
+        fn set_char_background(&mut self, _x: i32, _y: i32, _color: Color, _flag: BackgroundFlag) {}
+
+        fn print_ex(&mut self, x: i32, y: i32, flag: BackgroundFlag, _alignment: TextAlignment, text: &str) {
+            for (dx, glyph) in text.chars().enumerate() {
+                self.put_char(x + dx as i32, y, glyph, flag);
+            }
+        }
+
+        fn print_rect_ex(&mut self, x: i32, y: i32, _width: i32, _height: i32,
+                         flag: BackgroundFlag, alignment: TextAlignment, text: &str) {
+            for (dy, line) in text.lines().enumerate() {
+                self.print_ex(x, y + dy as i32, flag, alignment, line);
+            }
+        }
+
+        fn get_height_rect(&self, _x: i32, _y: i32, _width: i32, _height: i32, text: &str) -> i32 {
+            text.lines().count() as i32
+        }
+
+        fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, clear: bool, flag: BackgroundFlag) {
+            if clear {
+                for dx in 0..width {
+                    for dy in 0..height {
+                        self.put_char(x + dx, y + dy, ' ', flag);
+                    }
+                }
+            }
+        }
+
+        fn clear(&mut self) {
+            for glyph in self.chars.borrow_mut().iter_mut() {
+                *glyph = ' ';
+            }
+        }
+
+        fn flush(&mut self) {}
+
+        fn console(&self) -> Option<&Console> {
+            None
+        }
+    }
+
+    impl renderer::Window for MockConsole {
+        /// there's nobody to press anything, so every menu gets cancelled
+        fn wait_for_keypress(&mut self) -> Key {
+            Key { code: tcod::input::KeyCode::Escape, ..Default::default() }
+        }
+
+        fn window_closed(&self) -> bool {
+            false
+        }
+
+        fn is_fullscreen(&self) -> bool {
+            false
+        }
+
+        fn set_fullscreen(&mut self, _fullscreen: bool) {}
+
+        fn offscreen(&self, width: i32, height: i32) -> Box<renderer::Renderer> {
+            Box::new(MockConsole::new(width, height))
+        }
+    }
+
+    /// the size the game has when it's started without options
+    fn default_config() -> Config {
+        Config::new(DEFAULT_SCREEN_WIDTH,
+                    DEFAULT_SCREEN_HEIGHT,
+                    DEFAULT_SCREEN_WIDTH,
+                    DEFAULT_SCREEN_HEIGHT - PANEL_HEIGHT)
+            .unwrap()
+    }
+
+    /// a `TcodState` drawing on mock consoles, and the one standing for the screen
+    fn mock_tcod() -> (TcodState, MockConsole) {
+        let config = default_config();
+        let root = MockConsole::new(config.screen_width, config.screen_height);
+        let tcod = TcodState::with_screen(config,
+                                          Box::new(root.clone()),
+                                          Box::new(MockConsole::new(config.map_width, config.map_height)),
+                                          Box::new(MockConsole::new(config.screen_width, PANEL_HEIGHT)));
+        (tcod, root)
+    }
+
+    fn new_game(tcod: &mut TcodState, seed: u64) -> (Game, Vec<Object>) {
+        Game::new(SavePolicy::Practice,
+                  PlayerClass::Warrior,
+                  Difficulty::Normal,
+                  seed,
+                  GameData::load(),
+                  tcod)
+    }
+
+    /// a monster made from the definition with that name, put on the map next to the player
+    fn add_monster(name: &str, objects: &mut Vec<Object>, game: &Game) -> usize {
+        let def = game.data.monsters.iter().find(|def| def.name == name).unwrap();
+        let (x, y) = objects[PLAYER].pos();
+        objects.push(make_monster(def, x + 1, y));
+        objects.len() - 1
+    }
+
+    #[test]
+    fn render_all_draws_the_player_on_the_screen() {
+        let (mut tcod, root) = mock_tcod();
+        let (mut game, objects) = new_game(&mut tcod, 1);
+        render_all(&objects, &mut game, &mut tcod);
+        assert_eq!(root.char_at(objects[PLAYER].x, objects[PLAYER].y), '@');
+        // the welcome message ends up in the panel, below the map
+        let panel = root.lines()[tcod.config.panel_y() as usize..].join("\n");
+        assert!(panel.contains("Welcome stranger!"));
+    }
+
+    #[test]
+    fn menus_are_drawn_on_the_screen() {
+        let (mut tcod, root) = mock_tcod();
+        assert_eq!(tcod.menu("Pick one", &["Sword", "Shield"], 24), None);
+        let screen = root.lines().join("\n");
+        assert!(screen.contains("Pick one"));
+        assert!(screen.contains("(A) Sword"));
+        assert!(screen.contains("(B) Shield"));
+    }
+
+    #[test]
+    fn immune_monsters_are_never_confused() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        let id = add_monster("orc", &mut objects, &game);
+        objects[id].fighter.as_mut().unwrap().control_resistance = 100;
+        let ai_type = objects[id].ai.as_ref().unwrap().ai_type;
+        for _ in 0..50 {
+            // the scroll is still used up
+            assert_eq!(confuse(id, 10, &mut objects, &mut game), UseResult::UsedUp);
+            assert_eq!(objects[id].ai.as_ref().unwrap().ai_type, ai_type);
+        }
+        assert_eq!(game.log.messages().last().unwrap().0, "The orc is unaffected!");
+    }
+
+    #[test]
+    fn monsters_without_resistance_are_always_confused() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        let id = add_monster("orc", &mut objects, &game);
+        assert_eq!(confuse(id, 10, &mut objects, &mut game), UseResult::UsedUp);
+        assert_eq!(objects[id].ai.as_ref().unwrap().ai_type, MonsterAIType::Confused{num_turns: 10});
+    }
+
+    #[test]
+    fn confused_monsters_in_the_corner_of_the_map_stay_on_it() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        let id = add_monster("orc", &mut objects, &game);
+        objects[id].set_pos(0, 0);
+        assert_eq!(confuse(id, 1000, &mut objects, &mut game), UseResult::UsedUp);
+        for _ in 0..500 {
+            let mut ai = objects[id].ai.take().unwrap();
+            let new_ai = ai.take_turn(id, &mut objects, &mut game, &mut tcod);
+            objects[id].ai = new_ai.or(Some(ai));
+            let (x, y) = objects[id].pos();
+            assert!(x >= 0 && y >= 0 && x < tcod.config.map_width && y < tcod.config.map_height);
+        }
+    }
+
+    #[test]
+    fn finding_a_trap_stops_automatic_moves() {
+        let (mut tcod, _) = mock_tcod();
+        let (game, mut objects) = new_game(&mut tcod, 1);
+        let (x, y) = objects[PLAYER].pos();
+        objects.push(make_trap(x + 1, y, TrapKind::Spikes));
+        let watch = AutoWatch::new(&objects, &game);
+        // no key gets pressed in between
+        tcod.replay.push_back(RecordedInput::Interrupt(false));
+        assert_eq!(watch.interruption(&objects, &game, &mut tcod), None);
+        objects.last_mut().unwrap().trap.as_mut().unwrap().discovered = true;
+        assert_eq!(watch.interruption(&objects, &game, &mut tcod), Some("You stop, there is danger ahead."));
+    }
+
+    #[test]
+    fn monster_turns_survive_summons_splits_and_deaths() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 3);
+        {
+            // the player must not die before the monsters are done
+            let fighter = objects[PLAYER].fighter.as_mut().unwrap();
+            fighter.base_max_hp = 1000000;
+            fighter.hp = 1000000;
+        }
+        // a crowd of shamans and slimes on the free tiles around the player
+        let free: Vec<_> = flood_fill(objects[PLAYER].pos(), &game.map).into_iter()
+            .filter(|&(x, y)| !is_blocked(x, y, &game.map, &objects))
+            .take(12)
+            .collect();
+        for (i, &(x, y)) in free.iter().enumerate() {
+            let name = if i % 2 == 0 { "goblin shaman" } else { "slime" };
+            let def = game.data.monsters.iter().find(|def| def.name == name).unwrap().clone();
+            objects.push(make_monster(&def, x, y));
+        }
+        for turn in 0..200 {
+            // hurt every slime so it splits, and confuse someone now and then so the
+            // monsters stumble into and kill each other
+            let slimes: Vec<_> = objects.iter().filter(|obj| obj.alive && obj.name == "slime").map(|obj| obj.uid).collect();
+            for uid in slimes {
+                let id = find_by_uid(uid, &objects).unwrap();
+                objects[id].take_damage(2, &mut game);
+            }
+            if turn % 5 == 0 {
+                if let Some(id) = objects.iter().position(|obj| obj.alive && obj.ai.is_some() && !obj.is_player()) {
+                    confuse(id, 3, &mut objects, &mut game);
+                }
+            }
+            game.fov_recompute = true;
+            render_all(&objects, &mut game, &mut tcod);
+            advance_turn(&mut objects, &mut game, &mut tcod);
+
+            let mut uids: Vec<_> = objects.iter().map(|obj| obj.uid).collect();
+            uids.sort();
+            uids.dedup();
+            assert_eq!(uids.len(), objects.len(), "two objects share a uid");
+            assert!(objects.iter().all(|obj| obj.ai.is_none() || obj.alive), "a dead monster still acts");
+        }
+        assert!(objects[PLAYER].alive);
+    }
+
+    // all in one test, with a single save file they can't run at the same time
+    #[test]
+    fn the_save_policy_decides_what_happens_to_the_save() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, objects) = new_game(&mut tcod, 1);
+        for &policy in &[SavePolicy::Normal, SavePolicy::Permadeath] {
+            game.settings.save_policy = policy;
+            game.save_game(&objects);
+            player_death(&mut objects[PLAYER].clone(), &mut game);
+            assert!(!Path::new(SAVE_FILE).exists(), "dying kept the save with {:?}", policy);
+        }
+
+        // a practice game leaves the save of another game alone
+        game.settings.save_policy = SavePolicy::Practice;
+        game.save_game(&objects);
+        player_death(&mut objects[PLAYER].clone(), &mut game);
+        assert!(Path::new(SAVE_FILE).exists());
+
+        // a permadeath game can be loaded only once
+        game.settings.save_policy = SavePolicy::Permadeath;
+        game.save_game(&objects);
+        let data = game.data.clone();
+        assert!(Game::load_game(data.clone(), &mut tcod).is_ok());
+        assert!(!Path::new(SAVE_FILE).exists());
+        game.settings.save_policy = SavePolicy::Normal;
+        game.save_game(&objects);
+        assert!(Game::load_game(data, &mut tcod).is_ok());
+        assert!(Path::new(SAVE_FILE).exists());
+        delete_save_game();
+    }
+
+    #[test]
+    fn the_stairs_can_always_be_reached() {
+        let config = default_config();
+        let data = GameData::load();
+        for seed in 0..1000 {
+            let mut objects = vec![Object::new(0, 0, '@', "player", colors::WHITE, true)];
+            let level = 1 + seed as i32 % 10;
+            let map = make_map_rooms(&config, &data, &mut objects, level, Difficulty::Normal,
+                                     &mut level_rng(seed, level));
+            let stairs = objects.iter().find(|obj| obj.name == "stairs").unwrap().pos();
+            assert!(flood_fill(objects[PLAYER].pos(), &map).contains(&stairs),
+                    "the stairs can't be reached with seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn nothing_is_placed_where_the_player_starts() {
+        let config = default_config();
+        let data = GameData::load();
+        for seed in 0..200 {
+            let mut objects = vec![Object::new(0, 0, '@', "player", colors::WHITE, true)];
+            let level = 1 + seed as i32 % 20;
+            make_level_map(MapStyle::for_level(level), &config, &data, &mut objects, level,
+                           Difficulty::Normal, &mut level_rng(seed, level));
+            let start = objects[PLAYER].pos();
+            assert!(objects[1..].iter().all(|obj| obj.pos() != start),
+                    "something is on the player's tile on level {} with seed {}", level, seed);
+        }
+    }
+
+    #[test]
+    fn every_shop_level_has_a_shopkeeper_the_player_can_reach() {
+        let config = default_config();
+        let data = GameData::load();
+        for seed in 0..50 {
+            for level in (1..4).map(|n| n * SHOP_LEVEL_INTERVAL) {
+                let mut objects = vec![Object::new(0, 0, '@', "player", colors::WHITE, true)];
+                let map = make_level_map(MapStyle::for_level(level), &config, &data, &mut objects, level,
+                                         Difficulty::Normal, &mut level_rng(seed, level));
+                let keeper = objects.iter().find(|obj| obj.shopkeeper).expect("no shopkeeper");
+                assert!(flood_fill(objects[PLAYER].pos(), &map).contains(&keeper.pos()),
+                        "the shopkeeper can't be reached on level {} with seed {}", level, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn a_confused_player_never_attacks_themselves() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        for _ in 0..200 {
+            objects[PLAYER].add_effect(StatusEffect { kind: StatusEffectKind::Confused, turns_left: 5, magnitude: 0 });
+            let before = objects[PLAYER].pos();
+            player_move_or_attack(0, 0, &mut objects, &mut game);
+            let after = objects[PLAYER].pos();
+            // still somewhere it could step to from where it was
+            assert!((after.0 - before.0).abs() <= 1 && (after.1 - before.1).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn only_wading_into_water_gives_the_monsters_an_extra_turn() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        objects.truncate(1);
+        let (x, y) = objects[PLAYER].pos();
+        for &(water_x, water_y) in &[(x, y), (x + 1, y)] {
+            let tile = &mut game.map[water_x as usize][water_y as usize];
+            tile.blocked = false;
+            tile.terrain = Terrain::Water;
+        }
+        // standing in water
+        advance_turn(&mut objects, &mut game, &mut tcod);
+        assert!(!game.waded);
+        player_move_or_attack(1, 0, &mut objects, &mut game);
+        assert!(game.waded);
+        advance_turn(&mut objects, &mut game, &mut tcod);
+        assert!(!game.waded);
+    }
+
+    #[test]
+    fn the_built_in_vaults_are_valid() {
+        assert!(!parse_vaults(DEFAULT_VAULTS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn vaults_bigger_than_the_smallest_map_are_rejected() {
+        let wall = "#".repeat(MIN_MAP_WIDTH as usize + 1);
+        let floor = format!("#{}#", ".".repeat(MIN_MAP_WIDTH as usize - 1));
+        let vault = format!("{}\n{}\n{}\n", wall, floor, wall);
+        assert!(parse_vaults(&vault).is_err());
+    }
+
+    #[test]
+    fn a_dumped_level_loads_back_the_same() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        // a bit of everything the levels don't all have, on free tiles around the player
+        let free: Vec<_> = flood_fill(objects[PLAYER].pos(), &game.map).into_iter()
+            .filter(|&(x, y)| objects.iter().all(|obj| obj.pos() != (x, y)))
+            .take(7)
+            .collect();
+        objects.push(make_gold(free[0].0, free[0].1, 5));
+        objects.push(make_dagger(free[1].0, free[1].1));
+        objects.push(make_shopkeeper(free[2].0, free[2].1));
+        let id = add_monster("orc", &mut objects, &game);
+        objects[id].set_pos(free[3].0, free[3].1);
+        become_corpse(&mut objects[id]);
+        for (&(x, y), &terrain) in free[4..].iter().zip(&[Terrain::Grass, Terrain::Water, Terrain::Lava]) {
+            game.map[x as usize][y as usize].terrain = terrain;
+        }
+        game.map[0][0].secret = true;
+
+        let text = level_text(&objects, &game);
+        let level = parse_level(&text, &game.data, &tcod.config).unwrap();
+        let (mut loaded, mut loaded_objects) = new_game(&mut tcod, 1);
+        loaded.use_fixed_level(&level, &mut loaded_objects, &mut tcod);
+        assert_eq!(level_text(&loaded_objects, &loaded), text);
+        assert!(loaded_objects.iter().any(|obj| obj.shopkeeper));
+        assert!(loaded_objects.iter().all(|obj| obj.pos() != free[3]), "the corpse came back");
+        assert!(loaded.map[0][0].secret);
+    }
 
-    main_menu(root, con, panel);
+    #[test]
+    fn messages_are_stamped_with_the_turn_being_played() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        for turn in 1..4 {
+            game.log.add(format!("You wait, turn {}.", turn), colors::WHITE);
+            assert_eq!(game.log.messages().last().unwrap().3, turn);
+            advance_turn(&mut objects, &mut game, &mut tcod);
+            assert_eq!(game.turn_count, turn);
+        }
+    }
+
+    #[test]
+    fn picking_an_item_up_leaves_the_monsters_their_own_ai() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        // the item goes before the monsters, so theirs are the indices that change
+        let (x, y) = objects[PLAYER].pos();
+        let def = game.data.item(Item::Heal).clone();
+        let potion = make_item(&def, x, y, &mut game.rng);
+        objects.insert(PLAYER + 1, potion);
+        for _ in 0..3 {
+            add_monster("orc", &mut objects, &game);
+        }
+        let summary = |objects: &[Object]| -> Vec<_> {
+            objects.iter().map(|obj| (obj.uid, obj.name.clone(), obj.ai.clone())).collect()
+        };
+        let before = summary(&objects[PLAYER + 2..]);
+        pick_item_up(PLAYER + 1, &mut objects, &mut game);
+        // nothing jumped into the slot of the item, everything after it just moved down
+        assert_eq!(summary(&objects[PLAYER + 1..]), before);
+
+        let acting: Vec<_> = objects.iter().filter(|obj| obj.ai.is_some()).map(|obj| obj.uid).collect();
+        game.fov_recompute = true;
+        render_all(&objects, &mut game, &mut tcod);
+        monster_turns(&mut objects, &mut game, &mut tcod);
+        let still_acting: Vec<_> = objects.iter().filter(|obj| obj.ai.is_some()).map(|obj| obj.uid).collect();
+        assert_eq!(still_acting, acting);
+    }
+
+    #[test]
+    fn enchantments_go_by_the_definition_of_the_equipment() {
+        let data = GameData::load();
+        let base = data.item(Item::Sword).equipment.unwrap();
+        let mut sword = base;
+        // down to no bonus at all, it's still a weapon
+        sword.enchant(&base, -base.power_bonus);
+        assert_eq!(sword.power_bonus, 0);
+        sword.enchant(&base, 1);
+        assert_eq!((sword.power_bonus, sword.defense_bonus), (1, 0));
+    }
+
+    #[test]
+    fn cursed_equipment_is_enchanted_and_uncursed_the_right_way_round() {
+        let data = GameData::load();
+        let base = data.item(Item::Sword).equipment.unwrap();
+        let mut sword = base;
+        sword.curse();
+        sword.enchant(&base, 2);
+        assert_eq!(sword.power_bonus, -(base.power_bonus + 2));
+        // it doesn't give the curse away until it's worn
+        assert_eq!(sword.looks().power_bonus, base.power_bonus + 2);
+        sword.is_equipped = true;
+        sword.lift_curse();
+        assert!(!sword.cursed);
+        assert_eq!(sword.power_bonus, base.power_bonus + 2);
+    }
+
+    #[test]
+    fn shooting_the_last_arrow_keeps_the_bow() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        let id = add_monster("orc", &mut objects, &game);
+        // the arrows come first, so using them up moves the bow
+        game.inventory.clear();
+        let arrow_def = game.data.item(Item::Arrow).clone();
+        let mut arrow = make_item(&arrow_def, 0, 0, &mut game.rng);
+        arrow.count = 1;
+        game.inventory.push(arrow);
+        let bow_def = game.data.item(Item::Bow).clone();
+        let mut bow = make_item(&bow_def, 0, 0, &mut game.rng);
+        bow.equipment.as_mut().unwrap().is_equipped = true;
+        game.inventory.push(bow);
+
+        let (x, y) = objects[id].pos();
+        tcod.replay.push_back(RecordedInput::Mouse(Mouse {
+            cx: x as isize,
+            cy: y as isize,
+            lbutton_pressed: true,
+            ..Default::default()
+        }));
+        game.fov_recompute = true;
+        assert_eq!(shoot_arrow(0, &mut objects, &mut game, &mut tcod), UseResult::UsedAndKept);
+        assert_eq!(game.inventory.len(), 1);
+        assert_eq!(game.inventory[0].item, Some(Item::Bow));
+    }
+
+    #[test]
+    fn enchanted_names_keep_the_name_of_the_item() {
+        assert_eq!(unenchanted_name("dagger", 0), "dagger");
+        assert_eq!(unenchanted_name(&enchanted_name("dagger", 2), 2), "dagger");
+        assert_eq!(unenchanted_name(&enchanted_name("helmet", -1), -1), "helmet");
+    }
+
+    #[test]
+    fn swapping_places_with_an_ally_still_steps_on_the_tile() {
+        let (mut tcod, _) = mock_tcod();
+        let (mut game, mut objects) = new_game(&mut tcod, 1);
+        objects.truncate(1);
+        let id = add_monster("orc", &mut objects, &game);
+        objects[id].faction = Faction::Player;
+        let (x, y) = objects[id].pos();
+        game.map[x as usize][y as usize].blocked = false;
+        game.map[x as usize][y as usize].terrain = Terrain::Lava;
+        let hp = objects[PLAYER].fighter.as_ref().unwrap().hp;
+        player_move_or_attack(1, 0, &mut objects, &mut game);
+        assert_eq!(objects[PLAYER].pos(), (x, y));
+        assert_eq!(objects[PLAYER].fighter.as_ref().unwrap().hp, hp - LAVA_DAMAGE);
+    }
 }
@@ -0,0 +1,571 @@
+//! The parts of the `final` game that don't need a window: the map, the
+//! building blocks of level generation, the combat math and a few small
+//! helpers. Keeping them here means they can be used (and tested) without
+//! initialising libtcod.
+
+extern crate rand;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+
+use std::cmp;
+use std::fmt;
+use rand::Rng;
+
+// the chance to hit is this, plus or minus `TO_HIT_PER_POINT` for each point of
+// the attacker's power above or below the defender's evasion
+pub const TO_HIT_BASE: f32 = 0.8;
+pub const TO_HIT_PER_POINT: f32 = 0.05;
+pub const TO_HIT_MIN: f32 = 0.2;
+pub const TO_HIT_MAX: f32 = 0.95;
+
+// attacks do up to this fraction more or less than their base damage
+pub const DAMAGE_VARIANCE: f32 = 0.25;
+// with `CombatModel::Percentage`, every point of defense takes this many percent
+// off the damage, up to the cap
+pub const ARMOR_PERCENT_PER_DEFENSE: i32 = 4;
+pub const ARMOR_MAX_REDUCTION_PERCENT: i32 = 80;
+
+/// The tiles of a level, column by column: `map[x][y]`. How big it is depends
+/// on the screen the game runs on, so its size is read from the map itself.
+pub type Map = Vec<Vec<Tile>>;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Tile {
+    pub blocked: bool,
+    pub explored: bool,
+    pub block_sight: bool,
+    pub terrain: Terrain,
+    // a wall that hides a passage, until the player finds it by searching
+    pub secret: bool,
+}
+
+/// what the floor of a tile is made of
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Terrain {
+    Ground,
+    // burns when it catches fire
+    Grass,
+    // never burns, puts out anyone who's on fire, and is slow to wade through
+    Water,
+    // burns anyone who steps on it; monsters walk around it
+    Lava,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        let center_x = (self.x1 + self.x2) / 2;
+        let center_y = (self.y1 + self.y2) / 2;
+        (center_x, center_y)
+    }
+
+    pub fn intersect(&self, other: &Rect) -> bool {
+        // returns true if this rectangle intersects with another one
+        (self.x1 <= other.x2) && (self.x2 >= other.x1) && (self.y1 <= other.y2) &&
+        (self.y2 >= other.y1)
+    }
+}
+
+/// anything on the map that can stand in the way
+pub trait Blocker {
+    fn pos(&self) -> (i32, i32);
+    fn blocks(&self) -> bool;
+}
+
+/// whether nothing can move onto the tile: it's off the map, a wall, or taken
+/// by a blocking object
+pub fn is_blocked<B: Blocker>(x: i32, y: i32, map: &Map, objects: &[B]) -> bool {
+    // nothing gets past the edge of the map
+    if !in_map(x, y, map) {
+        return true;
+    }
+    // first test the map tile
+    if map[x as usize][y as usize].blocked {
+        return true;
+    }
+    // now check for any blocking objects
+    objects.iter().any(|object| {
+        object.blocks() && object.pos() == (x, y)
+    })
+}
+
+/// the step that goes most directly from `from` to `to`, ignoring obstacles
+pub fn direction_towards((from_x, from_y): (i32, i32), (to_x, to_y): (i32, i32)) -> (i32, i32) {
+    // vector from this object to the target, and distance
+    let (dx, dy) = (to_x - from_x, to_y - from_y);
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // normalize it to length 1 (preserving direction), then round it and
+    // convert to integer so the movement is restricted to the map grid
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+    (dx, dy)
+}
+
+/// Mutably borrow two *separate* elements from the given slice.
+/// Panics when the indexes are equal or out of bounds.
+pub fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
+    assert!(first_index != second_index);
+    let split_at_index = if first_index < second_index {
+        second_index
+    } else {
+        first_index
+    };
+    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+/// Find the cheapest path from `start` to `goal` with A*, moving in all eight
+/// directions. `step_cost` tells what stepping on a tile costs (at least 1), or
+/// None if it can't be walked on; the goal itself is always allowed so that e.g.
+/// a monster standing there can be reached. At most `max_nodes` tiles are
+/// expanded, so hopeless searches give up early.
+/// Returns the tiles to step on (without the start), or None if there is no path.
+pub fn find_path<F>(start: (i32, i32), goal: (i32, i32), max_nodes: usize, step_cost: F)
+                -> Option<Vec<(i32, i32)>>
+    where F: Fn(i32, i32) -> Option<i32>
+{
+    use std::collections::{BinaryHeap, HashMap};
+    use std::cmp::Reverse;
+
+    if start == goal {
+        return Some(vec![]);
+    }
+    // diagonal steps cost the same as straight ones, so the longer axis is the distance
+    let heuristic = |(x, y): (i32, i32)| cmp::max((goal.0 - x).abs(), (goal.1 - y).abs());
+    let mut frontier = BinaryHeap::new();
+    let mut came_from = HashMap::new();
+    let mut cost = HashMap::new();
+    frontier.push(Reverse((heuristic(start), start)));
+    cost.insert(start, 0);
+    let mut expanded = 0;
+    while let Some(Reverse((_, current))) = frontier.pop() {
+        if current == goal {
+            // walk back from the goal to reconstruct the path
+            let mut path = vec![];
+            let mut pos = current;
+            while pos != start {
+                path.push(pos);
+                pos = came_from[&pos];
+            }
+            path.reverse();
+            return Some(path);
+        }
+        expanded += 1;
+        if expanded > max_nodes {
+            return None;
+        }
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let next = (current.0 + dx, current.1 + dy);
+                if next == current {
+                    continue;
+                }
+                let next_cost = match step_cost(next.0, next.1) {
+                    Some(step) => cost[&current] + step,
+                    None if next == goal => cost[&current] + 1,
+                    None => continue,
+                };
+                let better = match cost.get(&next) {
+                    Some(&old_cost) => next_cost < old_cost,
+                    None => true,
+                };
+                if better {
+                    cost.insert(next, next_cost);
+                    came_from.insert(next, current);
+                    frontier.push(Reverse((next_cost + heuristic(next), next)));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// the width and height of the map
+pub fn map_size(map: &Map) -> (i32, i32) {
+    (map.len() as i32, map.first().map_or(0, |column| column.len()) as i32)
+}
+
+/// whether a position is on the map at all
+pub fn in_map(x: i32, y: i32, map: &Map) -> bool {
+    let (width, height) = map_size(map);
+    x >= 0 && y >= 0 && x < width && y < height
+}
+
+pub fn create_room(room: Rect, map: &mut Map) {
+    // go through the tiles in the rectangle and make them passable
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            let (x, y) = (x as usize, y as usize);
+            map[x][y].blocked = false;
+            map[x][y].block_sight = false;
+        }
+    }
+}
+
+pub fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    // horizontal tunnel. `min()` and `max()` are used in case `x1 > x2`
+    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+        let (x, y) = (x as usize, y as usize);
+        map[x][y].blocked = false;
+        map[x][y].block_sight = false;
+    }
+}
+
+pub fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+        let (x, y) = (x as usize, y as usize);
+        map[x][y].blocked = false;
+        map[x][y].block_sight = false;
+    }
+}
+
+pub fn create_tunnel_between<R: Rng>((x1, y1): (i32, i32), (x2, y2): (i32, i32), map: &mut Map, rng: &mut R) {
+    // draw a coin (random bool value -- either true or false)
+    if rng.gen() {
+        // first move horizontally, then vertically
+        create_h_tunnel(x1, x2, y1, map);
+        create_v_tunnel(y1, y2, x2, map);
+    } else {
+        // first move vertically, then horizontally
+        create_v_tunnel(y1, y2, x1, map);
+        create_h_tunnel(x1, x2, y2, map);
+    }
+}
+
+pub fn filled_map(width: i32, height: i32) -> Map {
+    // fill map with "blocked" tiles
+    vec![vec![Tile{blocked: true, explored: false, block_sight: true, terrain: Terrain::Ground, secret: false};
+              height as usize];
+         width as usize]
+}
+
+/// What `make_map` puts in the rooms it digs. It picks the size of every room,
+/// and once a room has a place that doesn't overlap the others, digs it out
+/// and fills it.
+pub trait Furnisher {
+    /// the width and height of the next room to try, `first` if there's no room yet
+    fn room_size<R: Rng>(&mut self, first: bool, rng: &mut R) -> (i32, i32);
+    /// dig out the room that was just placed (usually with `create_room`) and fill it
+    fn furnish<R: Rng>(&mut self, room: Rect, first: bool, map: &mut Map, rng: &mut R);
+}
+
+/// Try to place `max_rooms` rooms at random spots of a map of the given size,
+/// keeping the ones that don't overlap, each joined to the one before it by a
+/// tunnel. Returns the map and the rooms, in the order they were placed.
+pub fn make_map<F, R>(width: i32, height: i32, max_rooms: i32, furnisher: &mut F, rng: &mut R)
+                      -> (Map, Vec<Rect>)
+    where F: Furnisher,
+          R: Rng
+{
+    let mut map = filled_map(width, height);
+    let mut rooms: Vec<Rect> = vec![];
+
+    for _ in 0..max_rooms {
+        let (w, h) = furnisher.room_size(rooms.is_empty(), rng);
+        // random position without going out of the boundaries of the map
+        let x = rng.gen_range(0, width - w);
+        let y = rng.gen_range(0, height - h);
+
+        // "Rect" struct makes rectangles easier to work with
+        let new_room = Rect::new(x, y, w, h);
+
+        // run through the other rooms and see if they intersect with this one
+        let failed = rooms.iter().any(|other_room| new_room.intersect(other_room));
+        if !failed {
+            // this means there are no intersections, so this room is valid
+            furnisher.furnish(new_room, rooms.is_empty(), &mut map, rng);
+
+            if let Some(prev) = rooms.last() {
+                // all rooms after the first:
+                // connect it to the previous room with a tunnel
+                create_tunnel_between(prev.center(), new_room.center(), &mut map, rng);
+            }
+
+            // finally, append the new room to the list
+            rooms.push(new_room);
+        }
+    }
+    (map, rooms)
+}
+
+/// all the open tiles that can be walked to from `start`, `start` included
+pub fn flood_fill(start: (i32, i32), map: &Map) -> Vec<(i32, i32)> {
+    let (width, height) = map_size(map);
+    let mut reached = vec![vec![false; height as usize]; width as usize];
+    let mut tiles = vec![];
+    let mut frontier = vec![start];
+    reached[start.0 as usize][start.1 as usize] = true;
+    while let Some((x, y)) = frontier.pop() {
+        tiles.push((x, y));
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let (nx, ny) = (x + dx, y + dy);
+                if !in_map(nx, ny, map) {
+                    continue;
+                }
+                let (ux, uy) = (nx as usize, ny as usize);
+                if !reached[ux][uy] && !map[ux][uy].blocked {
+                    reached[ux][uy] = true;
+                    frontier.push((nx, ny));
+                }
+            }
+        }
+    }
+    tiles
+}
+
+/// dig a tunnel from the center of every room that can't be walked to from
+/// `start` to the closest tile that can
+pub fn ensure_connected<R: Rng>(start: (i32, i32), rooms: &[Rect], map: &mut Map, rng: &mut R) {
+    for room in rooms {
+        let (x, y) = room.center();
+        let reachable = flood_fill(start, map);
+        if reachable.contains(&(x, y)) {
+            continue;
+        }
+        let nearest = *reachable.iter()
+            .min_by_key(|&&(rx, ry)| (rx - x).pow(2) + (ry - y).pow(2))
+            .unwrap();
+        create_tunnel_between((x, y), nearest, map, rng);
+    }
+}
+
+pub fn from_dungeon_level(table: &[(u32, i32)], level: i32) -> u32 {
+    // returns a value that depends on level. the table specifies
+    // what value occurs after each level, default is 0.
+    for &(value, table_level) in table.iter().rev() {
+        if level >= table_level {
+            return value;
+        }
+    }
+    0
+}
+
+/// how defense protects against attacks
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CombatModel {
+    // defense is taken off the power of the attack
+    Classic,
+    // every point of defense takes a share of the damage away
+    Percentage,
+}
+
+/// in the order the option goes through them
+pub const COMBAT_MODELS: &[CombatModel] = &[CombatModel::Classic, CombatModel::Percentage];
+
+impl CombatModel {
+    /// the damage an attack with this power does against this defense, before the random part
+    pub fn base_damage(&self, power: i32, defense: i32) -> i32 {
+        match *self {
+            CombatModel::Classic => power - defense,
+            // rounded to the nearest point
+            CombatModel::Percentage => (power * (100 - CombatModel::reduction_percent(defense)) + 50) / 100,
+        }
+    }
+
+    /// how much of the damage is taken away by the defense with `Percentage`
+    pub fn reduction_percent(defense: i32) -> i32 {
+        cmp::max(0, cmp::min(defense * ARMOR_PERCENT_PER_DEFENSE, ARMOR_MAX_REDUCTION_PERCENT))
+    }
+}
+
+impl fmt::Display for CombatModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CombatModel::Classic => write!(f, "classic (power - defense)"),
+            CombatModel::Percentage => write!(f, "armor (-{}% per defense)", ARMOR_PERCENT_PER_DEFENSE),
+        }
+    }
+}
+
+/// the chance an attack with this power lands on a target with this evasion
+pub fn hit_chance(power: i32, evasion: i32) -> f32 {
+    let chance = TO_HIT_BASE + (power - evasion) as f32 * TO_HIT_PER_POINT;
+    chance.max(TO_HIT_MIN).min(TO_HIT_MAX)
+}
+
+/// how far the damage of an attack may be off from its base damage, either way
+pub fn damage_spread(base: i32) -> i32 {
+    cmp::max(0, (base as f32 * DAMAGE_VARIANCE).round() as i32)
+}
+
+/// how much of the walkable part of the map has been explored, in percent
+pub fn explored_percentage(map: &Map) -> i32 {
+    let floor: Vec<_> = map.iter().flat_map(|column| column.iter()).filter(|tile| !tile.blocked).collect();
+    if floor.is_empty() {
+        return 0;
+    }
+    let explored = floor.iter().filter(|tile| tile.explored).count();
+    (explored * 100 / floor.len()) as i32
+}
+
+/// how many of the tiles around this one can be walked on
+pub fn open_neighbours((x, y): (i32, i32), map: &Map) -> usize {
+    let mut count = 0;
+    for dx in -1..2 {
+        for dy in -1..2 {
+            let (nx, ny) = (x + dx, y + dy);
+            if (dx, dy) != (0, 0) && in_map(nx, ny, map) && !map[nx as usize][ny as usize].blocked {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, StdRng};
+
+    struct Thing {
+        pos: (i32, i32),
+        blocks: bool,
+    }
+
+    impl Blocker for Thing {
+        fn pos(&self) -> (i32, i32) {
+            self.pos
+        }
+
+        fn blocks(&self) -> bool {
+            self.blocks
+        }
+    }
+
+    /// a map with just a room, open from (1, 1) to (4, 4)
+    fn room_map() -> Map {
+        let mut map = filled_map(10, 10);
+        create_room(Rect::new(0, 0, 5, 5), &mut map);
+        map
+    }
+
+    /// digs plain rooms, all of the same size
+    struct PlainRooms;
+
+    impl Furnisher for PlainRooms {
+        fn room_size<R: Rng>(&mut self, _first: bool, _rng: &mut R) -> (i32, i32) {
+            (6, 5)
+        }
+
+        fn furnish<R: Rng>(&mut self, room: Rect, _first: bool, map: &mut Map, _rng: &mut R) {
+            create_room(room, map);
+        }
+    }
+
+    #[test]
+    fn walls_and_blocking_objects_are_in_the_way() {
+        let map = room_map();
+        let things = [Thing { pos: (2, 2), blocks: true }, Thing { pos: (3, 3), blocks: false }];
+        assert!(!is_blocked(1, 1, &map, &things));
+        assert!(is_blocked(0, 0, &map, &things));
+        assert!(is_blocked(2, 2, &map, &things));
+        assert!(!is_blocked(3, 3, &map, &things));
+    }
+
+    #[test]
+    fn everything_off_the_map_is_in_the_way() {
+        let map = room_map();
+        let things: [Thing; 0] = [];
+        for &(x, y) in &[(-1, 1), (1, -1), (-1, -1), (10, 1), (1, 10), (10, 10), (i32::MIN, i32::MAX)] {
+            assert!(is_blocked(x, y, &map, &things), "({}, {}) isn't blocked", x, y);
+        }
+    }
+
+    #[test]
+    fn classic_damage_is_power_minus_defense() {
+        assert_eq!(CombatModel::Classic.base_damage(10, 3), 7);
+        assert_eq!(CombatModel::Classic.base_damage(3, 3), 0);
+        // it can go negative, which `take_damage` ignores
+        assert_eq!(CombatModel::Classic.base_damage(2, 5), -3);
+    }
+
+    #[test]
+    fn percentage_damage_is_reduced_by_the_defense() {
+        assert_eq!(CombatModel::Percentage.base_damage(10, 0), 10);
+        // 4% per point of defense: 10 * 92% is 9.2
+        assert_eq!(CombatModel::Percentage.base_damage(10, 2), 9);
+        // rounded to the nearest point: 7 * 80% is 5.6
+        assert_eq!(CombatModel::Percentage.base_damage(7, 5), 6);
+        // no more than 80% is taken away
+        assert_eq!(CombatModel::Percentage.base_damage(100, 1000), 20);
+        assert_eq!(CombatModel::reduction_percent(1000), ARMOR_MAX_REDUCTION_PERCENT);
+        assert_eq!(CombatModel::reduction_percent(-3), 0);
+    }
+
+    #[test]
+    fn rooms_are_inside_the_map_apart_and_connected() {
+        for seed in 0..50 {
+            let mut rng = StdRng::from_seed(&[seed][..]);
+            let (map, rooms) = make_map(80, 43, 30, &mut PlainRooms, &mut rng);
+            assert!(!rooms.is_empty());
+            let reachable = flood_fill(rooms[0].center(), &map);
+            for (i, room) in rooms.iter().enumerate() {
+                assert!(room.x1 >= 0 && room.y1 >= 0 && room.x2 < 80 && room.y2 < 43);
+                assert!(rooms[i + 1..].iter().all(|other| !room.intersect(other)));
+                assert!(reachable.contains(&room.center()));
+            }
+        }
+    }
+
+    #[test]
+    fn the_same_seed_makes_the_same_map() {
+        let tiles = |seed| {
+            let mut rng = StdRng::from_seed(&[seed][..]);
+            let (map, _) = make_map(80, 43, 30, &mut PlainRooms, &mut rng);
+            map.iter().map(|column| column.iter().map(|tile| tile.blocked).collect::<Vec<_>>()).collect::<Vec<_>>()
+        };
+        assert_eq!(tiles(7), tiles(7));
+    }
+
+    #[test]
+    fn the_chance_to_hit_is_capped() {
+        assert_eq!(hit_chance(3, 3), TO_HIT_BASE);
+        assert_eq!(hit_chance(100, 0), TO_HIT_MAX);
+        assert_eq!(hit_chance(0, 100), TO_HIT_MIN);
+    }
+
+    #[test]
+    fn the_damage_spread_is_a_quarter_of_the_base() {
+        assert_eq!(damage_spread(8), 2);
+        assert_eq!(damage_spread(1), 0);
+        assert_eq!(damage_spread(-4), 0);
+    }
+
+    #[test]
+    fn values_come_from_the_last_level_reached() {
+        let table = [(1, 1), (3, 4), (5, 6)];
+        assert_eq!(from_dungeon_level(&table, 0), 0);
+        assert_eq!(from_dungeon_level(&table, 1), 1);
+        assert_eq!(from_dungeon_level(&table, 5), 3);
+        assert_eq!(from_dungeon_level(&table, 20), 5);
+    }
+
+    #[test]
+    fn mut_two_borrows_both_ways_round() {
+        let mut items = [1, 2, 3];
+        {
+            let (first, third) = mut_two(0, 2, &mut items);
+            *first += 10;
+            *third += 30;
+        }
+        let (third, second) = mut_two(2, 1, &mut items);
+        assert_eq!((*third, *second), (33, 2));
+    }
+}